@@ -0,0 +1,92 @@
+//! Compares the token stores under a mixed load of concurrent lookups, registrations, updates and
+//! topic subscriptions.
+
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::Runtime;
+
+use pine5_cm_service::database::{RegistrationPolicy, TokenDb, TokenDbInMemory, TokenDbSharded};
+use pine5_cm_service::model::{Token, TokenKey};
+
+/// Number of tokens registered before measuring.
+const TOKENS: usize = 10_000;
+/// Number of operations each task performs per iteration.
+const OPERATIONS: usize = 1_000;
+/// Number of concurrent tasks issuing operations.
+const TASKS: [usize; 3] = [1, 8, 32];
+
+fn key(index: usize) -> TokenKey {
+    TokenKey::new(&format!("token-{}", index))
+}
+
+fn populate<Db: TokenDb>(rt: &Runtime, db: &Arc<Db>) {
+    rt.block_on(db.insert_many(
+        (0..TOKENS).map(|i| Token::new(key(i))).collect(),
+        RegistrationPolicy::Overwrite,
+    ));
+}
+
+/// Issue a mix of 70% lookups, 10% registrations, 10% updates and 10% topic subscriptions.
+async fn mixed<Db: TokenDb>(db: Arc<Db>, tasks: usize) {
+    let handles: Vec<_> = (0..tasks)
+        .map(|task| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for operation in 0..OPERATIONS {
+                    let key = key((task * OPERATIONS + operation * 7919) % TOKENS);
+                    match operation % 10 {
+                        0 => {
+                            db.insert(Token::new(key), RegistrationPolicy::Overwrite)
+                                .await
+                                .unwrap();
+                        }
+                        1 => {
+                            db.update(key, None).await.unwrap();
+                        }
+                        2 => {
+                            db.subscribe_topics(key, vec![Arc::from("bench")])
+                                .await
+                                .unwrap();
+                        }
+                        _ => {
+                            db.get(key).await.unwrap();
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+fn bench_mixed(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let in_memory = Arc::new(TokenDbInMemory::new());
+    let sharded = Arc::new(TokenDbSharded::default());
+    populate(&rt, &in_memory);
+    populate(&rt, &sharded);
+
+    let mut group = c.benchmark_group("mixed");
+    for tasks in TASKS {
+        group.throughput(Throughput::Elements((tasks * OPERATIONS) as u64));
+
+        group.bench_with_input(BenchmarkId::new("in_memory", tasks), &tasks, |b, &tasks| {
+            b.to_async(&rt).iter(|| mixed(in_memory.clone(), tasks))
+        });
+        group.bench_with_input(BenchmarkId::new("sharded", tasks), &tasks, |b, &tasks| {
+            b.to_async(&rt).iter(|| mixed(sharded.clone(), tasks))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_mixed);
+criterion_main!(benches);
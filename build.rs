@@ -1,4 +1,12 @@
 fn main() {
-    tonic_build::compile_protos("./proto/cm.proto")
+    tonic_build::configure()
+        .compile(
+            &[
+                "./proto/cm.proto",
+                "./proto/google/rpc/status.proto",
+                "./proto/google/rpc/error_details.proto",
+            ],
+            &["./proto"],
+        )
         .unwrap_or_else(|e| panic!("Failed to compile protos {:?}", e));
 }
@@ -0,0 +1,414 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tonic::{Request, Status};
+
+use crate::auth::Principal;
+use crate::config::AuthConfig;
+use crate::model::{self, DEFAULT_TENANT};
+use crate::tenant;
+
+/// Operation a role may be permitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Register tokens, or refresh registered ones.
+    Register,
+    /// Update tokens and their topic subscriptions.
+    Update,
+    /// Invalidate tokens. Granted for the policy to be complete; no RPC invalidates tokens yet.
+    Invalidate,
+    /// Send messages and look up their delivery status.
+    Send,
+    SubscribeTokens,
+    SubscribeMessages,
+    /// Export and import the token database.
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Permission::Register => "register",
+            Permission::Update => "update",
+            Permission::Invalidate => "invalidate",
+            Permission::Send => "send",
+            Permission::SubscribeTokens => "subscribe_tokens",
+            Permission::SubscribeMessages => "subscribe_messages",
+            Permission::Admin => "admin",
+        })
+    }
+}
+
+/// Tokens a role is permitted to register and update.
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    /// Only the tokens owned by the principal.
+    own_tokens: bool,
+    /// Only the tokens whose key starts with one of the prefixes, unless empty.
+    namespaces: Vec<String>,
+}
+
+impl Scope {
+    fn admits(&self, subject: &str, key: &str, owner: Option<&str>) -> bool {
+        (!self.own_tokens || owner == Some(subject))
+            && (self.namespaces.is_empty()
+                || self.namespaces.iter().any(|prefix| key.starts_with(prefix)))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Role {
+    permissions: HashSet<Permission>,
+    scope: Scope,
+}
+
+/// Maps the principals to their roles, and the roles to the permissions they grant over the
+/// tokens in their scope.
+///
+/// A principal is given the roles carried by its credentials and those bound to its subject, or
+/// else the default roles. The scope of a role restricts the tokens registered and updated with
+/// its permissions, while the other permissions are granted whole.
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    // Permits everything to everyone if `None`, as without authentication.
+    roles: Option<HashMap<String, Role>>,
+    bindings: HashMap<String, Vec<String>>,
+    default_roles: Vec<String>,
+}
+
+/// Permission granted to a principal, over the tokens in the scope of any of its roles granting it.
+#[derive(Debug, Clone)]
+pub struct Grant {
+    subject: Option<Arc<str>>,
+    tenant: Arc<str>,
+    // Unrestricted if `None`.
+    scopes: Option<Vec<Scope>>,
+}
+
+impl AccessPolicy {
+    /// A policy permitting everything, as when the clients are not authenticated.
+    pub fn open() -> Self {
+        Self::default()
+    }
+
+    /// The policy of the configured roles, or one permitting every authenticated principal
+    /// everything if no roles are configured.
+    pub fn new(config: &AuthConfig) -> Self {
+        if config.roles.is_empty() {
+            return Self::open();
+        }
+
+        let roles = config
+            .roles
+            .iter()
+            .map(|(name, role)| {
+                let role = Role {
+                    permissions: role.permissions.iter().copied().collect(),
+                    scope: Scope {
+                        own_tokens: role.own_tokens,
+                        namespaces: role.namespaces.clone(),
+                    },
+                };
+                (name.clone(), role)
+            })
+            .collect();
+
+        Self {
+            roles: Some(roles),
+            bindings: config.principals.clone(),
+            default_roles: config.default_roles.clone(),
+        }
+    }
+
+    /// Authorize a principal for a permission, refusing it with `PERMISSION_DENIED` unless one of
+    /// its roles grants it.
+    pub fn authorize_principal(
+        &self,
+        principal: Option<&Principal>,
+        permission: Permission,
+    ) -> Result<Grant, Status> {
+        let roles = match &self.roles {
+            Some(roles) => roles,
+            None => {
+                return Ok(Grant {
+                    subject: principal.map(|principal| Arc::from(principal.subject.as_str())),
+                    tenant: Arc::from(
+                        principal.map_or(DEFAULT_TENANT, |principal| principal.tenant.as_str()),
+                    ),
+                    scopes: None,
+                })
+            }
+        };
+
+        let principal =
+            principal.ok_or_else(|| Status::unauthenticated("credentials are required"))?;
+
+        let bound = self.bindings.get(&principal.subject);
+        let names: Vec<&String> = match (principal.roles.is_empty(), bound) {
+            (true, None) => self.default_roles.iter().collect(),
+            (_, bound) => principal
+                .roles
+                .iter()
+                .chain(bound.into_iter().flatten())
+                .collect(),
+        };
+
+        let scopes: Vec<Scope> = names
+            .into_iter()
+            .filter_map(|name| roles.get(name))
+            .filter(|role| role.permissions.contains(&permission))
+            .map(|role| role.scope.clone())
+            .collect();
+
+        if scopes.is_empty() {
+            return Err(Status::permission_denied(format!(
+                "`{}` is not permitted to {}",
+                principal.subject, permission
+            )));
+        }
+
+        Ok(Grant {
+            subject: Some(Arc::from(principal.subject.as_str())),
+            tenant: Arc::from(principal.tenant.as_str()),
+            scopes: Some(scopes),
+        })
+    }
+
+    /// Authorize the principal of a request for a permission, within the tenant of the request.
+    pub fn authorize<T>(
+        &self,
+        request: &Request<T>,
+        permission: Permission,
+    ) -> Result<Grant, Status> {
+        let tenant = tenant::of(request)?;
+        let grant = self.authorize_principal(Principal::of(request), permission)?;
+        Ok(Grant { tenant, ..grant })
+    }
+}
+
+impl Grant {
+    /// The tenant the permission is granted within.
+    pub fn tenant(&self) -> &Arc<str> {
+        &self.tenant
+    }
+
+    /// Whether the grant is restricted to the tokens in some scope.
+    pub fn restricted(&self) -> bool {
+        self.scopes.is_some()
+    }
+
+    /// Whether the token is in the scope of the grant.
+    pub fn admits(&self, token: &model::Token) -> bool {
+        let scopes = match &self.scopes {
+            Some(scopes) => scopes,
+            None => return true,
+        };
+
+        let subject = self.subject.as_deref().unwrap_or_default();
+        scopes
+            .iter()
+            .any(|scope| scope.admits(subject, &token.key.key, token.owner.as_deref()))
+    }
+
+    /// Refuse a token out of the scope of the grant with `PERMISSION_DENIED`.
+    pub fn check(&self, token: &model::Token) -> Result<(), Status> {
+        match self.admits(token) {
+            true => Ok(()),
+            false => Err(Status::permission_denied(format!(
+                "token `{}` is out of the scope of `{}`",
+                token.key.key,
+                self.subject.as_deref().unwrap_or_default()
+            ))),
+        }
+    }
+
+    /// Admit a token to register into the scope of the grant, refusing it with
+    /// `PERMISSION_DENIED` otherwise. A token without an owner is assigned to the principal if
+    /// that admits it.
+    pub fn admit(&self, token: &mut model::Token) -> Result<(), Status> {
+        if token.owner.is_none() && !self.admits(token) {
+            token.owner = self.subject.clone();
+        }
+
+        self.check(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+    use crate::auth::AuthMethod;
+    use crate::config::RoleConfig;
+
+    fn role(permissions: &[Permission], own_tokens: bool, namespaces: &[&str]) -> RoleConfig {
+        RoleConfig {
+            permissions: permissions.to_vec(),
+            own_tokens,
+            namespaces: namespaces.iter().map(|prefix| prefix.to_string()).collect(),
+        }
+    }
+
+    fn principal(subject: &str, roles: &[&str]) -> Principal {
+        Principal {
+            subject: subject.to_string(),
+            method: AuthMethod::ApiKey,
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            tenant: "acme".to_string(),
+        }
+    }
+
+    fn token(key: &str, owner: Option<&str>) -> model::Token {
+        model::Token::new(model::TokenKey::new(key)).with_owner(owner.map(Arc::from))
+    }
+
+    /// A policy of a sender role given by default, a registrar role bound to `registrar`, and
+    /// scoped roles registering owned and namespaced tokens.
+    fn policy() -> AccessPolicy {
+        let config = AuthConfig {
+            roles: [
+                ("sender", role(&[Permission::Send], false, &[])),
+                ("registrar", role(&[Permission::Register], false, &[])),
+                ("device", role(&[Permission::Register], true, &[])),
+                ("tenant-a", role(&[Permission::Register], false, &["a:"])),
+            ]
+            .into_iter()
+            .map(|(name, role)| (name.to_string(), role))
+            .collect(),
+            principals: [("registrar".to_string(), vec!["registrar".to_string()])].into(),
+            default_roles: vec!["sender".to_string()],
+            ..AuthConfig::default()
+        };
+
+        AccessPolicy::new(&config)
+    }
+
+    #[test]
+    fn default_roles_apply_without_roles() {
+        let policy = policy();
+        let anyone = principal("anyone", &[]);
+
+        assert!(policy
+            .authorize_principal(Some(&anyone), Permission::Send)
+            .is_ok());
+        assert_eq!(
+            policy
+                .authorize_principal(Some(&anyone), Permission::Register)
+                .unwrap_err()
+                .code(),
+            Code::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn bound_roles_replace_default_roles() {
+        let policy = policy();
+        let registrar = principal("registrar", &[]);
+
+        assert!(policy
+            .authorize_principal(Some(&registrar), Permission::Register)
+            .is_ok());
+        assert_eq!(
+            policy
+                .authorize_principal(Some(&registrar), Permission::Send)
+                .unwrap_err()
+                .code(),
+            Code::PermissionDenied
+        );
+
+        // Roles carried by the credentials replace the default roles as well.
+        let carried = principal("carried", &["registrar"]);
+        assert!(policy
+            .authorize_principal(Some(&carried), Permission::Send)
+            .is_err());
+    }
+
+    #[test]
+    fn own_tokens_scope_admits_owned_tokens() {
+        let grant = policy()
+            .authorize_principal(Some(&principal("alice", &["device"])), Permission::Register)
+            .unwrap();
+
+        assert!(grant.restricted());
+        assert!(grant.admits(&token("k", Some("alice"))));
+        assert!(!grant.admits(&token("k", Some("bob"))));
+        assert!(!grant.admits(&token("k", None)));
+        assert_eq!(
+            grant.check(&token("k", Some("bob"))).unwrap_err().code(),
+            Code::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn namespaces_scope_admits_prefixed_keys() {
+        let grant = policy()
+            .authorize_principal(
+                Some(&principal("service", &["tenant-a"])),
+                Permission::Register,
+            )
+            .unwrap();
+
+        assert!(grant.admits(&token("a:1", None)));
+        assert!(!grant.admits(&token("b:1", None)));
+    }
+
+    #[test]
+    fn admit_assigns_the_owner() {
+        let grant = policy()
+            .authorize_principal(Some(&principal("alice", &["device"])), Permission::Register)
+            .unwrap();
+
+        let mut unowned = token("k", None);
+        assert!(grant.admit(&mut unowned).is_ok());
+        assert_eq!(unowned.owner.as_deref(), Some("alice"));
+
+        let mut owned = token("k", Some("bob"));
+        assert_eq!(
+            grant.admit(&mut owned).unwrap_err().code(),
+            Code::PermissionDenied
+        );
+        assert_eq!(owned.owner.as_deref(), Some("bob"));
+
+        // A token already in scope keeps having no owner.
+        let grant = policy()
+            .authorize_principal(
+                Some(&principal("service", &["tenant-a"])),
+                Permission::Register,
+            )
+            .unwrap();
+        let mut namespaced = token("a:1", None);
+        assert!(grant.admit(&mut namespaced).is_ok());
+        assert_eq!(namespaced.owner, None);
+    }
+
+    #[test]
+    fn unauthenticated_without_a_principal() {
+        assert_eq!(
+            policy()
+                .authorize_principal(None, Permission::Send)
+                .unwrap_err()
+                .code(),
+            Code::Unauthenticated
+        );
+    }
+
+    #[test]
+    fn open_without_roles() {
+        let policy = AccessPolicy::new(&AuthConfig::default());
+
+        let grant = policy.authorize_principal(None, Permission::Admin).unwrap();
+        assert!(!grant.restricted());
+        assert_eq!(&**grant.tenant(), DEFAULT_TENANT);
+
+        let grant = policy
+            .authorize_principal(Some(&principal("anyone", &[])), Permission::Register)
+            .unwrap();
+        assert!(!grant.restricted());
+        assert_eq!(&**grant.tenant(), "acme");
+        assert!(grant.admits(&token("k", Some("someone-else"))));
+    }
+}
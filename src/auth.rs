@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use thiserror::Error;
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+use tonic::{Request, Status};
+use tower::filter::Predicate;
+use tower::BoxError;
+use tracing::{debug, info, warn};
+
+use crate::config::AuthConfig;
+use crate::tls::PeerIdentity;
+
+/// Header carrying an API key.
+pub const API_KEY_HEADER: &str = "x-api-key";
+/// Scheme of the `authorization` header carrying a JWT.
+pub const BEARER_PREFIX: &str = "Bearer ";
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("failed to read `{}`: {source}", .path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("failed to parse `{}`: {source}", .path.display())]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("`{}` holds no HS256 or RS256 key", .path.display())]
+    NoKeys { path: PathBuf },
+}
+
+/// How a principal authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    ApiKey,
+    Jwt,
+    Certificate,
+}
+
+/// Authenticated client of a request, as attached to its extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    /// Name of the principal of an API key, subject of a JWT, or name of a certificate.
+    pub subject: String,
+    pub method: AuthMethod,
+    /// Roles carried by the credentials: those of an API key, or the `roles` claim of a JWT.
+    pub roles: Vec<String>,
+    /// Tenant named by the credentials, or else bound to the subject. Empty for the default
+    /// tenant.
+    pub tenant: String,
+}
+
+impl Principal {
+    /// The principal of a request, if it was authenticated.
+    pub fn of<T>(request: &Request<T>) -> Option<&Self> {
+        request.extensions().get::<Self>()
+    }
+}
+
+/// Key of a JWKS, along with the algorithm tokens signed with it are verified by.
+struct JwtKey {
+    id: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+/// Authenticates every request by its API key, bearer token or client certificate, attaching the
+/// principal to its extensions, and refuses it with `UNAUTHENTICATED` otherwise.
+///
+/// Health checks are admitted without credentials, so that load balancers can probe the server.
+#[derive(Clone)]
+pub struct Authenticator {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    // Principals by the hex SHA-256 digest of their key.
+    api_keys: HashMap<String, Principal>,
+    // Tenants by subject, of the principals whose credentials name none.
+    tenants: HashMap<String, String>,
+    jwt_keys: Vec<JwtKey>,
+    validation: Validation,
+}
+
+impl std::fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Authenticator")
+            .field("api_keys", &self.inner.api_keys.len())
+            .field("jwt_keys", &self.inner.jwt_keys.len())
+            .finish()
+    }
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Result<Self, AuthError> {
+        let api_keys = config
+            .api_keys
+            .iter()
+            .map(|key| {
+                let principal = Principal {
+                    subject: key.principal.clone(),
+                    method: AuthMethod::ApiKey,
+                    roles: key.roles.clone(),
+                    tenant: key.tenant.clone().unwrap_or_default(),
+                };
+                (key.sha256.to_ascii_lowercase(), principal)
+            })
+            .collect();
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.algorithms = vec![Algorithm::HS256, Algorithm::RS256];
+
+        let jwt_keys = match &config.jwt {
+            Some(jwt) => {
+                validation.leeway = jwt.leeway_s;
+                if let Some(issuer) = &jwt.issuer {
+                    validation.set_issuer(&[issuer]);
+                }
+                if let Some(audience) = &jwt.audience {
+                    validation.set_audience(&[audience]);
+                }
+                read_jwks(&jwt.jwks)?
+            }
+            None => Vec::new(),
+        };
+
+        info!(
+            api_keys = config.api_keys.len(),
+            jwt_keys = jwt_keys.len(),
+            "authentication enabled"
+        );
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                api_keys,
+                tenants: config.tenants.clone(),
+                jwt_keys,
+                validation,
+            }),
+        })
+    }
+
+    /// Authenticate a request by the first of its credentials given: an API key, a bearer token
+    /// or a verified client certificate.
+    pub fn authenticate(
+        &self,
+        headers: &http::HeaderMap,
+        extensions: &http::Extensions,
+    ) -> Result<Principal, Status> {
+        let mut principal = self.credentials(headers, extensions)?;
+
+        if principal.tenant.is_empty() {
+            if let Some(tenant) = self.inner.tenants.get(&principal.subject) {
+                principal.tenant = tenant.clone();
+            }
+        }
+
+        Ok(principal)
+    }
+
+    fn credentials(
+        &self,
+        headers: &http::HeaderMap,
+        extensions: &http::Extensions,
+    ) -> Result<Principal, Status> {
+        if let Some(key) = headers.get(API_KEY_HEADER) {
+            let key = key
+                .to_str()
+                .map_err(|_| Status::unauthenticated("API key is malformed"))?;
+            return self.authenticate_api_key(key);
+        }
+
+        if let Some(authorization) = headers.get(http::header::AUTHORIZATION) {
+            let token = authorization
+                .to_str()
+                .ok()
+                .and_then(|authorization| authorization.strip_prefix(BEARER_PREFIX))
+                .ok_or_else(|| Status::unauthenticated("authorization is not a bearer token"))?;
+            return self.authenticate_jwt(token.trim());
+        }
+
+        let peer = extensions
+            .get::<TlsConnectInfo<TcpConnectInfo>>()
+            .and_then(|info| info.peer_certs())
+            .and_then(|certs| PeerIdentity::from_der(certs.first()?.get_ref()));
+        if let Some(subject) = peer.as_ref().and_then(PeerIdentity::name) {
+            return Ok(Principal {
+                subject: subject.to_string(),
+                method: AuthMethod::Certificate,
+                roles: Vec::new(),
+                tenant: String::new(),
+            });
+        }
+
+        Err(Status::unauthenticated("credentials are required"))
+    }
+
+    fn authenticate_api_key(&self, key: &str) -> Result<Principal, Status> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+        let digest: String = digest
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        match self.inner.api_keys.get(&digest) {
+            Some(principal) => Ok(principal.clone()),
+            None => Err(Status::unauthenticated("API key is unknown")),
+        }
+    }
+
+    fn authenticate_jwt(&self, token: &str) -> Result<Principal, Status> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|_| Status::unauthenticated("bearer token is malformed"))?;
+
+        let candidates = self.inner.jwt_keys.iter().filter(|key| {
+            key.algorithm == header.alg
+                && (header.kid.is_none() || key.id.as_ref() == header.kid.as_ref())
+        });
+
+        let mut last_error = None;
+        for key in candidates {
+            let mut validation = self.inner.validation.clone();
+            validation.algorithms = vec![key.algorithm];
+
+            match jsonwebtoken::decode::<Claims>(token, &key.key, &validation) {
+                Ok(data) => {
+                    return Ok(Principal {
+                        subject: data.claims.sub,
+                        method: AuthMethod::Jwt,
+                        roles: data.claims.roles,
+                        tenant: data.claims.tenant.unwrap_or_default(),
+                    })
+                }
+                Err(error) => last_error = Some(error),
+            }
+        }
+
+        match last_error {
+            Some(error) => {
+                debug!(%error, "bearer token refused");
+                Err(Status::unauthenticated(format!(
+                    "bearer token is invalid: {}",
+                    error
+                )))
+            }
+            None => Err(Status::unauthenticated(
+                "bearer token is signed by no known key",
+            )),
+        }
+    }
+}
+
+/// Whether a method is a health check, admitted without credentials or limits.
+pub(crate) fn method_exempt(path: &str) -> bool {
+    matches!(path.rsplit_once('/'), Some((_, "Check" | "Watch")))
+}
+
+impl<B> Predicate<http::Request<B>> for Authenticator {
+    type Request = http::Request<B>;
+
+    fn check(&mut self, mut request: http::Request<B>) -> Result<Self::Request, BoxError> {
+        if method_exempt(request.uri().path()) {
+            return Ok(request);
+        }
+
+        match self.authenticate(request.headers(), request.extensions()) {
+            Ok(principal) => {
+                debug!(?principal, "request authenticated");
+                request.extensions_mut().insert(principal);
+                Ok(request)
+            }
+            Err(status) => {
+                info!(status = ?&status, path = request.uri().path(), "request failed");
+                Err(Box::new(status))
+            }
+        }
+    }
+}
+
+fn read_jwks(path: &Path) -> Result<Vec<JwtKey>, AuthError> {
+    let content = std::fs::read(path).map_err(|source| AuthError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let jwks: JwkSet = serde_json::from_slice(&content).map_err(|source| AuthError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })?;
+
+    let mut keys = Vec::new();
+    for jwk in jwks.keys {
+        let id = jwk.common.key_id.clone();
+        let key = match &jwk.algorithm {
+            // The secret of an `oct` key is base64url encoded, which `DecodingKey::from_jwk`
+            // mistakes for standard base64.
+            AlgorithmParameters::OctetKey(params) => URL_SAFE_NO_PAD
+                .decode(params.value.trim_end_matches('='))
+                .ok()
+                .map(|secret| (Algorithm::HS256, DecodingKey::from_secret(&secret))),
+            AlgorithmParameters::RSA(_) => DecodingKey::from_jwk(&jwk)
+                .ok()
+                .map(|key| (Algorithm::RS256, key)),
+            _ => None,
+        };
+
+        match key {
+            Some((algorithm, key))
+                if jwk
+                    .common
+                    .algorithm
+                    .is_none_or(|declared| declared == algorithm) =>
+            {
+                keys.push(JwtKey { id, algorithm, key })
+            }
+            _ => warn!(kid = ?id, "skipping a key other than HS256 or RS256"),
+        }
+    }
+
+    match keys.is_empty() {
+        true => Err(AuthError::NoKeys {
+            path: path.to_path_buf(),
+        }),
+        false => Ok(keys),
+    }
+}
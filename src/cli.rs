@@ -0,0 +1,485 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::{Args, ValueEnum};
+use tokio::fs::File;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::AsciiMetadataValue;
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+use tonic::{Request, Status};
+
+use pine5_cm_service::auth::{API_KEY_HEADER, BEARER_PREFIX};
+use pine5_cm_service::config::{
+    Config, ConfigError, FsyncMode, MetricsConfig, OtlpConfig, StorageBackend, TlsConfig,
+};
+use pine5_cm_service::database::RegistrationPolicy;
+use pine5_cm_service::rpc::cm::{
+    cm_admin_client::CmAdminClient, ImportConflict, TokenExportRequest, TokenImportRequest,
+    TransferFormat,
+};
+use pine5_cm_service::tenant::TENANT_HEADER;
+
+/// Size of the chunks an import is streamed in.
+const IMPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// One JSON object per token and line.
+    Jsonl,
+    /// Length-delimited `cm.Token` protobuf messages.
+    Delimited,
+}
+
+impl From<Format> for TransferFormat {
+    fn from(source: Format) -> Self {
+        match source {
+            Format::Jsonl => TransferFormat::TransferJsonl,
+            Format::Delimited => TransferFormat::TransferDelimited,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Conflict {
+    /// Keep the registered token.
+    Skip,
+    /// Replace the registered token.
+    Overwrite,
+    /// Keep the token with the later timestamp.
+    Newer,
+}
+
+impl From<Conflict> for ImportConflict {
+    fn from(source: Conflict) -> Self {
+        match source {
+            Conflict::Skip => ImportConflict::ImportSkip,
+            Conflict::Overwrite => ImportConflict::ImportOverwrite,
+            Conflict::Newer => ImportConflict::ImportNewer,
+        }
+    }
+}
+
+/// Flags of the server, each of which overrides the configuration file and may be given in the
+/// environment instead.
+#[derive(Debug, Default, Args)]
+pub struct ServeArgs {
+    /// TOML configuration file.
+    #[arg(long, short, env = "CM_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "CM_LISTEN")]
+    listen: Option<SocketAddr>,
+    /// Address to serve Prometheus metrics at, over HTTP at `/metrics`.
+    #[arg(long, env = "CM_METRICS_LISTEN")]
+    metrics_listen: Option<SocketAddr>,
+    /// Url of the OpenTelemetry collector to post the spans to over OTLP/HTTP.
+    #[arg(long, env = "CM_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+    /// PEM certificate chain to serve over TLS with.
+    #[arg(long, env = "CM_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the TLS certificate.
+    #[arg(long, env = "CM_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// PEM certificates of the authorities client certificates are verified against, requiring
+    /// mutual TLS.
+    #[arg(long, env = "CM_TLS_CLIENT_CA")]
+    tls_client_ca: Option<PathBuf>,
+    /// One of `error`, `warn`, `info`, `debug` or `trace`.
+    #[arg(long, env = "CM_LOG_LEVEL")]
+    log_level: Option<String>,
+    /// One of `memory`, `sharded` or `durable`.
+    #[arg(long, env = "CM_STORAGE_BACKEND")]
+    storage_backend: Option<StorageBackend>,
+    /// Directory of the durable storage.
+    #[arg(long, env = "CM_STORAGE_PATH")]
+    storage_path: Option<PathBuf>,
+    #[arg(long, env = "CM_STORAGE_SHARDS")]
+    storage_shards: Option<usize>,
+    /// One of `always`, `interval` or `never`.
+    #[arg(long, env = "CM_STORAGE_FSYNC")]
+    storage_fsync: Option<FsyncMode>,
+    /// One of `overwrite`, `reject_conflicting` or `reject_existing`.
+    #[arg(long, env = "CM_REGISTRATION_POLICY")]
+    registration_policy: Option<RegistrationPolicy>,
+    #[arg(long, env = "CM_TOKEN_BROADCAST_CAPACITY")]
+    token_broadcast_capacity: Option<usize>,
+    #[arg(long, env = "CM_MESSAGE_BROADCAST_CAPACITY")]
+    message_broadcast_capacity: Option<usize>,
+    #[arg(long, env = "CM_MESSAGE_DISPATCH_CAPACITY")]
+    message_dispatch_capacity: Option<usize>,
+    /// Milliseconds given to draining subscribers and flushing storage on shutdown.
+    #[arg(long, env = "CM_SHUTDOWN_DEADLINE_MS")]
+    shutdown_deadline_ms: Option<u64>,
+}
+
+impl ServeArgs {
+    /// Layer the flags over the configuration file, if any, over the defaults, and validate the
+    /// outcome.
+    pub fn config(self) -> Result<Config, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        if let Some(listen) = self.listen {
+            config.listen = listen;
+        }
+        if let Some(listen) = self.metrics_listen {
+            config.metrics = Some(MetricsConfig { listen });
+        }
+        if let Some(endpoint) = self.otlp_endpoint {
+            match config.otlp.as_mut() {
+                Some(otlp) => otlp.endpoint = endpoint,
+                None => config.otlp = Some(OtlpConfig::new(endpoint)),
+            }
+        }
+        match (config.tls.as_mut(), self.tls_cert, self.tls_key) {
+            (Some(tls), cert, key) => {
+                if let Some(cert) = cert {
+                    tls.cert = cert;
+                }
+                if let Some(key) = key {
+                    tls.key = key;
+                }
+            }
+            (None, Some(cert), Some(key)) => config.tls = Some(TlsConfig::new(cert, key)),
+            (None, None, None) => {}
+            (None, _, _) => {
+                return Err(ConfigError::Invalid(vec![
+                    "tls cert and key must be given together".to_string(),
+                ]))
+            }
+        }
+        if let Some(client_ca) = self.tls_client_ca {
+            match config.tls.as_mut() {
+                Some(tls) => tls.client_ca = Some(client_ca),
+                None => {
+                    return Err(ConfigError::Invalid(vec![
+                        "tls client CA requires a tls cert and key".to_string(),
+                    ]))
+                }
+            }
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = Some(log_level);
+        }
+        if let Some(backend) = self.storage_backend {
+            config.storage.backend = backend;
+        }
+        if let Some(path) = self.storage_path {
+            config.storage.path = Some(path);
+        }
+        if let Some(shards) = self.storage_shards {
+            config.storage.shards = shards;
+        }
+        if let Some(fsync) = self.storage_fsync {
+            config.storage.fsync = fsync;
+        }
+        if let Some(policy) = self.registration_policy {
+            config.registration_policy = policy;
+        }
+        if let Some(capacity) = self.token_broadcast_capacity {
+            config.channels.token_broadcast = capacity;
+        }
+        if let Some(capacity) = self.message_broadcast_capacity {
+            config.channels.message_broadcast = capacity;
+        }
+        if let Some(capacity) = self.message_dispatch_capacity {
+            config.channels.message_dispatch = capacity;
+        }
+        if let Some(deadline) = self.shutdown_deadline_ms {
+            config.shutdown_deadline_ms = deadline;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Flags of a client of a running service: where it is, and how to authenticate to it.
+#[derive(Debug, Args)]
+pub struct ConnectArgs {
+    /// Address of the running service.
+    #[arg(long, default_value = "http://[::1]:10000")]
+    addr: String,
+    /// API key to authenticate with.
+    #[arg(long, env = "CM_API_KEY", conflicts_with = "bearer")]
+    api_key: Option<String>,
+    /// JWT to authenticate with as a bearer token.
+    #[arg(long, env = "CM_BEARER")]
+    bearer: Option<String>,
+    /// Tenant to act within, if the credentials do not name one.
+    #[arg(long, env = "CM_TENANT")]
+    tenant: Option<String>,
+    /// PEM certificates of the authorities the certificate of the service is verified against,
+    /// connecting over TLS.
+    #[arg(long)]
+    ca: Option<PathBuf>,
+    /// PEM certificate chain to present to the service, connecting over TLS.
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    /// PEM private key of the client certificate.
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+}
+
+/// Metadata attached to every request, authenticating the caller.
+#[derive(Debug, Clone, Default)]
+struct Credentials(Vec<(&'static str, AsciiMetadataValue)>);
+
+impl Interceptor for Credentials {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        for (key, value) in &self.0 {
+            request.metadata_mut().insert(*key, value.clone());
+        }
+        Ok(request)
+    }
+}
+
+impl ConnectArgs {
+    /// Connect to the service, over TLS if a certificate is given.
+    async fn connect(
+        &self,
+    ) -> Result<CmAdminClient<InterceptedService<Channel, Credentials>>, Box<dyn Error>> {
+        let mut endpoint = Channel::from_shared(self.addr.clone())?;
+
+        if self.ca.is_some() || self.cert.is_some() {
+            let mut tls = ClientTlsConfig::new();
+            if let Some(ca) = &self.ca {
+                tls = tls.ca_certificate(Certificate::from_pem(tokio::fs::read(ca).await?));
+            }
+            if let (Some(cert), Some(key)) = (&self.cert, &self.key) {
+                tls = tls.identity(Identity::from_pem(
+                    tokio::fs::read(cert).await?,
+                    tokio::fs::read(key).await?,
+                ));
+            }
+            endpoint = endpoint.tls_config(tls)?;
+        }
+
+        let mut credentials = Credentials::default();
+        if let Some(key) = &self.api_key {
+            credentials.0.push((API_KEY_HEADER, key.parse()?));
+        }
+        if let Some(token) = &self.bearer {
+            let authorization = format!("{}{}", BEARER_PREFIX, token).parse()?;
+            credentials.0.push(("authorization", authorization));
+        }
+        if let Some(tenant) = &self.tenant {
+            credentials.0.push((TENANT_HEADER, tenant.parse()?));
+        }
+
+        let channel = endpoint.connect().await?;
+        Ok(CmAdminClient::with_interceptor(channel, credentials))
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    #[command(flatten)]
+    connect: ConnectArgs,
+    #[arg(long, value_enum, default_value_t = Format::Jsonl)]
+    format: Format,
+    /// File to write the tokens to, instead of the standard output.
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    #[command(flatten)]
+    connect: ConnectArgs,
+    #[arg(long, value_enum, default_value_t = Format::Jsonl)]
+    format: Format,
+    /// How a token is treated when a token with the same key is registered.
+    #[arg(long, value_enum, default_value_t = Conflict::Skip)]
+    conflict: Conflict,
+    /// File to read the tokens from, instead of the standard input.
+    #[arg(long, short)]
+    input: Option<PathBuf>,
+}
+
+/// Stream every token out of a running service.
+pub async fn export(args: ExportArgs) -> Result<(), Box<dyn Error>> {
+    let mut client = args.connect.connect().await?;
+
+    let mut output: Box<dyn AsyncWrite + Unpin> = match &args.output {
+        Some(path) => Box::new(File::create(path).await?),
+        None => Box::new(io::stdout()),
+    };
+
+    let mut stream = client
+        .token_export(TokenExportRequest {
+            format: TransferFormat::from(args.format) as i32,
+        })
+        .await?
+        .into_inner();
+
+    while let Some(chunk) = stream.message().await? {
+        output.write_all(&chunk.data).await?;
+    }
+    output.flush().await?;
+
+    Ok(())
+}
+
+/// Stream tokens into a running service, reporting the summary of the import.
+pub async fn import(args: ImportArgs) -> Result<(), Box<dyn Error>> {
+    let mut client = args.connect.connect().await?;
+
+    let mut input: Box<dyn AsyncRead + Send + Unpin> = match &args.input {
+        Some(path) => Box::new(File::open(path).await?),
+        None => Box::new(io::stdin()),
+    };
+
+    let format = TransferFormat::from(args.format) as i32;
+    let conflict = ImportConflict::from(args.conflict) as i32;
+
+    let (tx, rx) = mpsc::channel(4);
+
+    let reader = tokio::spawn(async move {
+        loop {
+            let mut data = vec![0; IMPORT_CHUNK_SIZE];
+            let read = input.read(&mut data).await?;
+            if read == 0 {
+                break;
+            }
+            data.truncate(read);
+
+            let request = TokenImportRequest {
+                format,
+                conflict,
+                data,
+            };
+            if tx.send(request).await.is_err() {
+                break;
+            }
+        }
+
+        Ok::<_, io::Error>(())
+    });
+
+    let summary = client
+        .token_import(ReceiverStream::new(rx))
+        .await?
+        .into_inner();
+    reader.await??;
+
+    eprintln!(
+        "received {}, created {}, overwritten {}, skipped {}",
+        summary.received, summary.created, summary.overwritten, summary.skipped
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Debug, Parser)]
+    struct Serve {
+        #[command(flatten)]
+        args: ServeArgs,
+    }
+
+    /// Configuration file removed once dropped.
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(content: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("cm-config-{}.toml", uuid::Uuid::new_v4()));
+            std::fs::write(&path, content).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn parse(flags: &[&str]) -> Result<Config, ConfigError> {
+        let args = std::iter::once("serve").chain(flags.iter().copied());
+        Serve::try_parse_from(args).unwrap().args.config()
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let file = ConfigFile::new(
+            r#"
+            listen = "127.0.0.1:7000"
+
+            [storage]
+            backend = "sharded"
+            shards = 8
+            "#,
+        );
+        let path = file.0.to_str().unwrap();
+
+        let config = parse(&["--config", path]).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:7000".parse().unwrap());
+        assert_eq!(config.storage.shards, 8);
+
+        let config = parse(&["--config", path, "--storage-shards", "4"]).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:7000".parse().unwrap());
+        assert_eq!(config.storage.backend, StorageBackend::Sharded);
+        assert_eq!(config.storage.shards, 4);
+    }
+
+    #[test]
+    fn environment_overrides_the_file_and_flags_the_environment() {
+        let file = ConfigFile::new(r#"registration_policy = "overwrite""#);
+        let path = file.0.to_str().unwrap();
+
+        // No other test reads the variable, so setting it does not race with them.
+        std::env::set_var("CM_REGISTRATION_POLICY", "reject_existing");
+        let from_env = parse(&["--config", path]);
+        let from_flag = parse(&[
+            "--config",
+            path,
+            "--registration-policy",
+            "reject_conflicting",
+        ]);
+        std::env::remove_var("CM_REGISTRATION_POLICY");
+
+        assert_eq!(
+            from_env.unwrap().registration_policy,
+            RegistrationPolicy::RejectExisting
+        );
+        assert_eq!(
+            from_flag.unwrap().registration_policy,
+            RegistrationPolicy::RejectConflicting
+        );
+    }
+
+    #[test]
+    fn overridden_configuration_is_validated() {
+        let file = ConfigFile::new("[storage]\nbackend = \"durable\"\npath = \"/var/lib/cm\"");
+        let path = file.0.to_str().unwrap();
+        assert!(parse(&["--config", path]).is_ok());
+
+        match parse(&["--config", path, "--storage-shards", "0"]) {
+            Err(ConfigError::Invalid(problems)) => {
+                assert_eq!(problems, vec!["storage.shards must be positive"])
+            }
+            other => panic!("unexpected outcome {:?}", other),
+        }
+
+        match parse(&["--tls-cert", "server.pem"]) {
+            Err(ConfigError::Invalid(problems)) => {
+                assert_eq!(problems, vec!["tls cert and key must be given together"])
+            }
+            other => panic!("unexpected outcome {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,737 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::Level;
+
+use crate::access::Permission;
+use crate::database::{FsyncPolicy, RegistrationPolicy, WalOptions, DEFAULT_SHARDS};
+use crate::limit::METHODS;
+use crate::rpc::cm_token::TOKEN_BATCH_MAX;
+
+/// Configuration of the server, as read from a TOML file and overridden by the environment and
+/// command line flags.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    /// Serve over TLS rather than in plaintext, if given.
+    pub tls: Option<TlsConfig>,
+    /// Require the clients to authenticate, if given.
+    pub auth: Option<AuthConfig>,
+    /// One of `error`, `warn`, `info`, `debug` or `trace`. Defaults to `debug` in debug builds
+    /// and `info` otherwise.
+    pub log_level: Option<String>,
+    /// Time given to draining the subscribers and flushing the storage on shutdown, after which
+    /// the server exits regardless.
+    pub shutdown_deadline_ms: u64,
+    pub storage: StorageConfig,
+    /// One of `overwrite`, `reject_conflicting` or `reject_existing`, telling how a registration
+    /// of a registered key is treated.
+    pub registration_policy: RegistrationPolicy,
+    pub channels: ChannelConfig,
+    /// Limits of the calls each client may make, all of which a call must be within.
+    pub rate_limits: Vec<RateLimitConfig>,
+    /// Cap the messages each token receives, if given.
+    pub throttle: Option<ThrottleConfig>,
+    /// Serve Prometheus metrics, if given.
+    pub metrics: Option<MetricsConfig>,
+    /// Export the spans to an OpenTelemetry collector, if given.
+    pub otlp: Option<OtlpConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain presented to the clients.
+    pub cert: PathBuf,
+    /// PEM private key of the certificate.
+    pub key: PathBuf,
+    /// PEM certificates of the authorities the certificates of the clients are verified
+    /// against. Clients must present a certificate if given.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// Interval at which the files are checked for changes, and reloaded if they changed.
+    #[serde(default = "TlsConfig::default_reload_interval_ms")]
+    pub reload_interval_ms: u64,
+}
+
+/// Credentials the clients authenticate with. A client certificate verified against
+/// `tls.client_ca` authenticates its client as well.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub api_keys: Vec<ApiKeyConfig>,
+    pub jwt: Option<JwtConfig>,
+    /// Roles by name. Every authenticated principal is permitted everything if none are given.
+    pub roles: HashMap<String, RoleConfig>,
+    /// Roles bound to principals by subject, besides those their credentials carry.
+    pub principals: HashMap<String, Vec<String>>,
+    /// Roles of the principals given none by their credentials or `principals`.
+    pub default_roles: Vec<String>,
+    /// Tenants of principals by subject, unless their credentials name one. Principals of neither
+    /// belong to the default tenant.
+    pub tenants: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Name of the principal the key authenticates.
+    pub principal: String,
+    /// Hex SHA-256 digest of the key, so that the key itself is kept out of the configuration.
+    pub sha256: String,
+    /// Roles of the principal.
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Tenant of the principal, if other than the default.
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoleConfig {
+    pub permissions: Vec<Permission>,
+    /// Restrict registering and updating tokens to those owned by the principal. A token
+    /// registered without an owner is assigned to the principal.
+    #[serde(default)]
+    pub own_tokens: bool,
+    /// Restrict registering and updating tokens to those whose key starts with one of the
+    /// prefixes, if given.
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    /// JWKS file of the keys bearer tokens are verified with: `oct` keys for HS256 and `RSA` keys
+    /// for RS256.
+    pub jwks: PathBuf,
+    /// Issuer the tokens must be issued by, if given.
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// Audience the tokens must be issued for, if given.
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// Clock skew tolerated when checking the expiry of the tokens.
+    #[serde(default = "JwtConfig::default_leeway_s")]
+    pub leeway_s: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// A single locked map, lost on exit.
+    Memory,
+    /// A map striped across independently locked shards, lost on exit.
+    Sharded,
+    /// A single locked map, logged ahead and snapshotted to `path`.
+    Durable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncMode {
+    Always,
+    Interval,
+    Never,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Directory of the log and snapshots of the durable backend.
+    pub path: Option<PathBuf>,
+    /// Number of shards of the sharded backend.
+    pub shards: usize,
+    pub fsync: FsyncMode,
+    pub fsync_interval_ms: u64,
+    /// Number of records logged after which the log is compacted into a snapshot.
+    pub snapshot_every: usize,
+}
+
+/// Capacities of the channels between the RPCs and their subscribers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelConfig {
+    pub token_broadcast: usize,
+    pub token_stream: usize,
+    pub message_broadcast: usize,
+    pub message_dispatch: usize,
+    pub message_stream: usize,
+}
+
+/// What the calls limited by a rate limit are counted per.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RatePer {
+    /// The authenticated principal, or the peer address if not authenticated.
+    Principal,
+    /// The tenant of the authenticated principal, or the peer address if not authenticated.
+    Tenant,
+}
+
+/// Token bucket limiting the rate of the calls to each RPC, along with a daily quota.
+///
+/// The calls are counted apart per RPC, and a streaming RPC counts as a single call, but for the
+/// send RPCs which count each of their messages as a call.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Name of the RPC limited, such as `MessageSend`, or every RPC if not given.
+    #[serde(default)]
+    pub method: Option<String>,
+    pub per: RatePer,
+    /// Rate at which the bucket refills, in calls per second.
+    pub rate_per_s: f64,
+    /// Calls which may be made at once, as the capacity of the bucket.
+    pub burst: u32,
+    /// Calls allowed per day, reset at midnight UTC, if given.
+    #[serde(default)]
+    pub daily_quota: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address the metrics are served at over HTTP, at `/metrics`.
+    pub listen: SocketAddr,
+}
+
+/// Export of the spans to an OpenTelemetry collector over OTLP/HTTP.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpConfig {
+    /// Url of the collector the spans are posted to, such as `http://localhost:4318/v1/traces`.
+    pub endpoint: String,
+    /// Name the service is reported as.
+    #[serde(default = "OtlpConfig::default_service_name")]
+    pub service_name: String,
+    /// Share of the traces started by the service which are exported. Traces continued from a
+    /// caller are exported if the caller exports them.
+    #[serde(default = "OtlpConfig::default_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+/// What becomes of a message to a token which received as many messages as it may.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThrottlePolicy {
+    /// Drop the message for the token, reported as throttled.
+    #[default]
+    Drop,
+    /// Hold the message back until the token may receive it, reported as delayed.
+    Delay,
+    /// Hold the message back like `delay`, replacing the message held back for the token
+    /// before, which is reported as collapsed.
+    Collapse,
+}
+
+/// Cap on the messages each token receives, protecting the devices from a misbehaving sender
+/// regardless of the rate limits of the callers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// Messages a token may receive per minute, if capped.
+    #[serde(default)]
+    pub per_minute: Option<u32>,
+    /// Messages a token may receive per hour, if capped.
+    #[serde(default)]
+    pub per_hour: Option<u32>,
+    #[serde(default)]
+    pub policy: ThrottlePolicy,
+    /// Messages which may be held back for a token under `delay` or `collapse`, past which
+    /// messages to it are dropped.
+    #[serde(default = "ThrottleConfig::default_max_held")]
+    pub max_held: usize,
+}
+
+impl ThrottleConfig {
+    fn default_max_held() -> usize {
+        100
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read `{}`: {source}", .path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("failed to parse `{}`: {source}", .path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("{}", .0.join("; "))]
+    Invalid(Vec<String>),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: "[::1]:10000".parse().unwrap(),
+            tls: None,
+            auth: None,
+            log_level: None,
+            shutdown_deadline_ms: 10_000,
+            storage: StorageConfig::default(),
+            registration_policy: RegistrationPolicy::default(),
+            channels: ChannelConfig::default(),
+            rate_limits: Vec::new(),
+            throttle: None,
+            metrics: None,
+            otlp: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn new(cert: PathBuf, key: PathBuf) -> Self {
+        Self {
+            cert,
+            key,
+            client_ca: None,
+            reload_interval_ms: Self::default_reload_interval_ms(),
+        }
+    }
+
+    fn default_reload_interval_ms() -> u64 {
+        10_000
+    }
+
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_millis(self.reload_interval_ms)
+    }
+}
+
+impl OtlpConfig {
+    pub fn new(endpoint: String) -> Self {
+        Self {
+            endpoint,
+            service_name: Self::default_service_name(),
+            sample_ratio: Self::default_sample_ratio(),
+        }
+    }
+
+    fn default_service_name() -> String {
+        env!("CARGO_PKG_NAME").to_string()
+    }
+
+    fn default_sample_ratio() -> f64 {
+        1.0
+    }
+}
+
+impl JwtConfig {
+    fn default_leeway_s() -> u64 {
+        60
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        let wal = WalOptions::default();
+        Self {
+            backend: StorageBackend::Memory,
+            path: None,
+            shards: DEFAULT_SHARDS,
+            fsync: FsyncMode::Interval,
+            fsync_interval_ms: 1000,
+            snapshot_every: wal.snapshot_every,
+        }
+    }
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            // Holds the broadcasts of a whole batch registration which is not coalesced.
+            token_broadcast: TOKEN_BATCH_MAX,
+            token_stream: 4,
+            message_broadcast: 16,
+            message_dispatch: 16,
+            message_stream: 4,
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from a TOML file, defaulting whatever it leaves out.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        toml::from_str(&content).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Check the configuration as a whole, reporting every problem found at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if let Err(error) = self.log_level() {
+            problems.push(error);
+        }
+
+        if self.shutdown_deadline_ms == 0 {
+            problems.push("shutdown_deadline_ms must be positive".to_string());
+        }
+
+        if let Some(tls) = &self.tls {
+            for (name, path) in [
+                ("tls.cert", Some(&tls.cert)),
+                ("tls.key", Some(&tls.key)),
+                ("tls.client_ca", tls.client_ca.as_ref()),
+            ] {
+                if let Some(path) = path.filter(|path| !path.is_file()) {
+                    problems.push(format!("{} `{}` is not a file", name, path.display()));
+                }
+            }
+            if tls.reload_interval_ms == 0 {
+                problems.push("tls.reload_interval_ms must be positive".to_string());
+            }
+        }
+
+        if let Some(auth) = &self.auth {
+            let client_ca = self.tls.as_ref().is_some_and(|tls| tls.client_ca.is_some());
+            if auth.api_keys.is_empty() && auth.jwt.is_none() && !client_ca {
+                problems.push(
+                    "auth admits no credentials; give auth.api_keys, auth.jwt or tls.client_ca"
+                        .to_string(),
+                );
+            }
+            for (index, key) in auth.api_keys.iter().enumerate() {
+                if key.principal.is_empty() {
+                    problems.push(format!("auth.api_keys[{}].principal is required", index));
+                }
+                if key.sha256.len() != 64 || !key.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                    problems.push(format!(
+                        "auth.api_keys[{}].sha256 is not a hex SHA-256 digest",
+                        index
+                    ));
+                }
+            }
+            let roles = auth
+                .api_keys
+                .iter()
+                .enumerate()
+                .flat_map(|(index, key)| {
+                    key.roles
+                        .iter()
+                        .map(move |role| (format!("auth.api_keys[{}].roles", index), role))
+                })
+                .chain(auth.principals.iter().flat_map(|(subject, roles)| {
+                    roles
+                        .iter()
+                        .map(move |role| (format!("auth.principals.{}", subject), role))
+                }))
+                .chain(
+                    auth.default_roles
+                        .iter()
+                        .map(|role| ("auth.default_roles".to_string(), role)),
+                );
+            for (name, role) in roles {
+                if !auth.roles.contains_key(role) {
+                    problems.push(format!("{} names the unknown role `{}`", name, role));
+                }
+            }
+            if let Some(jwt) = auth.jwt.as_ref().filter(|jwt| !jwt.jwks.is_file()) {
+                problems.push(format!(
+                    "auth.jwt.jwks `{}` is not a file",
+                    jwt.jwks.display()
+                ));
+            }
+        }
+
+        let storage = &self.storage;
+        if storage.backend == StorageBackend::Durable && storage.path.is_none() {
+            problems.push("storage.path is required by the durable backend".to_string());
+        }
+        if storage.shards == 0 {
+            problems.push("storage.shards must be positive".to_string());
+        }
+        if storage.snapshot_every == 0 {
+            problems.push("storage.snapshot_every must be positive".to_string());
+        }
+        if storage.fsync == FsyncMode::Interval && storage.fsync_interval_ms == 0 {
+            problems.push("storage.fsync_interval_ms must be positive".to_string());
+        }
+
+        let channels = &self.channels;
+        for (name, capacity) in [
+            ("channels.token_broadcast", channels.token_broadcast),
+            ("channels.token_stream", channels.token_stream),
+            ("channels.message_broadcast", channels.message_broadcast),
+            ("channels.message_dispatch", channels.message_dispatch),
+            ("channels.message_stream", channels.message_stream),
+        ] {
+            if capacity == 0 {
+                problems.push(format!("{} must be positive", name));
+            }
+        }
+        if channels.token_broadcast < TOKEN_BATCH_MAX {
+            problems.push(format!(
+                "channels.token_broadcast must be at least {}, the tokens of a batch",
+                TOKEN_BATCH_MAX
+            ));
+        }
+
+        for (index, limit) in self.rate_limits.iter().enumerate() {
+            if let Some(method) = limit
+                .method
+                .as_ref()
+                .filter(|method| !METHODS.contains(&method.as_str()))
+            {
+                problems.push(format!(
+                    "rate_limits[{}].method `{}` is not an RPC",
+                    index, method
+                ));
+            }
+            if !(limit.rate_per_s.is_finite() && limit.rate_per_s > 0.0) {
+                problems.push(format!(
+                    "rate_limits[{}].rate_per_s must be positive",
+                    index
+                ));
+            }
+            if limit.burst == 0 {
+                problems.push(format!("rate_limits[{}].burst must be positive", index));
+            }
+            if limit.daily_quota == Some(0) {
+                problems.push(format!(
+                    "rate_limits[{}].daily_quota must be positive",
+                    index
+                ));
+            }
+        }
+
+        if self
+            .metrics
+            .as_ref()
+            .is_some_and(|metrics| metrics.listen == self.listen)
+        {
+            problems.push("metrics.listen must differ from listen".to_string());
+        }
+
+        if let Some(otlp) = &self.otlp {
+            let endpoint = otlp.endpoint.parse::<http::Uri>().ok();
+            if !endpoint.is_some_and(|endpoint| {
+                endpoint.scheme_str() == Some("http") && endpoint.host().is_some()
+            }) {
+                problems.push(format!(
+                    "otlp.endpoint `{}` is not an http url",
+                    otlp.endpoint
+                ));
+            }
+            if !(0.0..=1.0).contains(&otlp.sample_ratio) {
+                problems.push("otlp.sample_ratio must be between 0 and 1".to_string());
+            }
+        }
+
+        if let Some(throttle) = &self.throttle {
+            if throttle.per_minute.is_none() && throttle.per_hour.is_none() {
+                problems.push("throttle requires per_minute or per_hour".to_string());
+            }
+            for (name, limit) in [
+                ("throttle.per_minute", throttle.per_minute),
+                ("throttle.per_hour", throttle.per_hour),
+            ] {
+                if limit == Some(0) {
+                    problems.push(format!("{} must be positive", name));
+                }
+            }
+            if throttle.max_held == 0 {
+                problems.push("throttle.max_held must be positive".to_string());
+            }
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError::Invalid(problems)),
+        }
+    }
+
+    pub fn log_level(&self) -> Result<Level, String> {
+        match &self.log_level {
+            Some(level) => Level::from_str(level)
+                .map_err(|_| format!("log_level `{}` is not a log level", level)),
+            None if cfg!(debug_assertions) => Ok(Level::DEBUG),
+            None => Ok(Level::INFO),
+        }
+    }
+
+    pub fn shutdown_deadline(&self) -> Duration {
+        Duration::from_millis(self.shutdown_deadline_ms)
+    }
+
+    pub fn wal_options(&self) -> WalOptions {
+        WalOptions {
+            fsync: match self.storage.fsync {
+                FsyncMode::Always => FsyncPolicy::Always,
+                FsyncMode::Interval => {
+                    FsyncPolicy::Interval(Duration::from_millis(self.storage.fsync_interval_ms))
+                }
+                FsyncMode::Never => FsyncPolicy::Never,
+            },
+            snapshot_every: self.storage.snapshot_every,
+        }
+    }
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(StorageBackend::Memory),
+            "sharded" => Ok(StorageBackend::Sharded),
+            "durable" => Ok(StorageBackend::Durable),
+            _ => Err(format!(
+                "`{}` is not one of `memory`, `sharded` or `durable`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StorageBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StorageBackend::Memory => "memory",
+            StorageBackend::Sharded => "sharded",
+            StorageBackend::Durable => "durable",
+        })
+    }
+}
+
+impl FromStr for FsyncMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncMode::Always),
+            "interval" => Ok(FsyncMode::Interval),
+            "never" => Ok(FsyncMode::Never),
+            _ => Err(format!(
+                "`{}` is not one of `always`, `interval` or `never`",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(config: &str) -> Vec<String> {
+        let config: Config = toml::from_str(config).unwrap();
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(ConfigError::Invalid(problems)) => problems,
+            Err(error) => panic!("unexpected error {}", error),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid_and_kept_in_memory() {
+        let config: Config = toml::from_str("").unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.storage.backend, StorageBackend::Memory);
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let problems = problems(
+            r#"
+            shutdown_deadline_ms = 0
+
+            [storage]
+            backend = "durable"
+            shards = 0
+
+            [otlp]
+            endpoint = "localhost:4318"
+
+            [throttle]
+            max_held = 1
+            "#,
+        );
+
+        assert_eq!(
+            problems,
+            vec![
+                "shutdown_deadline_ms must be positive",
+                "storage.path is required by the durable backend",
+                "storage.shards must be positive",
+                "otlp.endpoint `localhost:4318` is not an http url",
+                "throttle requires per_minute or per_hour",
+            ]
+        );
+    }
+
+    #[test]
+    fn auth_must_admit_credentials_of_known_roles() {
+        assert_eq!(
+            problems("[auth]"),
+            vec!["auth admits no credentials; give auth.api_keys, auth.jwt or tls.client_ca"]
+        );
+
+        let problems = problems(
+            r#"
+            [[auth.api_keys]]
+            principal = "backend"
+            sha256 = "17e28982bfcc0338d260fa11869d993f2634dd85f28924e9e30e1f78dcf00336"
+            roles = ["sender"]
+            "#,
+        );
+        assert_eq!(
+            problems,
+            vec!["auth.api_keys[0].roles names the unknown role `sender`"]
+        );
+    }
+
+    #[test]
+    fn rate_limits_name_existing_rpcs() {
+        let problems = problems(
+            r#"
+            [[rate_limits]]
+            method = "MessageSend"
+            per = "principal"
+            rate_per_s = 10.0
+            burst = 10
+
+            [[rate_limits]]
+            method = "MessageShout"
+            per = "tenant"
+            rate_per_s = 0.0
+            burst = 10
+            "#,
+        );
+
+        assert_eq!(
+            problems,
+            vec![
+                "rate_limits[1].method `MessageShout` is not an RPC",
+                "rate_limits[1].rate_per_s must be positive",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_fields_are_refused() {
+        assert!(toml::from_str::<Config>("[providers.fcm]\ncredentials = \"key.json\"").is_err());
+        assert!(toml::from_str::<Config>("[storage]\nbackend = \"disk\"").is_err());
+    }
+}
@@ -1,26 +1,316 @@
 use crate::model;
-use std::collections::HashMap;
+use crate::topic::Condition;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
+use thiserror::Error;
 use tokio::sync::Mutex;
 use tonic::async_trait;
-use tracing::{debug, info, trace};
+use tracing::{debug, info, warn};
+
+mod metered;
+mod sharded;
+mod wal;
+
+pub use metered::TokenDbMetered;
+pub use sharded::{TokenDbSharded, DEFAULT_SHARDS};
+pub use wal::{FsyncPolicy, WalOptions};
+
+use wal::{Frame, Record, Snapshot, Wal};
 
 #[async_trait]
 pub trait TokenDb: Send + Sync + 'static {
-    async fn insert(&self, token: model::TokenKey) -> Result<model::Token, TokenDbError>;
-    async fn update(&self, token: model::TokenKey) -> Result<model::TokenUpdate, TokenDbError>;
+    async fn get(&self, token: model::TokenKey) -> Result<Option<model::Token>, TokenDbError>;
+    /// Get many tokens at once, each of them or none in the order of the keys.
+    async fn get_many(
+        &self,
+        tokens: Vec<model::TokenKey>,
+    ) -> Result<Vec<Option<model::Token>>, TokenDbError>;
+    /// Insert a token, refreshing the token registered with the same key if there is one, which
+    /// keeps its topic subscriptions and its owner unless the inserted token sets them.
+    ///
+    /// The registered token is checked against the policy in the same step, so that a concurrent
+    /// registration of the same key cannot slip in between.
+    async fn insert(
+        &self,
+        token: model::Token,
+        policy: RegistrationPolicy,
+    ) -> Result<model::TokenInsert, TokenDbError>;
+    /// Insert many tokens at once, with an outcome for each of them.
+    async fn insert_many(
+        &self,
+        tokens: Vec<model::Token>,
+        policy: RegistrationPolicy,
+    ) -> Vec<Result<model::TokenInsert, TokenDbError>>;
+    /// Refresh a token, provided it is at the expected version if one is given.
+    async fn update(
+        &self,
+        token: model::TokenKey,
+        expected_version: Option<u64>,
+    ) -> Result<model::TokenUpdate, TokenDbError>;
     async fn invalidate(&self, token: model::TokenKey) -> Result<(), TokenDbError>;
+
+    /// Add the given topics to the subscriptions of a token.
+    async fn subscribe_topics(
+        &self,
+        token: model::TokenKey,
+        topics: Vec<Arc<str>>,
+    ) -> Result<model::TokenUpdate, TokenDbError>;
+    /// Remove the given topics from the subscriptions of a token.
+    async fn unsubscribe_topics(
+        &self,
+        token: model::TokenKey,
+        topics: Vec<Arc<str>>,
+    ) -> Result<model::TokenUpdate, TokenDbError>;
+    /// Select the keys of every token of the tenant whose topic subscriptions satisfy the
+    /// condition.
+    async fn select_topics(
+        &self,
+        tenant: &str,
+        condition: &Condition,
+    ) -> Result<Vec<model::TokenKey>, TokenDbError>;
+    /// Select the keys of every token of the tenant registered to the given owner.
+    async fn select_owner(
+        &self,
+        tenant: &str,
+        owner: &str,
+    ) -> Result<Vec<model::TokenKey>, TokenDbError>;
+    /// Select every token in the store, of every tenant.
+    async fn select_all(&self) -> Result<Vec<model::Token>, TokenDbError>;
+    /// Count the tokens in the store, of every tenant.
+    async fn count(&self) -> Result<usize, TokenDbError>;
+    /// Insert a restored token, unless the policy keeps the token registered with the same key.
+    ///
+    /// Returns `None` if the restored token was skipped.
+    async fn restore(
+        &self,
+        token: model::Token,
+        policy: ConflictPolicy,
+    ) -> Result<Option<model::TokenInsert>, TokenDbError>;
+    /// Bring what the store persists up to date with its tokens, as done before shutting down.
+    ///
+    /// Stores that persist nothing have nothing to flush.
+    async fn flush(&self) -> Result<(), TokenDbError> {
+        Ok(())
+    }
+}
+
+/// How a restored token is treated when a token with the same key is registered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The registered token is kept.
+    Skip,
+    /// The restored token replaces the registered one.
+    Overwrite,
+    /// The token with the later timestamp is kept.
+    Newer,
+}
+
+impl ConflictPolicy {
+    /// Whether a restored token is to replace the registered one, if any.
+    fn admits(&self, registered: Option<&model::Token>, restored: &model::Token) -> bool {
+        match (self, registered) {
+            (_, None) => true,
+            (ConflictPolicy::Skip, Some(_)) => false,
+            (ConflictPolicy::Overwrite, Some(_)) => true,
+            (ConflictPolicy::Newer, Some(registered)) => restored.timestamp > registered.timestamp,
+        }
+    }
+}
+
+/// How a registration of an already registered key is treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationPolicy {
+    /// The registration refreshes the registered token.
+    #[default]
+    Overwrite,
+    /// The registration is rejected if it declares another platform or owner than the registered token.
+    RejectConflicting,
+    /// The registration is rejected, as is any key repeated within a batch.
+    RejectExisting,
+}
+
+impl RegistrationPolicy {
+    /// Admit a token over the registered one, if any.
+    fn admit(
+        &self,
+        registered: Option<&model::Token>,
+        token: &model::Token,
+    ) -> Result<(), TokenDbError> {
+        let registered = match registered {
+            Some(registered) => registered,
+            None => return Ok(()),
+        };
+
+        let conflicting = registered.platform != token.platform
+            || (registered.owner.is_some()
+                && token.owner.is_some()
+                && registered.owner != token.owner);
+
+        match self {
+            RegistrationPolicy::Overwrite => Ok(()),
+            RegistrationPolicy::RejectConflicting if !conflicting => Ok(()),
+            RegistrationPolicy::RejectConflicting => {
+                Err(TokenDbError::RejectedConflicting(token.key.clone()))
+            }
+            RegistrationPolicy::RejectExisting => {
+                Err(TokenDbError::AlreadyExists(token.key.clone()))
+            }
+        }
+    }
+}
+
+impl FromStr for RegistrationPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(RegistrationPolicy::Overwrite),
+            "reject_conflicting" => Ok(RegistrationPolicy::RejectConflicting),
+            "reject_existing" => Ok(RegistrationPolicy::RejectExisting),
+            _ => Err(format!(
+                "`{}` is not one of `overwrite`, `reject_conflicting` or `reject_existing`",
+                s
+            )),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct TokenDbInMemory {
     db: Arc<Mutex<HashMap<model::TokenKey, model::Token>>>,
+    // Index from owners to the keys of their tokens, of every tenant. Only locked while `db` is
+    // held.
+    owners: Arc<Mutex<HashMap<Arc<str>, HashSet<model::TokenKey>>>>,
+    // Log every change is written to before it is applied, if durable. Only locked while `db` is held.
+    wal: Option<Arc<std::sync::Mutex<Wal>>>,
+    // Held while a snapshot is written, so that a single compaction runs at a time.
+    compacting: Arc<Mutex<()>>,
 }
 
 impl TokenDbInMemory {
     pub fn new() -> Self {
         Self {
             db: Arc::new(Mutex::new(HashMap::new())),
+            owners: Arc::new(Mutex::new(HashMap::new())),
+            wal: None,
+            compacting: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Open a durable store in `dir`, recovering its tokens from the snapshot and log found there.
+    pub fn open(dir: impl AsRef<Path>, options: WalOptions) -> io::Result<Self> {
+        let (wal, tokens) = Wal::open(dir, options)?;
+
+        let mut owners: HashMap<Arc<str>, HashSet<model::TokenKey>> = HashMap::new();
+        for token in tokens.values() {
+            if let Some(owner) = &token.owner {
+                owners
+                    .entry(owner.clone())
+                    .or_default()
+                    .insert(token.key.clone());
+            }
+        }
+
+        Ok(Self {
+            db: Arc::new(Mutex::new(tokens)),
+            owners: Arc::new(Mutex::new(owners)),
+            wal: Some(wal),
+            compacting: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Write changes to the log ahead of applying them, if the store is durable, as a single frame
+    /// synced at once.
+    ///
+    /// The file I/O runs off the workers of the runtime, while the caller holds the store locked,
+    /// so that the changes are logged in the order they are applied.
+    async fn log<'a>(
+        &self,
+        records: impl IntoIterator<Item = Record<'a>>,
+    ) -> Result<(), TokenDbError> {
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return Ok(()),
+        };
+
+        let logged = match Frame::encode(records) {
+            Ok(frame) => wal::blocking(wal, move |wal| wal.append(&frame)).await,
+            Err(error) => Err(error),
+        };
+
+        // The detail of the failure is of no use to the clients, and tells about the host.
+        logged.map_err(|error| {
+            warn!(%error, "failed to log a change");
+            TokenDbError::Unavailable("log unavailable".to_string())
+        })
+    }
+
+    /// Compact the log into a snapshot of the store once enough changes were logged.
+    ///
+    /// The log is set aside while the store is locked, and the snapshot is written from a copy of
+    /// the tokens in the background, so that no change waits on it.
+    async fn compact(&self, locked: &HashMap<model::TokenKey, model::Token>) {
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return,
+        };
+
+        let due = match wal.lock() {
+            Ok(wal) => wal.should_snapshot(),
+            Err(_) => false,
+        };
+        if !due {
+            return;
+        }
+
+        // A compaction is running already; this one is left to the next change.
+        let compacting = match self.compacting.clone().try_lock_owned() {
+            Ok(compacting) => compacting,
+            Err(_) => return,
+        };
+
+        let dir = match wal::blocking(wal, |wal| wal.rotate().map(|_| wal.dir())).await {
+            Ok(dir) => dir,
+            Err(error) => {
+                warn!(%error, "failed to rotate the log");
+                return;
+            }
+        };
+
+        let tokens = locked.clone();
+        tokio::spawn(async move {
+            if let Err(error) = write_snapshot(dir, tokens).await {
+                warn!(%error, "failed to snapshot the log");
+            }
+            drop(compacting);
+        });
+    }
+
+    /// Move `key` in the owner index from the owner of `previous` to the owner of `current`.
+    async fn reindex_owner(
+        &self,
+        key: &model::TokenKey,
+        previous: Option<&model::Token>,
+        current: Option<&model::Token>,
+    ) {
+        let mut owners = self.owners.lock().await;
+
+        if let Some(owner) = previous.and_then(|tok| tok.owner.as_ref()) {
+            if let Some(keys) = owners.get_mut(owner) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    owners.remove(owner);
+                }
+            }
+        }
+
+        if let Some(owner) = current.and_then(|tok| tok.owner.as_ref()) {
+            owners.entry(owner.clone()).or_default().insert(key.clone());
         }
     }
 }
@@ -31,75 +321,217 @@ impl Default for TokenDbInMemory {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Write a snapshot of the tokens off the workers of the runtime.
+async fn write_snapshot(
+    dir: std::path::PathBuf,
+    tokens: HashMap<model::TokenKey, model::Token>,
+) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let snapshot = Snapshot::encode(tokens.values())?;
+        Wal::write_snapshot(&dir, &snapshot)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Version of a token inserted over the given registered token, if any.
+fn token_version(registered: Option<&model::Token>) -> u64 {
+    registered.map_or(0, |registered| registered.version) + 1
+}
+
+/// Merge a token onto the registered token with the same key, if any, of which it keeps the
+/// topic subscriptions and the owner unless it sets them.
+fn token_merge(mut token: model::Token, registered: Option<&model::Token>) -> model::Token {
+    token.version = token_version(registered);
+
+    if let Some(registered) = registered {
+        if token.topics.is_empty() {
+            token.topics = registered.topics.clone();
+        }
+        if token.owner.is_none() {
+            token.owner = registered.owner.clone();
+        }
+    }
+
+    token
+}
+
+/// Select the keys of the tenant from the keys of an owner, which may span tenants.
+fn select_tenant(keys: &HashSet<model::TokenKey>, tenant: &str) -> Vec<model::TokenKey> {
+    keys.iter()
+        .filter(|key| &*key.tenant == tenant)
+        .cloned()
+        .collect()
+}
+
+fn token_insert(previous: Option<model::Token>, token: model::Token) -> model::TokenInsert {
+    match previous {
+        Some(original) => model::TokenInsert::Refreshed(model::TokenUpdate {
+            original,
+            delta: token,
+        }),
+        None => model::TokenInsert::Created(token),
+    }
+}
+
+#[derive(Debug, Clone, Error)]
 pub enum TokenDbError {
-    TokenNotPresent(model::TokenKey),
-    Unknown,
+    #[error("token `{}` not existing", .0.key)]
+    NotFound(model::TokenKey),
+    #[error("token `{}` already existing", .0.key)]
+    AlreadyExists(model::TokenKey),
+    #[error("token `{}` is registered with another platform or owner", .0.key)]
+    RejectedConflicting(model::TokenKey),
+    #[error("token `{}` is at version {actual}, not the expected {expected}", .key.key)]
+    Conflict {
+        key: model::TokenKey,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("database unavailable: {0}")]
+    Unavailable(String),
 }
 
 #[async_trait]
 impl TokenDb for TokenDbInMemory {
     #[tracing::instrument]
-    async fn insert(&self, token: model::TokenKey) -> Result<model::Token, TokenDbError> {
-        let t = model::Token::from(token.clone());
+    async fn get(&self, token: model::TokenKey) -> Result<Option<model::Token>, TokenDbError> {
+        debug!("preparing to lock database");
+        let locked = self.db.lock().await;
+        debug!("database locked");
+
+        Ok(locked.get(&token).cloned())
+    }
+
+    #[tracing::instrument(skip(self, tokens), fields(tokens = tokens.len()))]
+    async fn get_many(
+        &self,
+        tokens: Vec<model::TokenKey>,
+    ) -> Result<Vec<Option<model::Token>>, TokenDbError> {
+        debug!("preparing to lock database");
+        let locked = self.db.lock().await;
+        debug!("database locked");
+
+        Ok(tokens.iter().map(|t| locked.get(t).cloned()).collect())
+    }
+
+    #[tracing::instrument]
+    async fn insert(
+        &self,
+        token: model::Token,
+        policy: RegistrationPolicy,
+    ) -> Result<model::TokenInsert, TokenDbError> {
+        let t;
+        let previous;
 
         {
             debug!("preparing to lock database");
             let mut locked = self.db.lock().await;
             debug!("database locked");
 
-            locked.insert(token.clone(), t.clone());
-            info!("inserting to database");
+            let registered = locked.get(&token.key);
+            policy.admit(registered, &token)?;
+            t = token_merge(token, registered);
+            self.log([Record::Put(&t)]).await?;
+            previous = locked.insert(t.key.clone(), t.clone());
+            self.reindex_owner(&t.key, previous.as_ref(), Some(&t))
+                .await;
+            info!(refreshed = previous.is_some(), "inserting to database");
+            self.compact(&locked).await;
         }
         debug!("database unlocked");
 
-        return Ok(t);
+        Ok(token_insert(previous, t))
     }
 
-    #[tracing::instrument]
-    async fn update(&self, token: model::TokenKey) -> Result<model::TokenUpdate, TokenDbError> {
-        let mut original: Option<model::Token> = None;
+    #[tracing::instrument(skip(tokens), fields(tokens = tokens.len()))]
+    async fn insert_many(
+        &self,
+        tokens: Vec<model::Token>,
+        policy: RegistrationPolicy,
+    ) -> Vec<Result<model::TokenInsert, TokenDbError>> {
+        debug!("preparing to lock database");
+        let mut locked = self.db.lock().await;
+        debug!("database locked");
 
-        {
-            debug!("preparing to lock database");
-            let locked = self.db.lock().await;
-            debug!("database locked");
-            let tok = locked.get(&token).clone();
+        // Tokens admitted by the batch, by key, so that a key repeated within it is checked
+        // against the token admitted before.
+        let mut admitted: HashMap<model::TokenKey, model::Token> = HashMap::new();
+        let mut merged = Vec::with_capacity(tokens.len());
+        for t in tokens {
+            let registered = admitted.get(&t.key).or_else(|| locked.get(&t.key));
+            let t = policy
+                .admit(registered, &t)
+                .map(|_| token_merge(t, registered));
+            if let Ok(t) = &t {
+                admitted.insert(t.key.clone(), t.clone());
+            }
+            merged.push(t);
+        }
+
+        let logged = self.log(merged.iter().flatten().map(Record::Put)).await;
 
-            original = match tok {
-                Some(tkn) => {
-                    debug!("value to update selected");
-                    Some(tkn.clone())
+        let mut inserted = Vec::with_capacity(merged.len());
+        for t in merged {
+            let t = match (t, &logged) {
+                (Ok(t), Ok(())) => t,
+                (Ok(_), Err(error)) => {
+                    inserted.push(Err(error.clone()));
+                    continue;
                 }
-                None => {
-                    debug!("value to update not selected");
-                    return Err(TokenDbError::TokenNotPresent(token.clone()));
+                (Err(error), _) => {
+                    inserted.push(Err(error));
+                    continue;
                 }
-            }
+            };
+            let previous = locked.insert(t.key.clone(), t.clone());
+            self.reindex_owner(&t.key, previous.as_ref(), Some(&t))
+                .await;
+            inserted.push(Ok(token_insert(previous, t)));
         }
-        debug!("database unlocked");
+        info!(tokens = inserted.len(), "inserting many to database");
+        self.compact(&locked).await;
+
+        inserted
+    }
 
+    #[tracing::instrument]
+    async fn update(
+        &self,
+        token: model::TokenKey,
+        expected_version: Option<u64>,
+    ) -> Result<model::TokenUpdate, TokenDbError> {
         debug!("preparing to lock database");
         let mut locked = self.db.lock().await;
         debug!("database locked");
 
-        if locked.contains_key(&token) {
-            let delta = model::Token::from(token.clone());
-            locked.insert(token.clone(), delta.clone());
-            info!("updating to database");
-
-            match original {
-                Some(tok) => {
-                    return Ok(model::TokenUpdate {
-                        original: tok,
-                        delta,
-                    })
-                }
-                None => return Err(TokenDbError::TokenNotPresent(token.clone())),
-            };
+        let entry = match locked.get_mut(&token) {
+            Some(entry) => {
+                debug!("value to update selected");
+                entry
+            }
+            None => {
+                debug!("value to update not selected");
+                return Err(TokenDbError::NotFound(token));
+            }
+        };
+
+        if let Some(expected) = expected_version.filter(|expected| *expected != entry.version) {
+            return Err(TokenDbError::Conflict {
+                key: token,
+                expected,
+                actual: entry.version,
+            });
         }
 
-        return Err(TokenDbError::TokenNotPresent(token.clone()));
+        let original = entry.clone();
+        let delta = original.refresh();
+        self.log([Record::Put(&delta)]).await?;
+        *entry = delta.clone();
+        info!(version = delta.version, "updating to database");
+        self.compact(&locked).await;
+
+        Ok(model::TokenUpdate { original, delta })
     }
 
     #[tracing::instrument]
@@ -107,8 +539,176 @@ impl TokenDb for TokenDbInMemory {
         debug!("preparing to lock database");
         let mut locked = self.db.lock().await;
         debug!("database locked");
-        locked.remove(&token);
+        if locked.contains_key(&token) {
+            self.log([Record::Remove(&token)]).await?;
+        }
+        let previous = locked.remove(&token);
+        self.reindex_owner(&token, previous.as_ref(), None).await;
         info!("removed from database");
+        self.compact(&locked).await;
         Ok(())
     }
+
+    #[tracing::instrument]
+    async fn subscribe_topics(
+        &self,
+        token: model::TokenKey,
+        topics: Vec<Arc<str>>,
+    ) -> Result<model::TokenUpdate, TokenDbError> {
+        debug!("preparing to lock database");
+        let mut locked = self.db.lock().await;
+        debug!("database locked");
+
+        let entry = locked
+            .get_mut(&token)
+            .ok_or_else(|| TokenDbError::NotFound(token.clone()))?;
+
+        let original = entry.clone();
+        let mut delta = original.refresh();
+        delta.topics.extend(topics);
+        self.log([Record::Put(&delta)]).await?;
+        *entry = delta.clone();
+        info!("subscribing to topics in database");
+        self.compact(&locked).await;
+
+        Ok(model::TokenUpdate { original, delta })
+    }
+
+    #[tracing::instrument]
+    async fn unsubscribe_topics(
+        &self,
+        token: model::TokenKey,
+        topics: Vec<Arc<str>>,
+    ) -> Result<model::TokenUpdate, TokenDbError> {
+        debug!("preparing to lock database");
+        let mut locked = self.db.lock().await;
+        debug!("database locked");
+
+        let entry = locked
+            .get_mut(&token)
+            .ok_or_else(|| TokenDbError::NotFound(token.clone()))?;
+
+        let original = entry.clone();
+        let mut delta = original.refresh();
+        for topic in topics.iter() {
+            delta.topics.remove(topic);
+        }
+        self.log([Record::Put(&delta)]).await?;
+        *entry = delta.clone();
+        info!("unsubscribing from topics in database");
+        self.compact(&locked).await;
+
+        Ok(model::TokenUpdate { original, delta })
+    }
+
+    #[tracing::instrument]
+    async fn select_topics(
+        &self,
+        tenant: &str,
+        condition: &Condition,
+    ) -> Result<Vec<model::TokenKey>, TokenDbError> {
+        debug!("preparing to lock database");
+        let locked = self.db.lock().await;
+        debug!("database locked");
+
+        Ok(locked
+            .values()
+            .filter(|token| &*token.key.tenant == tenant && condition.matches(&token.topics))
+            .map(|token| token.key.clone())
+            .collect())
+    }
+
+    #[tracing::instrument]
+    async fn select_owner(
+        &self,
+        tenant: &str,
+        owner: &str,
+    ) -> Result<Vec<model::TokenKey>, TokenDbError> {
+        debug!("preparing to lock database");
+        let _locked = self.db.lock().await;
+        debug!("database locked");
+
+        let owners = self.owners.lock().await;
+        Ok(owners
+            .get(owner)
+            .map(|keys| select_tenant(keys, tenant))
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument]
+    async fn select_all(&self) -> Result<Vec<model::Token>, TokenDbError> {
+        debug!("preparing to lock database");
+        let locked = self.db.lock().await;
+        debug!("database locked");
+
+        Ok(locked.values().cloned().collect())
+    }
+
+    #[tracing::instrument]
+    async fn count(&self) -> Result<usize, TokenDbError> {
+        debug!("preparing to lock database");
+        let locked = self.db.lock().await;
+        debug!("database locked");
+
+        Ok(locked.len())
+    }
+
+    #[tracing::instrument]
+    async fn restore(
+        &self,
+        token: model::Token,
+        policy: ConflictPolicy,
+    ) -> Result<Option<model::TokenInsert>, TokenDbError> {
+        let mut t = token;
+
+        debug!("preparing to lock database");
+        let mut locked = self.db.lock().await;
+        debug!("database locked");
+
+        if !policy.admits(locked.get(&t.key), &t) {
+            debug!("restored token skipped");
+            return Ok(None);
+        }
+
+        t.version = token_version(locked.get(&t.key));
+        self.log([Record::Put(&t)]).await?;
+        let previous = locked.insert(t.key.clone(), t.clone());
+        self.reindex_owner(&t.key, previous.as_ref(), Some(&t))
+            .await;
+        info!(refreshed = previous.is_some(), "restoring to database");
+        self.compact(&locked).await;
+
+        Ok(Some(token_insert(previous, t)))
+    }
+
+    /// Compact the log into a snapshot of the store, if the store is durable.
+    #[tracing::instrument]
+    async fn flush(&self) -> Result<(), TokenDbError> {
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return Ok(()),
+        };
+
+        // Waits for a compaction running, whose snapshot this one replaces.
+        let _compacting = self.compacting.lock().await;
+
+        let tokens = {
+            debug!("preparing to lock database");
+            let locked = self.db.lock().await;
+            debug!("database locked");
+
+            let rotated = wal::blocking(wal, |wal| wal.rotate().map(|_| wal.dir())).await;
+            rotated.map(|dir| (dir, locked.clone()))
+        };
+        debug!("database unlocked");
+
+        let written = match tokens {
+            Ok((dir, tokens)) => write_snapshot(dir, tokens).await,
+            Err(error) => Err(error),
+        };
+        written.map_err(|error| {
+            warn!(%error, "failed to snapshot the log");
+            TokenDbError::Unavailable("snapshot unavailable".to_string())
+        })
+    }
 }
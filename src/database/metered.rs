@@ -0,0 +1,134 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+use tonic::async_trait;
+
+use super::{ConflictPolicy, RegistrationPolicy, TokenDb, TokenDbError};
+use crate::metrics::Metrics;
+use crate::model;
+use crate::topic::Condition;
+
+/// A token store recording the time each of its operations takes into the metrics.
+#[derive(Debug)]
+pub struct TokenDbMetered<Db: TokenDb> {
+    db: Db,
+    metrics: Metrics,
+}
+
+impl<Db: TokenDb> TokenDbMetered<Db> {
+    pub fn new(db: Db, metrics: Metrics) -> Self {
+        Self { db, metrics }
+    }
+
+    async fn timed<T>(&self, operation: &str, future: impl Future<Output = T>) -> T {
+        let started = Instant::now();
+        let output = future.await;
+        self.metrics.token_db(operation, started.elapsed());
+        output
+    }
+}
+
+#[async_trait]
+impl<Db: TokenDb> TokenDb for TokenDbMetered<Db> {
+    async fn get(&self, token: model::TokenKey) -> Result<Option<model::Token>, TokenDbError> {
+        self.timed("get", self.db.get(token)).await
+    }
+
+    async fn get_many(
+        &self,
+        tokens: Vec<model::TokenKey>,
+    ) -> Result<Vec<Option<model::Token>>, TokenDbError> {
+        self.timed("get_many", self.db.get_many(tokens)).await
+    }
+
+    async fn insert(
+        &self,
+        token: model::Token,
+        policy: RegistrationPolicy,
+    ) -> Result<model::TokenInsert, TokenDbError> {
+        self.timed("insert", self.db.insert(token, policy)).await
+    }
+
+    async fn insert_many(
+        &self,
+        tokens: Vec<model::Token>,
+        policy: RegistrationPolicy,
+    ) -> Vec<Result<model::TokenInsert, TokenDbError>> {
+        self.timed("insert_many", self.db.insert_many(tokens, policy))
+            .await
+    }
+
+    async fn update(
+        &self,
+        token: model::TokenKey,
+        expected_version: Option<u64>,
+    ) -> Result<model::TokenUpdate, TokenDbError> {
+        self.timed("update", self.db.update(token, expected_version))
+            .await
+    }
+
+    async fn invalidate(&self, token: model::TokenKey) -> Result<(), TokenDbError> {
+        self.timed("invalidate", self.db.invalidate(token)).await
+    }
+
+    async fn subscribe_topics(
+        &self,
+        token: model::TokenKey,
+        topics: Vec<Arc<str>>,
+    ) -> Result<model::TokenUpdate, TokenDbError> {
+        self.timed("subscribe_topics", self.db.subscribe_topics(token, topics))
+            .await
+    }
+
+    async fn unsubscribe_topics(
+        &self,
+        token: model::TokenKey,
+        topics: Vec<Arc<str>>,
+    ) -> Result<model::TokenUpdate, TokenDbError> {
+        self.timed(
+            "unsubscribe_topics",
+            self.db.unsubscribe_topics(token, topics),
+        )
+        .await
+    }
+
+    async fn select_topics(
+        &self,
+        tenant: &str,
+        condition: &Condition,
+    ) -> Result<Vec<model::TokenKey>, TokenDbError> {
+        self.timed("select_topics", self.db.select_topics(tenant, condition))
+            .await
+    }
+
+    async fn select_owner(
+        &self,
+        tenant: &str,
+        owner: &str,
+    ) -> Result<Vec<model::TokenKey>, TokenDbError> {
+        self.timed("select_owner", self.db.select_owner(tenant, owner))
+            .await
+    }
+
+    async fn select_all(&self) -> Result<Vec<model::Token>, TokenDbError> {
+        self.timed("select_all", self.db.select_all()).await
+    }
+
+    // Sampled when the metrics are scraped, so not timed itself.
+    async fn count(&self) -> Result<usize, TokenDbError> {
+        self.db.count().await
+    }
+
+    async fn restore(
+        &self,
+        token: model::Token,
+        policy: ConflictPolicy,
+    ) -> Result<Option<model::TokenInsert>, TokenDbError> {
+        self.timed("restore", self.db.restore(token, policy)).await
+    }
+
+    async fn flush(&self) -> Result<(), TokenDbError> {
+        self.timed("flush", self.db.flush()).await
+    }
+}
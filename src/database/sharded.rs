@@ -0,0 +1,354 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use tonic::async_trait;
+use tracing::{debug, info};
+
+use super::{
+    select_tenant, token_insert, token_merge, token_version, ConflictPolicy, RegistrationPolicy,
+    TokenDb, TokenDbError,
+};
+use crate::model;
+use crate::topic::Condition;
+
+type Shard = Mutex<HashMap<model::TokenKey, model::Token>>;
+type OwnerShard = Mutex<HashMap<Arc<str>, HashSet<model::TokenKey>>>;
+
+/// Number of shards of a store constructed by default.
+pub const DEFAULT_SHARDS: usize = 64;
+
+/// An in-memory token store striped across independently locked shards.
+///
+/// Tokens are assigned to shards by the hash of their key, and owners to the shards of the owner
+/// index by the hash of the owner, so that operations on different tokens rarely contend. A shard
+/// of the owner index is only locked while the shard of the token being indexed is held.
+#[derive(Debug)]
+pub struct TokenDbSharded {
+    shards: Box<[Shard]>,
+    owners: Box<[OwnerShard]>,
+    hasher: RandomState,
+}
+
+impl TokenDbSharded {
+    pub fn new(shards: usize) -> Self {
+        let shards = shards.max(1);
+        Self {
+            shards: (0..shards).map(|_| Mutex::new(HashMap::new())).collect(),
+            owners: (0..shards).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn index<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, key: &model::TokenKey) -> &Shard {
+        &self.shards[self.index(key)]
+    }
+
+    fn owner_shard(&self, owner: &str) -> &OwnerShard {
+        &self.owners[self.index(owner)]
+    }
+
+    /// Move `key` in the owner index from the owner of `previous` to the owner of `current`.
+    async fn reindex_owner(
+        &self,
+        key: &model::TokenKey,
+        previous: Option<&model::Token>,
+        current: Option<&model::Token>,
+    ) {
+        let previous = previous.and_then(|tok| tok.owner.as_ref());
+        let current = current.and_then(|tok| tok.owner.as_ref());
+
+        if previous == current {
+            return;
+        }
+
+        if let Some(owner) = previous {
+            let mut owners = self.owner_shard(owner).lock().await;
+            if let Some(keys) = owners.get_mut(owner) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    owners.remove(owner);
+                }
+            }
+        }
+
+        if let Some(owner) = current {
+            let mut owners = self.owner_shard(owner).lock().await;
+            owners.entry(owner.clone()).or_default().insert(key.clone());
+        }
+    }
+
+    /// Apply a change to the token of the given key, reporting it as an update.
+    async fn modify<F>(
+        &self,
+        token: model::TokenKey,
+        f: F,
+    ) -> Result<model::TokenUpdate, TokenDbError>
+    where
+        F: FnOnce(&mut model::Token),
+    {
+        debug!("preparing to lock shard");
+        let mut locked = self.shard(&token).lock().await;
+        debug!("shard locked");
+
+        let entry = locked
+            .get_mut(&token)
+            .ok_or_else(|| TokenDbError::NotFound(token.clone()))?;
+
+        let original = entry.clone();
+        let mut delta = original.refresh();
+        f(&mut delta);
+        *entry = delta.clone();
+
+        Ok(model::TokenUpdate { original, delta })
+    }
+}
+
+impl Default for TokenDbSharded {
+    fn default() -> Self {
+        Self::new(DEFAULT_SHARDS)
+    }
+}
+
+#[async_trait]
+impl TokenDb for TokenDbSharded {
+    #[tracing::instrument(skip(self))]
+    async fn get(&self, token: model::TokenKey) -> Result<Option<model::Token>, TokenDbError> {
+        debug!("preparing to lock shard");
+        let locked = self.shard(&token).lock().await;
+        debug!("shard locked");
+
+        Ok(locked.get(&token).cloned())
+    }
+
+    #[tracing::instrument(skip(self, tokens), fields(tokens = tokens.len()))]
+    async fn get_many(
+        &self,
+        tokens: Vec<model::TokenKey>,
+    ) -> Result<Vec<Option<model::Token>>, TokenDbError> {
+        let mut found = Vec::with_capacity(tokens.len());
+
+        // Shards are locked one token at a time, as for the insertion of many tokens.
+        for t in tokens {
+            let locked = self.shard(&t).lock().await;
+            found.push(locked.get(&t).cloned());
+        }
+
+        Ok(found)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn insert(
+        &self,
+        token: model::Token,
+        policy: RegistrationPolicy,
+    ) -> Result<model::TokenInsert, TokenDbError> {
+        debug!("preparing to lock shard");
+        let mut locked = self.shard(&token.key).lock().await;
+        debug!("shard locked");
+
+        let registered = locked.get(&token.key);
+        policy.admit(registered, &token)?;
+        let t = token_merge(token, registered);
+        let previous = locked.insert(t.key.clone(), t.clone());
+        self.reindex_owner(&t.key, previous.as_ref(), Some(&t))
+            .await;
+        info!(refreshed = previous.is_some(), "inserting to database");
+
+        Ok(token_insert(previous, t))
+    }
+
+    #[tracing::instrument(skip(self, tokens), fields(tokens = tokens.len()))]
+    async fn insert_many(
+        &self,
+        tokens: Vec<model::Token>,
+        policy: RegistrationPolicy,
+    ) -> Vec<Result<model::TokenInsert, TokenDbError>> {
+        let mut inserted = Vec::with_capacity(tokens.len());
+
+        // Shards are locked one token at a time, so that a large batch never holds up the others.
+        for t in tokens {
+            let mut locked = self.shard(&t.key).lock().await;
+
+            let registered = locked.get(&t.key);
+            if let Err(error) = policy.admit(registered, &t) {
+                inserted.push(Err(error));
+                continue;
+            }
+            let t = token_merge(t, registered);
+            let previous = locked.insert(t.key.clone(), t.clone());
+            self.reindex_owner(&t.key, previous.as_ref(), Some(&t))
+                .await;
+            inserted.push(Ok(token_insert(previous, t)));
+        }
+        info!(tokens = inserted.len(), "inserting many to database");
+
+        inserted
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn update(
+        &self,
+        token: model::TokenKey,
+        expected_version: Option<u64>,
+    ) -> Result<model::TokenUpdate, TokenDbError> {
+        debug!("preparing to lock shard");
+        let mut locked = self.shard(&token).lock().await;
+        debug!("shard locked");
+
+        let entry = locked
+            .get_mut(&token)
+            .ok_or_else(|| TokenDbError::NotFound(token.clone()))?;
+
+        if let Some(expected) = expected_version.filter(|expected| *expected != entry.version) {
+            return Err(TokenDbError::Conflict {
+                key: token,
+                expected,
+                actual: entry.version,
+            });
+        }
+
+        let original = entry.clone();
+        let delta = original.refresh();
+        *entry = delta.clone();
+        info!(version = delta.version, "updating to database");
+
+        Ok(model::TokenUpdate { original, delta })
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn invalidate(&self, token: model::TokenKey) -> Result<(), TokenDbError> {
+        debug!("preparing to lock shard");
+        let mut locked = self.shard(&token).lock().await;
+        debug!("shard locked");
+
+        let previous = locked.remove(&token);
+        self.reindex_owner(&token, previous.as_ref(), None).await;
+        info!("removed from database");
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn subscribe_topics(
+        &self,
+        token: model::TokenKey,
+        topics: Vec<Arc<str>>,
+    ) -> Result<model::TokenUpdate, TokenDbError> {
+        let update = self
+            .modify(token, |delta| delta.topics.extend(topics))
+            .await?;
+        info!("subscribing to topics in database");
+
+        Ok(update)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn unsubscribe_topics(
+        &self,
+        token: model::TokenKey,
+        topics: Vec<Arc<str>>,
+    ) -> Result<model::TokenUpdate, TokenDbError> {
+        let update = self
+            .modify(token, |delta| {
+                for topic in topics.iter() {
+                    delta.topics.remove(topic);
+                }
+            })
+            .await?;
+        info!("unsubscribing from topics in database");
+
+        Ok(update)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn select_topics(
+        &self,
+        tenant: &str,
+        condition: &Condition,
+    ) -> Result<Vec<model::TokenKey>, TokenDbError> {
+        let mut keys = Vec::new();
+
+        // Shards are scanned one at a time; a token changing meanwhile is seen before or after.
+        for shard in self.shards.iter() {
+            let locked = shard.lock().await;
+            keys.extend(
+                locked
+                    .values()
+                    .filter(|token| {
+                        &*token.key.tenant == tenant && condition.matches(&token.topics)
+                    })
+                    .map(|token| token.key.clone()),
+            );
+        }
+
+        Ok(keys)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn select_owner(
+        &self,
+        tenant: &str,
+        owner: &str,
+    ) -> Result<Vec<model::TokenKey>, TokenDbError> {
+        let owners = self.owner_shard(owner).lock().await;
+        Ok(owners
+            .get(owner)
+            .map(|keys| select_tenant(keys, tenant))
+            .unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn select_all(&self) -> Result<Vec<model::Token>, TokenDbError> {
+        let mut tokens = Vec::new();
+
+        for shard in self.shards.iter() {
+            let locked = shard.lock().await;
+            tokens.extend(locked.values().cloned());
+        }
+
+        Ok(tokens)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn count(&self) -> Result<usize, TokenDbError> {
+        let mut count = 0;
+
+        for shard in self.shards.iter() {
+            count += shard.lock().await.len();
+        }
+
+        Ok(count)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn restore(
+        &self,
+        token: model::Token,
+        policy: ConflictPolicy,
+    ) -> Result<Option<model::TokenInsert>, TokenDbError> {
+        let mut t = token;
+
+        debug!("preparing to lock shard");
+        let mut locked = self.shard(&t.key).lock().await;
+        debug!("shard locked");
+
+        if !policy.admits(locked.get(&t.key), &t) {
+            debug!("restored token skipped");
+            return Ok(None);
+        }
+
+        t.version = token_version(locked.get(&t.key));
+        let previous = locked.insert(t.key.clone(), t.clone());
+        self.reindex_owner(&t.key, previous.as_ref(), Some(&t))
+            .await;
+        info!(refreshed = previous.is_some(), "restoring to database");
+
+        Ok(Some(token_insert(previous, t)))
+    }
+}
@@ -0,0 +1,616 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use prost::Message;
+use tracing::{debug, info, warn};
+
+use crate::cm;
+use crate::model;
+
+const LOG_FILE: &str = "tokens.wal";
+/// Log set aside by a compaction, until the snapshot of the records in it is written.
+const LOG_ROTATED_FILE: &str = "tokens.wal.old";
+const SNAPSHOT_FILE: &str = "tokens.snapshot";
+const SNAPSHOT_TEMP_FILE: &str = "tokens.snapshot.tmp";
+
+/// Tag of a record storing the state of a token.
+const RECORD_PUT: u8 = 1;
+/// Tag of a record removing a token.
+const RECORD_REMOVE: u8 = 2;
+/// Length of the header of a frame: the length of its records, their CRC-32, then the CRC-32 of
+/// the header so far, each as a little-endian `u32`.
+const FRAME_HEADER_LENGTH: usize = 12;
+
+type Tokens = HashMap<model::TokenKey, model::Token>;
+
+/// When the log is forced to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every record, before the operation is acknowledged.
+    Always,
+    /// Periodically, losing at most the given interval of records on a power loss.
+    Interval(Duration),
+    /// Never; records reach the OS on every write and stable storage whenever it flushes them.
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub struct WalOptions {
+    pub fsync: FsyncPolicy,
+    /// Number of records logged after which the log is compacted into a snapshot.
+    pub snapshot_every: usize,
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        Self {
+            fsync: FsyncPolicy::Interval(Duration::from_secs(1)),
+            snapshot_every: 10_000,
+        }
+    }
+}
+
+/// A change of the store, as written to the log ahead of applying it.
+#[derive(Debug)]
+pub enum Record<'a> {
+    Put(&'a model::Token),
+    Remove(&'a model::TokenKey),
+}
+
+/// Append-only log of the changes to a token store since its last snapshot.
+///
+/// Every record carries the full state of the token it changes, so replaying the log over the
+/// snapshot is idempotent. Records are appended in frames checksummed with a CRC-32: a frame torn
+/// by a crash at the tail of the log is discarded, while a corrupt frame followed by others fails
+/// the recovery rather than losing the records after it.
+#[derive(Debug)]
+pub struct Wal {
+    dir: PathBuf,
+    log: BufWriter<File>,
+    options: WalOptions,
+    records: usize,
+    unsynced: bool,
+}
+
+impl Wal {
+    /// Open the log in `dir`, recovering the tokens from its snapshot and records.
+    ///
+    /// With an interval fsync policy, a task syncing the log is spawned, so it must then be called
+    /// within a tokio runtime.
+    pub fn open(
+        dir: impl AsRef<Path>,
+        options: WalOptions,
+    ) -> io::Result<(Arc<Mutex<Self>>, Tokens)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut tokens = HashMap::new();
+
+        let snapshot = dir.join(SNAPSHOT_FILE);
+        if snapshot.exists() {
+            let buf = fs::read(&snapshot)?;
+            let mut remaining = buf.as_slice();
+            while !remaining.is_empty() {
+                let token = cm::Token::decode_length_delimited(&mut remaining)
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                let token = model::Token::restored(token)
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                tokens.insert(token.key.clone(), token);
+            }
+            info!(tokens = tokens.len(), "snapshot loaded");
+        }
+
+        // Left by a compaction which did not complete, and replayed first as the older records.
+        let rotated = dir.join(LOG_ROTATED_FILE);
+        if rotated.exists() {
+            let buf = fs::read(&rotated)?;
+            let (records, valid) = replay(&buf, &mut tokens)?;
+            if valid < buf.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "rotated log is torn",
+                ));
+            }
+            info!(records, tokens = tokens.len(), "rotated log replayed");
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let (records, valid) = replay(&buf, &mut tokens)?;
+        if valid < buf.len() {
+            warn!(
+                discarded = buf.len() - valid,
+                "discarding torn frame at the tail of the log"
+            );
+            file.set_len(valid as u64)?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        info!(records, tokens = tokens.len(), "log replayed");
+
+        let wal = Arc::new(Mutex::new(Self {
+            dir,
+            log: BufWriter::new(file),
+            options: options.clone(),
+            records,
+            unsynced: false,
+        }));
+
+        if let FsyncPolicy::Interval(interval) = options.fsync {
+            tokio::spawn(sync_periodically(Arc::downgrade(&wal), interval));
+        }
+
+        Ok((wal, tokens))
+    }
+
+    /// Append a frame, syncing it to stable storage if the policy says so.
+    pub fn append(&mut self, frame: &Frame) -> io::Result<()> {
+        self.log.write_all(&frame.buf)?;
+        self.log.flush()?;
+        self.records += frame.records;
+        self.unsynced = true;
+
+        if self.options.fsync == FsyncPolicy::Always {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether enough records were logged since the last snapshot to compact them.
+    pub fn should_snapshot(&self) -> bool {
+        self.records >= self.options.snapshot_every
+    }
+
+    /// Set the log aside, to be compacted by the snapshot of the tokens it is the outcome of, and
+    /// start a new one for the changes after it.
+    ///
+    /// A log set aside by a previous compaction which did not complete is kept as is, the records
+    /// since being covered by the next snapshot as well.
+    pub fn rotate(&mut self) -> io::Result<()> {
+        let rotated = self.dir.join(LOG_ROTATED_FILE);
+        if !rotated.exists() {
+            self.sync()?;
+            fs::rename(self.dir.join(LOG_FILE), &rotated)?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir.join(LOG_FILE))?;
+            File::open(&self.dir)?.sync_all()?;
+            self.log = BufWriter::new(file);
+        }
+
+        self.records = 0;
+        Ok(())
+    }
+
+    /// The directory the log is in.
+    pub fn dir(&self) -> PathBuf {
+        self.dir.clone()
+    }
+
+    /// Write a snapshot of the tokens as of the last rotation of the log in `dir` or later, and
+    /// remove the rotated log it compacts.
+    ///
+    /// The snapshot replaces the previous one atomically, so a crash at any point leaves either
+    /// the previous snapshot and the full log, or the new snapshot and some of the log, whose
+    /// records up to the snapshot replay to the state it holds.
+    pub fn write_snapshot(dir: &Path, snapshot: &Snapshot) -> io::Result<()> {
+        let temp = dir.join(SNAPSHOT_TEMP_FILE);
+
+        {
+            let mut file = BufWriter::new(File::create(&temp)?);
+            file.write_all(&snapshot.buf)?;
+            file.flush()?;
+            file.get_ref().sync_all()?;
+        }
+
+        fs::rename(&temp, dir.join(SNAPSHOT_FILE))?;
+        File::open(dir)?.sync_all()?;
+
+        match fs::remove_file(dir.join(LOG_ROTATED_FILE)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+        File::open(dir)?.sync_all()?;
+
+        info!(tokens = snapshot.tokens, "snapshot written");
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        self.unsynced = false;
+        Ok(())
+    }
+}
+
+/// Records encoded as a frame of the log, so that they are appended, synced and recovered at once.
+#[derive(Debug)]
+pub struct Frame {
+    buf: Vec<u8>,
+    records: usize,
+}
+
+impl Frame {
+    pub fn encode<'a>(records: impl IntoIterator<Item = Record<'a>>) -> io::Result<Self> {
+        let mut buf = vec![0; FRAME_HEADER_LENGTH];
+        let mut count = 0;
+        for record in records {
+            match record {
+                Record::Put(token) => {
+                    buf.push(RECORD_PUT);
+                    cm::Token::from(token).encode_length_delimited(&mut buf)?;
+                }
+                Record::Remove(key) => {
+                    buf.push(RECORD_REMOVE);
+                    cm::TokenKey::from(key).encode_length_delimited(&mut buf)?;
+                }
+            }
+            count += 1;
+        }
+
+        let records = &buf[FRAME_HEADER_LENGTH..];
+        let length = u32::try_from(records.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too long"))?;
+        let crc = crc32fast::hash(records);
+        buf[..4].copy_from_slice(&length.to_le_bytes());
+        buf[4..8].copy_from_slice(&crc.to_le_bytes());
+        let header_crc = crc32fast::hash(&buf[..8]);
+        buf[8..FRAME_HEADER_LENGTH].copy_from_slice(&header_crc.to_le_bytes());
+
+        Ok(Self {
+            buf,
+            records: count,
+        })
+    }
+}
+
+/// Tokens encoded as written to a snapshot.
+#[derive(Debug)]
+pub struct Snapshot {
+    buf: Vec<u8>,
+    tokens: usize,
+}
+
+impl Snapshot {
+    pub fn encode<'a>(tokens: impl Iterator<Item = &'a model::Token>) -> io::Result<Self> {
+        let mut snapshot = Self {
+            buf: Vec::new(),
+            tokens: 0,
+        };
+        for token in tokens {
+            cm::Token::from(token).encode_length_delimited(&mut snapshot.buf)?;
+            snapshot.tokens += 1;
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Run file I/O on the log on a thread meant for blocking, off the workers of the runtime.
+pub async fn blocking<T, F>(wal: &Arc<Mutex<Wal>>, f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Wal) -> io::Result<T> + Send + 'static,
+{
+    let wal = wal.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut locked = wal.lock().map_err(|_| io::Error::other("log poisoned"))?;
+        f(&mut locked)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Apply the frames in `buf` to the tokens, returning the number of records applied and the
+/// length of the prefix of `buf` they take.
+///
+/// A frame is appended by a single write, so a crash may only tear the last frame of the log,
+/// which ends the replay: one cut short, failing its checksum at the very end of the log, or
+/// zeroed by a file extended without its data. A corrupt frame followed by anything else, or a
+/// frame whose records are not valid tokens, fails the replay.
+fn replay(buf: &[u8], tokens: &mut Tokens) -> io::Result<(usize, usize)> {
+    let mut offset = 0;
+    let mut records = 0;
+    let corrupt = |offset: usize| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame at offset {} of the log is corrupt", offset),
+        )
+    };
+
+    while offset < buf.len() {
+        let remaining = &buf[offset..];
+        if remaining.len() < FRAME_HEADER_LENGTH || remaining.iter().all(|b| *b == 0) {
+            break;
+        }
+
+        let field = |at: usize| u32::from_le_bytes(remaining[at..at + 4].try_into().unwrap());
+        if crc32fast::hash(&remaining[..8]) != field(8) {
+            return Err(corrupt(offset));
+        }
+
+        let end = FRAME_HEADER_LENGTH + field(0) as usize;
+        if remaining.len() < end {
+            break;
+        }
+
+        let frame = &remaining[FRAME_HEADER_LENGTH..end];
+        if crc32fast::hash(frame) != field(4) {
+            if remaining.len() == end {
+                break;
+            }
+            return Err(corrupt(offset));
+        }
+
+        records += replay_frame(frame, records, tokens)?;
+        offset += end;
+    }
+
+    Ok((records, offset))
+}
+
+/// Apply the records of a frame which passed its checksum, counted from `first`.
+fn replay_frame(mut frame: &[u8], first: usize, tokens: &mut Tokens) -> io::Result<usize> {
+    let mut records = 0;
+    let malformed = |record: usize, error: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("record {} is malformed: {}", first + record, error),
+        )
+    };
+
+    while let Some((&tag, mut rest)) = frame.split_first() {
+        match tag {
+            RECORD_PUT => {
+                let token = cm::Token::decode_length_delimited(&mut rest)
+                    .map_err(|error| malformed(records, &error))?;
+                let token =
+                    model::Token::restored(token).map_err(|error| malformed(records, &error))?;
+                tokens.insert(token.key.clone(), token);
+            }
+            RECORD_REMOVE => {
+                let key = cm::TokenKey::decode_length_delimited(&mut rest)
+                    .map_err(|error| malformed(records, &error))?;
+                tokens.remove(&model::TokenKey::from(key));
+            }
+            tag => return Err(malformed(records, &format!("unknown tag {}", tag))),
+        }
+
+        frame = rest;
+        records += 1;
+    }
+
+    Ok(records)
+}
+
+async fn sync_periodically(wal: Weak<Mutex<Wal>>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        // The store closed along with its log.
+        let wal = match wal.upgrade() {
+            Some(wal) => wal,
+            None => break,
+        };
+
+        let synced = blocking(&wal, |locked| match locked.unsynced {
+            true => locked.sync().map(|_| true),
+            false => Ok(false),
+        })
+        .await;
+
+        match synced {
+            Ok(true) => debug!("log synced"),
+            Ok(false) => {}
+            Err(error) => warn!(%error, "failed to sync the log"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Directory removed along with everything in it when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("wal-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn options() -> WalOptions {
+        WalOptions {
+            fsync: FsyncPolicy::Never,
+            snapshot_every: usize::MAX,
+        }
+    }
+
+    fn token(key: &str) -> model::Token {
+        model::Token::new(model::TokenKey::new(key))
+    }
+
+    fn append(wal: &Arc<Mutex<Wal>>, records: Vec<Record<'_>>) {
+        let frame = Frame::encode(records).unwrap();
+        wal.lock().unwrap().append(&frame).unwrap();
+    }
+
+    fn keys(tokens: &Tokens) -> Vec<&str> {
+        let mut keys: Vec<&str> = tokens.keys().map(|key| &*key.key).collect();
+        keys.sort();
+        keys
+    }
+
+    fn log_length(dir: &TempDir) -> u64 {
+        fs::metadata(dir.0.join(LOG_FILE)).unwrap().len()
+    }
+
+    #[test]
+    fn reopened_log_recovers_its_records() {
+        let dir = TempDir::new();
+        let (a, b, c) = (token("a"), token("b"), token("c"));
+
+        let (wal, tokens) = Wal::open(&dir.0, options()).unwrap();
+        assert!(tokens.is_empty());
+        append(&wal, vec![Record::Put(&a), Record::Put(&b)]);
+        append(&wal, vec![Record::Put(&c)]);
+        append(&wal, vec![Record::Remove(&b.key)]);
+        drop(wal);
+
+        let (_, tokens) = Wal::open(&dir.0, options()).unwrap();
+        assert_eq!(keys(&tokens), ["a", "c"]);
+    }
+
+    #[test]
+    fn torn_frame_at_the_tail_is_discarded() {
+        let dir = TempDir::new();
+        let (a, b) = (token("a"), token("b"));
+
+        let (wal, _) = Wal::open(&dir.0, options()).unwrap();
+        append(&wal, vec![Record::Put(&a)]);
+        let intact = log_length(&dir);
+        append(&wal, vec![Record::Put(&b)]);
+        drop(wal);
+
+        // Cut short.
+        let file = OpenOptions::new()
+            .write(true)
+            .open(dir.0.join(LOG_FILE))
+            .unwrap();
+        file.set_len(log_length(&dir) - 3).unwrap();
+        drop(file);
+
+        let (wal, tokens) = Wal::open(&dir.0, options()).unwrap();
+        assert_eq!(keys(&tokens), ["a"]);
+        assert_eq!(log_length(&dir), intact);
+
+        // Failing its checksum.
+        append(&wal, vec![Record::Put(&b)]);
+        drop(wal);
+        let mut buf = fs::read(dir.0.join(LOG_FILE)).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        fs::write(dir.0.join(LOG_FILE), &buf).unwrap();
+
+        let (wal, tokens) = Wal::open(&dir.0, options()).unwrap();
+        assert_eq!(keys(&tokens), ["a"]);
+        assert_eq!(log_length(&dir), intact);
+
+        // Zeroed.
+        drop(wal);
+        let file = OpenOptions::new()
+            .write(true)
+            .open(dir.0.join(LOG_FILE))
+            .unwrap();
+        file.set_len(intact + 64).unwrap();
+        drop(file);
+
+        let (wal, tokens) = Wal::open(&dir.0, options()).unwrap();
+        assert_eq!(keys(&tokens), ["a"]);
+        assert_eq!(log_length(&dir), intact);
+
+        // The log goes on after the discarded frame.
+        append(&wal, vec![Record::Put(&b)]);
+        drop(wal);
+        let (_, tokens) = Wal::open(&dir.0, options()).unwrap();
+        assert_eq!(keys(&tokens), ["a", "b"]);
+    }
+
+    #[test]
+    fn corrupt_frame_before_others_fails_the_recovery() {
+        let dir = TempDir::new();
+        let (a, b, c) = (token("a"), token("b"), token("c"));
+
+        let (wal, _) = Wal::open(&dir.0, options()).unwrap();
+        append(&wal, vec![Record::Put(&a)]);
+        let corrupted = log_length(&dir) as usize;
+        append(&wal, vec![Record::Put(&b)]);
+        append(&wal, vec![Record::Put(&c)]);
+        drop(wal);
+
+        let original = fs::read(dir.0.join(LOG_FILE)).unwrap();
+        for at in [corrupted + 2, corrupted + FRAME_HEADER_LENGTH + 4] {
+            let mut buf = original.clone();
+            buf[at] ^= 0xff;
+            fs::write(dir.0.join(LOG_FILE), &buf).unwrap();
+
+            let error = Wal::open(&dir.0, options()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            // The records after the corrupt frame are kept for recovery.
+            assert_eq!(fs::read(dir.0.join(LOG_FILE)).unwrap(), buf);
+        }
+    }
+
+    #[test]
+    fn log_is_replayed_over_the_snapshot() {
+        let dir = TempDir::new();
+        let (a, b, c) = (token("a"), token("b"), token("c"));
+
+        let (wal, _) = Wal::open(&dir.0, options()).unwrap();
+        append(&wal, vec![Record::Put(&a), Record::Put(&b)]);
+        wal.lock().unwrap().rotate().unwrap();
+        assert_eq!(log_length(&dir), 0);
+        let snapshot = Snapshot::encode([&a, &b].into_iter()).unwrap();
+        Wal::write_snapshot(&dir.0, &snapshot).unwrap();
+        assert!(!dir.0.join(LOG_ROTATED_FILE).exists());
+
+        let b = b.refresh();
+        append(&wal, vec![Record::Remove(&a.key), Record::Put(&b)]);
+        append(&wal, vec![Record::Put(&c)]);
+        drop(wal);
+
+        let (_, tokens) = Wal::open(&dir.0, options()).unwrap();
+        assert_eq!(keys(&tokens), ["b", "c"]);
+        assert_eq!(tokens[&b.key].version, b.version);
+    }
+
+    #[test]
+    fn compaction_is_recovered_from_at_any_point() {
+        let dir = TempDir::new();
+        let (a, b, c) = (token("a"), token("b"), token("c"));
+
+        // Crashing between the rotation of the log and its snapshot.
+        let (wal, _) = Wal::open(&dir.0, options()).unwrap();
+        append(&wal, vec![Record::Put(&a)]);
+        wal.lock().unwrap().rotate().unwrap();
+        append(&wal, vec![Record::Put(&b)]);
+        drop(wal);
+
+        let (wal, tokens) = Wal::open(&dir.0, options()).unwrap();
+        assert_eq!(keys(&tokens), ["a", "b"]);
+
+        // The next compaction covers the rotated log along with the records since.
+        wal.lock().unwrap().rotate().unwrap();
+        append(&wal, vec![Record::Put(&c)]);
+        let snapshot = Snapshot::encode(tokens.values()).unwrap();
+        Wal::write_snapshot(&dir.0, &snapshot).unwrap();
+        assert!(!dir.0.join(LOG_ROTATED_FILE).exists());
+        drop(wal);
+
+        let (_, tokens) = Wal::open(&dir.0, options()).unwrap();
+        assert_eq!(keys(&tokens), ["a", "b", "c"]);
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{broadcast, mpsc, Notify};
+use tracing::{debug, info};
+
+use crate::cm;
+
+/// Number of lanes, ordered from the highest to the lowest priority.
+pub const LANES: usize = 3;
+/// Number of consecutive turns each lane is served before the lower lanes get their turn.
+pub const LANE_WEIGHTS: [usize; LANES] = [8, 4, 1];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lane {
+    High = 0,
+    Normal = 1,
+    Low = 2,
+}
+
+impl From<cm::Priority> for Lane {
+    fn from(source: cm::Priority) -> Self {
+        match source {
+            cm::Priority::High => Lane::High,
+            cm::Priority::Normal => Lane::Normal,
+            cm::Priority::Low => Lane::Low,
+        }
+    }
+}
+
+impl Lane {
+    /// Select the lane of a message by the priority of its payload.
+    pub fn of(message: &cm::Message) -> Self {
+        message
+            .payload
+            .as_ref()
+            .map_or(Lane::Normal, |payload| payload.priority().into())
+    }
+}
+
+/// Items queued in priority lanes, dequeued by weighted round robin.
+///
+/// A higher lane is always served first while it has turns left, so that time critical items are
+/// never stuck behind bulk, while the lower lanes are guaranteed a share and never starve.
+#[derive(Debug)]
+pub struct Lanes<T> {
+    queues: [VecDeque<T>; LANES],
+    turns: [usize; LANES],
+}
+
+impl<T> Default for Lanes<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Lanes<T> {
+    pub fn new() -> Self {
+        Self {
+            queues: Default::default(),
+            turns: LANE_WEIGHTS,
+        }
+    }
+
+    pub fn push(&mut self, lane: Lane, item: T) {
+        self.queues[lane as usize].push_back(item);
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        for _ in 0..2 {
+            for lane in 0..LANES {
+                if self.turns[lane] > 0 && !self.queues[lane].is_empty() {
+                    self.turns[lane] -= 1;
+                    return self.queues[lane].pop_front();
+                }
+            }
+
+            // Every non-empty lane used up its turns; begin a new round.
+            self.turns = LANE_WEIGHTS;
+        }
+
+        None
+    }
+
+    pub fn lane_len(&self, lane: Lane) -> usize {
+        self.queues[lane as usize].len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+}
+
+/// Bounded priority lanes in front of the message broadcast channel.
+///
+/// Senders wait for room in the lane of their message, while a single task moves messages from
+/// the lanes to the broadcast channel by weighted round robin.
+#[derive(Debug, Clone)]
+pub struct Dispatcher {
+    lanes: [mpsc::Sender<cm::MessageBroadcast>; LANES],
+    pending: Arc<Pending>,
+}
+
+/// Number of broadcasts queued in the lanes and not yet broadcast.
+#[derive(Debug, Default)]
+struct Pending {
+    count: AtomicUsize,
+    flushed: Notify,
+}
+
+#[derive(Debug)]
+pub struct DispatchError;
+
+impl Dispatcher {
+    /// Spawn the dispatching task. Must be called within a tokio runtime.
+    pub fn spawn(capacity: usize, tx: broadcast::Sender<cm::MessageBroadcast>) -> Self {
+        let (high_tx, high_rx) = mpsc::channel(capacity);
+        let (normal_tx, normal_rx) = mpsc::channel(capacity);
+        let (low_tx, low_rx) = mpsc::channel(capacity);
+
+        let pending = Arc::new(Pending::default());
+        tokio::spawn(dispatch([high_rx, normal_rx, low_rx], tx, pending.clone()));
+
+        Self {
+            lanes: [high_tx, normal_tx, low_tx],
+            pending,
+        }
+    }
+
+    /// Queue a broadcast in the given lane, waiting for room if the lane is full.
+    pub async fn dispatch(
+        &self,
+        lane: Lane,
+        bcast: cm::MessageBroadcast,
+    ) -> Result<(), DispatchError> {
+        self.pending.count.fetch_add(1, Ordering::SeqCst);
+
+        let sent = self.lanes[lane as usize].send(bcast).await;
+        if sent.is_err() {
+            self.pending.done();
+        }
+        sent.map_err(|_| DispatchError)
+    }
+
+    /// Number of broadcasts queued in the lanes and not yet broadcast.
+    pub fn queued(&self) -> usize {
+        self.pending.count.load(Ordering::SeqCst)
+    }
+
+    /// Wait for every broadcast queued so far, and any queued meanwhile, to be broadcast.
+    pub async fn flushed(&self) {
+        loop {
+            // Registered ahead of the check, so that a flush in between is not missed.
+            let flushed = self.pending.flushed.notified();
+            if self.pending.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            flushed.await;
+        }
+    }
+}
+
+impl Pending {
+    fn done(&self) {
+        if self.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.flushed.notify_waiters();
+        }
+    }
+}
+
+async fn dispatch(
+    mut rxs: [mpsc::Receiver<cm::MessageBroadcast>; LANES],
+    tx: broadcast::Sender<cm::MessageBroadcast>,
+    pending: Arc<Pending>,
+) {
+    let mut lanes = Lanes::new();
+
+    loop {
+        // Take in what is ready in the channels, up to a round worth of each lane, so the lanes
+        // can be weighed against each other while the rest waits in the bounded channels.
+        for (lane, rx) in [Lane::High, Lane::Normal, Lane::Low]
+            .into_iter()
+            .zip(rxs.iter_mut())
+        {
+            while lanes.lane_len(lane) < LANE_WEIGHTS[lane as usize] {
+                match rx.try_recv() {
+                    Ok(bcast) => lanes.push(lane, bcast),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        if let Some(bcast) = lanes.pop() {
+            if tx.send(bcast).is_err() {
+                debug!("no subscribers to dispatch to");
+            }
+            pending.done();
+            continue;
+        }
+
+        // Nothing is queued; wait for the next message in any lane.
+        let [high, normal, low] = &mut rxs;
+        let next = tokio::select! {
+            biased;
+            Some(bcast) = high.recv() => (Lane::High, bcast),
+            Some(bcast) = normal.recv() => (Lane::Normal, bcast),
+            Some(bcast) = low.recv() => (Lane::Low, bcast),
+            else => break,
+        };
+        lanes.push(next.0, next.1);
+    }
+
+    info!("dispatcher closed");
+}
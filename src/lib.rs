@@ -0,0 +1,23 @@
+// `tonic::Status` is the error currency of the RPC layer, even if it is large.
+#![allow(clippy::result_large_err)]
+
+pub mod access;
+pub mod auth;
+pub mod config;
+pub mod database;
+pub mod dispatch;
+pub mod lifecycle;
+pub mod limit;
+pub mod metrics;
+pub mod model;
+pub mod outbox;
+pub mod release;
+pub mod rpc;
+pub mod telemetry;
+pub mod tenant;
+pub mod throttle;
+pub mod tls;
+pub mod topic;
+pub mod transfer;
+
+use rpc::cm;
@@ -0,0 +1,121 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{watch, Notify};
+use tonic::Status;
+
+use crate::cm::health_check_response::ServingStatus;
+
+/// Names of the services whose health is reported, besides the server as a whole by `""`.
+pub const SERVICES: [&str; 3] = ["cm.cm_message", "cm.cm_token", "cm.cm_admin"];
+
+/// Serving status of the server and the long lived streams it holds open, shared by the services
+/// so that they can be shut down together.
+///
+/// Shutting down goes in steps: the server stops serving, so that new sends are refused while the
+/// queued ones are flushed, and then the subscribers are closed, flushing what is buffered for
+/// them before ending their streams with a status.
+#[derive(Debug, Clone)]
+pub struct Lifecycle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    status: watch::Sender<ServingStatus>,
+    closing: watch::Sender<bool>,
+    subscribers: AtomicUsize,
+    released: Notify,
+}
+
+/// Registration of an open subscriber stream, released when dropped.
+#[derive(Debug)]
+pub struct Subscriber {
+    inner: Arc<Inner>,
+    closing: watch::Receiver<bool>,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                status: watch::channel(ServingStatus::Serving).0,
+                closing: watch::channel(false).0,
+                subscribers: AtomicUsize::new(0),
+                released: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn status(&self) -> ServingStatus {
+        *self.inner.status.borrow()
+    }
+
+    /// Watch the serving status as it changes.
+    pub fn watch_status(&self) -> watch::Receiver<ServingStatus> {
+        self.inner.status.subscribe()
+    }
+
+    /// Refuse the request if the server stopped serving.
+    pub fn admit(&self) -> Result<(), Status> {
+        match self.status() {
+            ServingStatus::Serving => Ok(()),
+            _ => Err(Status::unavailable("server is shutting down")),
+        }
+    }
+
+    /// Stop serving, reporting NOT_SERVING to health checks and refusing new sends.
+    pub fn stop_serving(&self) {
+        self.inner.status.send_replace(ServingStatus::NotServing);
+    }
+
+    /// Register an open subscriber stream.
+    pub fn subscriber(&self) -> Subscriber {
+        self.inner.subscribers.fetch_add(1, Ordering::SeqCst);
+        Subscriber {
+            inner: self.inner.clone(),
+            closing: self.inner.closing.subscribe(),
+        }
+    }
+
+    /// Ask every subscriber to flush what is buffered for it and close its stream.
+    pub fn close_subscribers(&self) {
+        self.inner.closing.send_replace(true);
+    }
+
+    /// Wait for every subscriber to be released.
+    pub async fn subscribers_closed(&self) {
+        loop {
+            // Registered ahead of the check, so that a release in between is not missed.
+            let released = self.inner.released.notified();
+            if self.inner.subscribers.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            released.await;
+        }
+    }
+}
+
+impl Subscriber {
+    /// Wait for the subscriber to be asked to close.
+    pub async fn closing(&mut self) {
+        while !*self.closing.borrow_and_update() {
+            // The sender is held by the subscriber itself, so it cannot be dropped while waiting.
+            let _ = self.closing.changed().await;
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        if self.inner.subscribers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.released.notify_waiters();
+        }
+    }
+}
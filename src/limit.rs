@@ -0,0 +1,568 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use tonic::metadata::MetadataValue;
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+use tonic::{Request, Status};
+use tower::filter::Predicate;
+use tower::BoxError;
+use tracing::{debug, info};
+
+use crate::auth::{self, Principal};
+use crate::cm;
+use crate::config::{RateLimitConfig, RatePer};
+use crate::metrics;
+use crate::tenant::{self, TENANT_HEADER};
+
+/// Names of the RPCs which may be limited.
+pub const METHODS: [&str; 15] = [
+    "MessageSend",
+    "MessageSendBatch",
+    "MessageSendStream",
+    "MessageSubscribe",
+    "MessageStatus",
+    "TokenRegister",
+    "TokenRegisterBatch",
+    "TokenRegisterStream",
+    "TokenUpdate",
+    "TokenTopicSubscribe",
+    "TokenTopicUnsubscribe",
+    "TokenSubscribe",
+    "TokenExport",
+    "TokenImport",
+    "RateUsage",
+];
+
+/// Metadata telling a limited client how many seconds to wait before calling again.
+pub const RETRY_AFTER: &str = "retry-after";
+/// Identity calls are counted for when neither authenticated nor coming from a known address.
+const ANONYMOUS: &str = "anonymous";
+/// Minimum interval between two sweeps of the buckets which refilled since last used.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits the calls of every client by the configured rate limits, refusing a call exceeding any
+/// of them with `RESOURCE_EXHAUSTED` and the seconds to wait in its `retry-after` metadata.
+///
+/// Meant to run behind the authentication, so that calls are counted per principal, and per peer
+/// address for those not authenticated, whatever tenant they name. Health checks are not limited.
+/// A refused call counts against none of the limits. The send RPCs are charged for each of their
+/// messages, by the services through the [`Charge`] of the call.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    limits: Vec<RateLimitConfig>,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    // Day the calls are counted for, in UTC.
+    day: Option<NaiveDate>,
+    buckets: HashMap<BucketKey, Bucket>,
+    swept: Option<Instant>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct BucketKey {
+    limit: usize,
+    identity: Arc<str>,
+    method: Arc<str>,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    used: u64,
+    // Tenant of the calls counted, whose administrators may look the bucket up.
+    tenant: Arc<str>,
+}
+
+impl Bucket {
+    fn full(limit: &RateLimitConfig, now: Instant, tenant: &str) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            refilled: now,
+            used: 0,
+            tenant: Arc::from(tenant),
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimitConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate_per_s).min(limit.burst as f64);
+        self.refilled = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(limits: Vec<RateLimitConfig>) -> Self {
+        info!(limits = limits.len(), "rate limiting enabled");
+        Self {
+            inner: Arc::new(Inner {
+                limits,
+                buckets: Mutex::new(Buckets::default()),
+            }),
+        }
+    }
+
+    fn buckets(&self) -> std::sync::MutexGuard<'_, Buckets> {
+        // The buckets are left consistent by every panic, being only counters.
+        match self.inner.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Admit a call to a method by a principal, or a peer if not authenticated, within a tenant.
+    pub fn admit(&self, principal: &str, tenant: &str, method: &str) -> Result<(), Status> {
+        self.admit_at(
+            principal,
+            tenant,
+            method,
+            Instant::now(),
+            chrono::Utc::now().naive_utc(),
+        )
+    }
+
+    fn admit_at(
+        &self,
+        principal: &str,
+        tenant: &str,
+        method: &str,
+        now: Instant,
+        utc: NaiveDateTime,
+    ) -> Result<(), Status> {
+        let mut locked = self.buckets();
+        locked.roll(utc.date(), &self.inner.limits, now);
+        locked.sweep(&self.inner.limits, now);
+
+        let keys: Vec<BucketKey> = self
+            .inner
+            .limits
+            .iter()
+            .enumerate()
+            .filter(|(_, limit)| {
+                limit
+                    .method
+                    .as_deref()
+                    .is_none_or(|limited| limited == method)
+            })
+            .map(|(index, limit)| BucketKey {
+                limit: index,
+                identity: Arc::from(match limit.per {
+                    RatePer::Principal => principal,
+                    RatePer::Tenant => tenant,
+                }),
+                method: Arc::from(method),
+            })
+            .collect();
+
+        // Every limit is checked before any is counted against, so a refused call counts for none.
+        let mut refusal: Option<(Duration, String)> = None;
+        for key in keys.iter() {
+            let limit = &self.inner.limits[key.limit];
+            let bucket = locked
+                .buckets
+                .entry(key.clone())
+                .or_insert_with(|| Bucket::full(limit, now, tenant));
+            bucket.refill(limit, now);
+
+            let refused = match limit.daily_quota {
+                Some(quota) if bucket.used >= quota => Some((
+                    until_midnight(utc),
+                    format!(
+                        "daily quota of {} calls to `{}` exhausted by `{}`",
+                        quota, method, key.identity
+                    ),
+                )),
+                _ if bucket.tokens < 1.0 => Some((
+                    Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate_per_s),
+                    format!("rate limit of `{}` exceeded by `{}`", method, key.identity),
+                )),
+                _ => None,
+            };
+
+            if let Some(refused) = refused {
+                if refusal.as_ref().is_none_or(|refusal| refused.0 > refusal.0) {
+                    refusal = Some(refused);
+                }
+            }
+        }
+
+        if let Some((wait, message)) = refusal {
+            let mut status = Status::resource_exhausted(message);
+            // Rounded up, so that the call is admitted once the client waited as told.
+            let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            status
+                .metadata_mut()
+                .insert(RETRY_AFTER, MetadataValue::from(seconds.max(1)));
+            return Err(status);
+        }
+
+        for key in keys.iter() {
+            if let Some(bucket) = locked.buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+                bucket.used += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The current usage of the buckets of the tenant, or of those counted for the identity if
+    /// given.
+    pub fn usage(&self, tenant: &str, identity: Option<&str>) -> Vec<cm::RateUsage> {
+        let now = Instant::now();
+        let utc = chrono::Utc::now().naive_utc();
+
+        let mut locked = self.buckets();
+        locked.roll(utc.date(), &self.inner.limits, now);
+
+        let mut buckets: Vec<_> = locked
+            .buckets
+            .iter()
+            .filter(|(key, bucket)| {
+                &*bucket.tenant == tenant
+                    && identity.is_none_or(|identity| &*key.identity == identity)
+            })
+            .collect();
+        buckets.sort_by(|a, b| a.0.cmp(b.0));
+
+        buckets
+            .into_iter()
+            .map(|(key, bucket)| {
+                let limit = &self.inner.limits[key.limit];
+                let mut bucket = bucket.clone();
+                bucket.refill(limit, now);
+
+                cm::RateUsage {
+                    limit: key.limit as u32,
+                    key: match limit.per {
+                        RatePer::Principal => cm::RateLimitKey::RatePerPrincipal,
+                        RatePer::Tenant => cm::RateLimitKey::RatePerTenant,
+                    } as i32,
+                    identity: key.identity.to_string(),
+                    method: key.method.to_string(),
+                    available: bucket.tokens,
+                    used_today: bucket.used,
+                    daily_quota: limit.daily_quota.unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// The limits a call was admitted by, to count each of its messages past the first against them.
+///
+/// Attached to the calls admitted by the limiter, so that a batch or a stream of messages is
+/// limited as the calls to send each of them would be.
+#[derive(Debug, Clone)]
+pub struct Charge {
+    limiter: RateLimiter,
+    identity: Arc<str>,
+    tenant: Arc<str>,
+    method: Arc<str>,
+}
+
+impl Charge {
+    /// The charge of a request, if it was admitted by a limiter.
+    pub fn of<T>(request: &Request<T>) -> Option<Self> {
+        request.extensions().get::<Self>().cloned()
+    }
+
+    /// Admit another message of the call, as a call of its own.
+    pub fn admit(&self) -> Result<(), Status> {
+        self.limiter
+            .admit(&self.identity, &self.tenant, &self.method)
+    }
+}
+
+impl Buckets {
+    /// Start counting the calls of a new day, forgetting the buckets which refilled since used.
+    fn roll(&mut self, today: NaiveDate, limits: &[RateLimitConfig], now: Instant) {
+        if self.day == Some(today) {
+            return;
+        }
+        self.day = Some(today);
+
+        self.buckets.retain(|key, bucket| {
+            let limit = &limits[key.limit];
+            bucket.refill(limit, now);
+            bucket.used = 0;
+            bucket.tokens < limit.burst as f64
+        });
+    }
+
+    /// Forget the buckets which refilled since last used, at most once per sweep interval, unless
+    /// they count the calls against a daily quota.
+    fn sweep(&mut self, limits: &[RateLimitConfig], now: Instant) {
+        if self
+            .swept
+            .is_some_and(|swept| now.saturating_duration_since(swept) < SWEEP_INTERVAL)
+        {
+            return;
+        }
+        self.swept = Some(now);
+
+        let before = self.buckets.len();
+        self.buckets.retain(|key, bucket| {
+            let limit = &limits[key.limit];
+            let mut refilled = bucket.clone();
+            refilled.refill(limit, now);
+            refilled.tokens < limit.burst as f64 || (limit.daily_quota.is_some() && bucket.used > 0)
+        });
+
+        let swept = before - self.buckets.len();
+        if swept > 0 {
+            debug!(swept, "idle rate limit buckets swept");
+        }
+    }
+}
+
+fn until_midnight(utc: NaiveDateTime) -> Duration {
+    let midnight = utc.date().succ().and_hms(0, 0, 0);
+    (midnight - utc).to_std().unwrap_or_default()
+}
+
+/// The address of the peer of a request, as the identity of a client not authenticated.
+fn peer_address(extensions: &http::Extensions) -> Option<String> {
+    let tcp = extensions.get::<TcpConnectInfo>().or_else(|| {
+        extensions
+            .get::<TlsConnectInfo<TcpConnectInfo>>()
+            .map(TlsConnectInfo::get_ref)
+    })?;
+
+    tcp.remote_addr().map(|addr| addr.ip().to_string())
+}
+
+impl<B> Predicate<http::Request<B>> for RateLimiter {
+    type Request = http::Request<B>;
+
+    fn check(&mut self, mut request: http::Request<B>) -> Result<Self::Request, BoxError> {
+        let path = request.uri().path();
+        if auth::method_exempt(path) {
+            return Ok(request);
+        }
+        // Calls to a path no service serves are counted together, so that they cannot grow the
+        // buckets without bound nor evade a limit by the method they name.
+        let method = metrics::method_label(path);
+
+        let principal = request.extensions().get::<Principal>();
+        let identity = match principal {
+            Some(principal) => principal.subject.clone(),
+            None => peer_address(request.extensions()).unwrap_or_else(|| ANONYMOUS.to_string()),
+        };
+
+        // The tenant named by a caller not authenticated is its own choice, so it is counted by
+        // its address instead. A malformed tenant is counted for the tenant of the principal.
+        let tenant = match principal {
+            Some(principal) => {
+                let named = request
+                    .headers()
+                    .get(TENANT_HEADER)
+                    .and_then(|named| named.to_str().ok());
+                tenant::resolve(Some(principal), named)
+            }
+            None => Ok(Arc::from(identity.as_str())),
+        };
+
+        let admitted = tenant.and_then(|tenant| {
+            self.admit(&identity, &tenant, method)?;
+            Ok(tenant)
+        });
+
+        match admitted {
+            Ok(tenant) => {
+                let charge = Charge {
+                    limiter: self.clone(),
+                    identity: Arc::from(identity),
+                    tenant,
+                    method: Arc::from(method),
+                };
+                request.extensions_mut().insert(charge);
+                Ok(request)
+            }
+            Err(status) => {
+                info!(status = ?&status, path = request.uri().path(), "request failed");
+                Err(Box::new(status))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(method: Option<&str>, per: RatePer, burst: u32) -> RateLimitConfig {
+        RateLimitConfig {
+            method: method.map(str::to_string),
+            per,
+            rate_per_s: 1.0,
+            burst,
+            daily_quota: None,
+        }
+    }
+
+    fn noon() -> NaiveDateTime {
+        NaiveDate::from_ymd(2026, 10, 18).and_hms(12, 0, 0)
+    }
+
+    fn retry_after(status: &Status) -> Option<&str> {
+        status
+            .metadata()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate() {
+        let limiter = RateLimiter::new(vec![limit(None, RatePer::Principal, 2)]);
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            assert!(limiter
+                .admit_at("alice", "acme", "MessageSend", now, noon())
+                .is_ok());
+        }
+        let status = limiter
+            .admit_at("alice", "acme", "MessageSend", now, noon())
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(retry_after(&status), Some("1"));
+
+        let later = now + Duration::from_secs(1);
+        assert!(limiter
+            .admit_at("alice", "acme", "MessageSend", later, noon())
+            .is_ok());
+        assert!(limiter
+            .admit_at("alice", "acme", "MessageSend", later, noon())
+            .is_err());
+    }
+
+    #[test]
+    fn daily_quota_is_reset_at_midnight() {
+        let mut quota = limit(None, RatePer::Principal, 10);
+        quota.daily_quota = Some(2);
+        let limiter = RateLimiter::new(vec![quota]);
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            assert!(limiter
+                .admit_at("alice", "acme", "MessageSend", now, noon())
+                .is_ok());
+        }
+        let status = limiter
+            .admit_at("alice", "acme", "MessageSend", now, noon())
+            .unwrap_err();
+        assert!(status.message().contains("daily quota"));
+        assert_eq!(retry_after(&status), Some("43200"));
+
+        let tomorrow = noon().date().succ().and_hms(0, 0, 1);
+        assert!(limiter
+            .admit_at("alice", "acme", "MessageSend", now, tomorrow)
+            .is_ok());
+    }
+
+    #[test]
+    fn refused_call_counts_against_no_limit() {
+        let limiter = RateLimiter::new(vec![
+            limit(Some("MessageSend"), RatePer::Principal, 1),
+            limit(None, RatePer::Tenant, 10),
+        ]);
+        let now = Instant::now();
+
+        assert!(limiter
+            .admit_at("alice", "acme", "MessageSend", now, noon())
+            .is_ok());
+        for _ in 0..3 {
+            assert!(limiter
+                .admit_at("alice", "acme", "MessageSend", now, noon())
+                .is_err());
+        }
+
+        let locked = limiter.buckets();
+        let tenant = locked
+            .buckets
+            .iter()
+            .find(|(key, _)| key.limit == 1)
+            .map(|(_, bucket)| bucket)
+            .unwrap();
+        assert_eq!(tenant.used, 1);
+        assert_eq!(tenant.tokens, 9.0);
+    }
+
+    #[test]
+    fn calls_are_counted_per_tenant_or_per_principal() {
+        let now = Instant::now();
+
+        let limiter = RateLimiter::new(vec![limit(None, RatePer::Tenant, 1)]);
+        assert!(limiter
+            .admit_at("alice", "acme", "MessageSend", now, noon())
+            .is_ok());
+        assert!(limiter
+            .admit_at("bob", "acme", "MessageSend", now, noon())
+            .is_err());
+        assert!(limiter
+            .admit_at("bob", "globex", "MessageSend", now, noon())
+            .is_ok());
+
+        let limiter = RateLimiter::new(vec![limit(None, RatePer::Principal, 1)]);
+        assert!(limiter
+            .admit_at("alice", "acme", "MessageSend", now, noon())
+            .is_ok());
+        assert!(limiter
+            .admit_at("bob", "acme", "MessageSend", now, noon())
+            .is_ok());
+        assert!(limiter
+            .admit_at("alice", "globex", "MessageSend", now, noon())
+            .is_err());
+    }
+
+    #[test]
+    fn calls_to_unknown_methods_share_a_bucket() {
+        let mut limiter = RateLimiter::new(vec![limit(None, RatePer::Principal, 1)]);
+        let request = |path: &str| http::Request::builder().uri(path).body(()).unwrap();
+
+        assert!(limiter.check(request("/cm.cm_message/Invented1")).is_ok());
+        assert!(limiter.check(request("/cm.cm_message/Invented2")).is_err());
+        assert!(limiter.check(request("/elsewhere/MessageSend")).is_err());
+        assert!(limiter.check(request("/cm.cm_message/MessageSend")).is_ok());
+
+        let locked = limiter.buckets();
+        let mut methods: Vec<_> = locked.buckets.keys().map(|key| &*key.method).collect();
+        methods.sort();
+        assert_eq!(methods, ["MessageSend", "unknown"]);
+    }
+
+    #[test]
+    fn usage_is_reported_within_the_tenant() {
+        let limiter = RateLimiter::new(vec![
+            limit(None, RatePer::Principal, 10),
+            limit(None, RatePer::Tenant, 10),
+        ]);
+        let now = Instant::now();
+        assert!(limiter
+            .admit_at("alice", "acme", "MessageSend", now, noon())
+            .is_ok());
+        assert!(limiter
+            .admit_at("bob", "globex", "MessageSend", now, noon())
+            .is_ok());
+
+        let mut identities: Vec<_> = limiter
+            .usage("acme", None)
+            .into_iter()
+            .map(|usage| usage.identity)
+            .collect();
+        identities.sort();
+        assert_eq!(identities, ["acme", "alice"]);
+        assert!(limiter.usage("acme", Some("bob")).is_empty());
+    }
+}
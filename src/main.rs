@@ -1,48 +1,293 @@
-pub mod database;
-pub mod model;
-pub mod rpc;
+mod cli;
 
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand};
+use cm::cm_admin_server::CmAdminServer;
 use cm::cm_message_server::CmMessageServer;
 use cm::cm_token_server::CmTokenServer;
 
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, oneshot};
+use tokio::time::{self, Instant};
 use tonic::transport::Server;
+use tower::filter::FilterLayer;
 
-use rpc::cm;
-use rpc::cm_message::CmMessageService;
-use tracing::{info, Instrument, Level};
+use opentelemetry::trace::TraceError;
+use pine5_cm_service::rpc::cm;
+use pine5_cm_service::rpc::cm_message::CmMessageService;
+use tracing::{info, warn, Level};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
 
-use crate::rpc::cm_token::CmTokenService;
+use pine5_cm_service::access::AccessPolicy;
+use pine5_cm_service::auth::Authenticator;
+use pine5_cm_service::config::{Config, OtlpConfig, StorageBackend};
+use pine5_cm_service::database::{TokenDb, TokenDbInMemory, TokenDbMetered, TokenDbSharded};
+use pine5_cm_service::dispatch::Dispatcher;
+use pine5_cm_service::lifecycle::Lifecycle;
+use pine5_cm_service::limit::RateLimiter;
+use pine5_cm_service::metrics::{Metrics, MetricsLayer};
+use pine5_cm_service::release::Releaser;
+use pine5_cm_service::rpc::cm_admin::CmAdminService;
+use pine5_cm_service::rpc::cm_token::CmTokenService;
+use pine5_cm_service::telemetry;
+use pine5_cm_service::throttle::Throttle;
+use pine5_cm_service::tls::ServerTls;
 
-fn setup_log() {
-    if cfg!(debug_assertions) {
-        tracing_subscriber::fmt()
-            .with_max_level(Level::DEBUG)
-            .init();
-    } else {
-        tracing_subscriber::fmt().init();
-    }
+/// pine5 cloud messaging microservice.
+#[derive(Debug, Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    // Flags of the default `serve` command.
+    #[command(flatten)]
+    serve: cli::ServeArgs,
+}
+
+// Parsed once at startup, so the size of the flags of `serve` is of no concern.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Serve the RPC services. The default when no command is given.
+    Serve(cli::ServeArgs),
+    /// Export every token of a running service.
+    Export(cli::ExportArgs),
+    /// Import tokens into a running service.
+    Import(cli::ImportArgs),
+}
+
+/// Log to the standard output, and export the spans if configured.
+///
+/// The spans of the calls are exported whatever the level, so that traces are not cut short.
+fn setup_log(level: Level, otlp: Option<&OtlpConfig>) -> Result<(), TraceError> {
+    let export = otlp
+        .map(telemetry::layer)
+        .transpose()?
+        .map(|layer| layer.with_filter(LevelFilter::from_level(level.max(Level::INFO))));
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::from_level(level)))
+        .with(export)
+        .init();
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    setup_log();
+    let cli = Cli::parse();
+
+    match cli.command.unwrap_or(Command::Serve(cli.serve)) {
+        Command::Serve(args) => {
+            let config = match args.config() {
+                Ok(config) => config,
+                Err(error) => {
+                    eprintln!("invalid configuration: {}", error);
+                    std::process::exit(2);
+                }
+            };
+            serve(config).await
+        }
+        Command::Export(args) => cli::export(args).await,
+        Command::Import(args) => cli::import(args).await,
+    }
+}
+
+async fn serve(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    // The level is validated along with the rest of the configuration.
+    setup_log(config.log_level()?, config.otlp.as_ref())?;
+    let exporting = config.otlp.is_some();
+
+    info!(backend = %config.storage.backend, "opening storage");
+
+    let served = match config.storage.backend {
+        StorageBackend::Memory => run(config, TokenDbInMemory::new()).await,
+        StorageBackend::Sharded => {
+            let db = TokenDbSharded::new(config.storage.shards);
+            run(config, db).await
+        }
+        StorageBackend::Durable => {
+            let path = config
+                .storage
+                .path
+                .clone()
+                .ok_or("storage.path is required by the durable backend")?;
+            let db = TokenDbInMemory::open(path, config.wal_options())?;
+            run(config, db).await
+        }
+    };
+
+    if exporting {
+        telemetry::shutdown().await;
+    }
+    served
+}
+
+async fn run<Db: TokenDb>(config: Config, db: Db) -> Result<(), Box<dyn std::error::Error>> {
+    // The store is only timed when the metrics are served.
+    match config.metrics.as_ref().map(|_| Metrics::new()) {
+        Some(metrics) => {
+            let db = TokenDbMetered::new(db, metrics.clone());
+            run_services(config, Arc::new(db), Some(metrics)).await
+        }
+        None => run_services(config, Arc::new(db), None).await,
+    }
+}
 
-    let addr = "[::1]:10000".parse().unwrap();
+async fn run_services<Db: TokenDb>(
+    config: Config,
+    db: Arc<Db>,
+    metrics: Option<Metrics>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let channels = &config.channels;
+    let lifecycle = Lifecycle::new();
+    let access = Arc::new(match &config.auth {
+        Some(auth) => AccessPolicy::new(auth),
+        None => AccessPolicy::open(),
+    });
 
-    let message = CmMessageService::default();
-    let token = CmTokenService::default();
+    let message = CmMessageService::new_with_dispatch_capacity(
+        broadcast::channel(channels.message_broadcast),
+        db.clone(),
+        channels.message_dispatch,
+    )
+    .with_stream_capacity(channels.message_stream)
+    .with_lifecycle(lifecycle.clone())
+    .with_access(access.clone())
+    .with_throttle(config.throttle.as_ref().map(Throttle::new))
+    .with_metrics(metrics.clone());
+    let dispatcher = message.dispatcher();
+    let releaser = message.releaser();
+    let token = CmTokenService::new(broadcast::channel(channels.token_broadcast), db.clone())
+        .with_policy(config.registration_policy)
+        .with_stream_capacity(channels.token_stream)
+        .with_lifecycle(lifecycle.clone())
+        .with_access(access.clone())
+        .with_metrics(metrics.clone());
+    let limiter = Some(config.rate_limits.clone())
+        .filter(|limits| !limits.is_empty())
+        .map(RateLimiter::new);
+    let admin = CmAdminService::new_with_db(db.clone())
+        .with_access(access)
+        .with_limiter(limiter.clone());
 
     let message_svc = CmMessageServer::new(message);
     let token_svc = CmTokenServer::new(token);
+    let admin_svc = CmAdminServer::new(admin);
 
-    info!(message = "Starting server.", %addr);
+    info!(
+        message = "Starting server.",
+        addr = %config.listen,
+        tls = config.tls.is_some(),
+        auth = config.auth.is_some()
+    );
 
-    Server::builder()
-        .trace_fn(|_| tracing::info_span!("cm_server"))
+    if let (Some(metrics), Some(listen)) = (&metrics, config.metrics.as_ref()) {
+        metrics.serve(listen.listen, db.clone(), dispatcher.clone())?;
+    }
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let shutdown = async {
+        let _ = shutdown_rx.await;
+    };
+    let auth = config.auth.as_ref().map(Authenticator::new).transpose()?;
+    let router = Server::builder()
+        .trace_fn(telemetry::server_span)
+        // Ahead of the authentication and the limits, so that the calls they refuse are counted.
+        .layer(tower::util::option_layer(metrics.map(MetricsLayer::new)))
+        .layer(tower::util::option_layer(auth.map(FilterLayer::new)))
+        // Behind the authentication, so that calls are counted per principal.
+        .layer(tower::util::option_layer(limiter.map(FilterLayer::new)))
         .add_service(message_svc)
         .add_service(token_svc)
-        .serve(addr)
-        .await?;
+        .add_service(admin_svc);
+
+    let mut server: Pin<Box<dyn Future<Output = Result<(), tonic::transport::Error>>>> =
+        match &config.tls {
+            Some(tls) => {
+                let tls = ServerTls::load(tls.clone())?;
+                let listener = TcpListener::bind(config.listen).await?;
+                Box::pin(router.serve_with_incoming_shutdown(tls.incoming(listener), shutdown))
+            }
+            None => Box::pin(router.serve_with_shutdown(config.listen, shutdown)),
+        };
+
+    tokio::select! {
+        served = &mut server => return Ok(served?),
+        _ = shutdown_signal() => {}
+    }
 
+    info!(deadline_ms = config.shutdown_deadline_ms, "shutting down");
+    let deadline = Instant::now() + config.shutdown_deadline();
+
+    if time::timeout_at(deadline, drain(&lifecycle, &releaser, &dispatcher))
+        .await
+        .is_err()
+    {
+        warn!("shutdown deadline passed while draining subscribers");
+    }
+
+    // Stop accepting connections and wait for the requests in flight, so that no token is
+    // written after the storage is flushed.
+    let _ = shutdown_tx.send(());
+    let served = match time::timeout_at(deadline, server).await {
+        Ok(served) => served,
+        Err(_) => {
+            warn!("shutdown deadline passed with requests in flight");
+            Ok(())
+        }
+    };
+
+    // Flushed regardless of the deadline, as whatever it leaves out is only recovered from the log.
+    match db.flush().await {
+        Ok(_) => info!("storage flushed"),
+        Err(error) => warn!(%error, "failed to flush storage"),
+    }
+    served?;
+
+    info!("server stopped");
     Ok(())
 }
+
+/// Refuse new sends, stop releasing the held back ones, which are left pending, flush the queued
+/// ones to the subscribers and close their streams.
+async fn drain(lifecycle: &Lifecycle, releaser: &Releaser, dispatcher: &Dispatcher) {
+    lifecycle.stop_serving();
+    releaser.stop().await;
+    dispatcher.flushed().await;
+    info!("dispatcher flushed");
+
+    lifecycle.close_subscribers();
+    lifecycle.subscribers_closed().await;
+    info!("subscribers closed");
+}
+
+/// Resolve once the process is asked to terminate, by SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(error) => {
+                warn!(%error, "failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+        _ = terminate => info!("received SIGTERM"),
+    }
+}
@@ -0,0 +1,423 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tokio::task::JoinHandle;
+use tonic::{Code, Status};
+use tower::{BoxError, Layer, Service};
+use tracing::{info, warn};
+
+use crate::cm;
+use crate::database::TokenDb;
+use crate::dispatch::Dispatcher;
+use crate::limit::METHODS;
+
+/// Path the metrics are served at.
+pub const METRICS_PATH: &str = "/metrics";
+/// Services whose calls are labelled with their method.
+const SERVICES: [&str; 3] = ["/cm.cm_message/", "/cm.cm_token/", "/cm.cm_admin/"];
+/// Methods of the health checks every service serves.
+const HEALTH_METHODS: [&str; 2] = ["Check", "Watch"];
+/// Method label of the calls to a path no service serves.
+const UNKNOWN_METHOD: &str = "unknown";
+
+/// What became of a message to a token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageOutcome {
+    /// Queued for delivery to the token.
+    Sent,
+    Delivered,
+    /// Replaced by a newer message before being delivered.
+    Collapsed,
+    /// Failed for no longer being relevant by the time it could have been delivered.
+    Expired,
+    /// Failed for the token having received as many messages as it may.
+    Throttled,
+}
+
+/// Metrics of the server, exposed in the Prometheus text format.
+///
+/// Counting is cheap and done as things happen, while the gauges of the token store and the
+/// dispatcher are sampled when the metrics are scraped.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    registry: Registry,
+    rpc_calls: IntCounterVec,
+    rpc_duration: HistogramVec,
+    messages_sent: IntCounterVec,
+    messages_delivered: IntCounterVec,
+    messages_collapsed: IntCounterVec,
+    messages_failed: IntCounterVec,
+    subscribers: IntGaugeVec,
+    broadcast_lagged: IntCounterVec,
+    dispatch_queued: IntGauge,
+    tokens: IntGauge,
+    token_db_duration: HistogramVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("cm".to_string()), None)
+            .expect("the namespace of the metrics is valid");
+
+        let inner = Inner {
+            rpc_calls: IntCounterVec::new(
+                Opts::new("rpc_calls_total", "RPC calls by method and status code"),
+                &["method", "code"],
+            )
+            .expect("the metric is valid"),
+            rpc_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "rpc_duration_seconds",
+                    "Time to respond to RPC calls by method, up to the opening of a stream",
+                ),
+                &["method"],
+            )
+            .expect("the metric is valid"),
+            messages_sent: IntCounterVec::new(
+                Opts::new(
+                    "messages_sent_total",
+                    "Messages queued for delivery, per token by provider",
+                ),
+                &["provider"],
+            )
+            .expect("the metric is valid"),
+            messages_delivered: IntCounterVec::new(
+                Opts::new(
+                    "messages_delivered_total",
+                    "Messages delivered to subscribers, per token by provider",
+                ),
+                &["provider"],
+            )
+            .expect("the metric is valid"),
+            messages_collapsed: IntCounterVec::new(
+                Opts::new(
+                    "messages_collapsed_total",
+                    "Messages replaced by a newer one before being delivered, per token by provider",
+                ),
+                &["provider"],
+            )
+            .expect("the metric is valid"),
+            messages_failed: IntCounterVec::new(
+                Opts::new(
+                    "messages_failed_total",
+                    "Messages never delivered, per token by provider and reason",
+                ),
+                &["provider", "reason"],
+            )
+            .expect("the metric is valid"),
+            subscribers: IntGaugeVec::new(
+                Opts::new("subscribers", "Subscribers streaming from each service"),
+                &["service"],
+            )
+            .expect("the metric is valid"),
+            broadcast_lagged: IntCounterVec::new(
+                Opts::new(
+                    "broadcast_lagged_total",
+                    "Broadcasts dropped by subscribers lagging behind, by service",
+                ),
+                &["service"],
+            )
+            .expect("the metric is valid"),
+            dispatch_queued: IntGauge::new(
+                "dispatch_queued",
+                "Messages queued in the priority lanes, not yet broadcast",
+            )
+            .expect("the metric is valid"),
+            tokens: IntGauge::new("tokens", "Tokens in the store, of every tenant")
+                .expect("the metric is valid"),
+            token_db_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "token_db_duration_seconds",
+                    "Time taken by the operations of the token store",
+                )
+                .buckets(prometheus::exponential_buckets(0.000_01, 4.0, 10).unwrap_or_default()),
+                &["operation"],
+            )
+            .expect("the metric is valid"),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 11] = [
+            Box::new(inner.rpc_calls.clone()),
+            Box::new(inner.rpc_duration.clone()),
+            Box::new(inner.messages_sent.clone()),
+            Box::new(inner.messages_delivered.clone()),
+            Box::new(inner.messages_collapsed.clone()),
+            Box::new(inner.messages_failed.clone()),
+            Box::new(inner.subscribers.clone()),
+            Box::new(inner.broadcast_lagged.clone()),
+            Box::new(inner.dispatch_queued.clone()),
+            Box::new(inner.tokens.clone()),
+            Box::new(inner.token_db_duration.clone()),
+        ];
+        for collector in collectors {
+            inner
+                .registry
+                .register(collector)
+                .expect("the metrics are registered once");
+        }
+
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Count an RPC call which was responded to with the code after the elapsed time.
+    pub fn rpc(&self, method: &str, code: Code, elapsed: Duration) {
+        self.inner
+            .rpc_calls
+            .with_label_values(&[method, &format!("{:?}", code)])
+            .inc();
+        self.inner
+            .rpc_duration
+            .with_label_values(&[method])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Count what became of a message to a token of the platform.
+    pub fn message(&self, platform: cm::Platform, outcome: MessageOutcome) {
+        let provider = provider(platform);
+        match outcome {
+            MessageOutcome::Sent => self.inner.messages_sent.with_label_values(&[provider]),
+            MessageOutcome::Delivered => {
+                self.inner.messages_delivered.with_label_values(&[provider])
+            }
+            MessageOutcome::Collapsed => {
+                self.inner.messages_collapsed.with_label_values(&[provider])
+            }
+            MessageOutcome::Expired => self
+                .inner
+                .messages_failed
+                .with_label_values(&[provider, "expired"]),
+            MessageOutcome::Throttled => self
+                .inner
+                .messages_failed
+                .with_label_values(&[provider, "throttled"]),
+        }
+        .inc();
+    }
+
+    /// Count a subscriber of the service for as long as the returned guard is held.
+    pub fn subscribed(&self, service: &str) -> Subscribed {
+        let gauge = self.inner.subscribers.with_label_values(&[service]);
+        gauge.inc();
+        Subscribed { gauge }
+    }
+
+    /// Count the broadcasts a subscriber of the service skipped for lagging behind.
+    pub fn lagged(&self, service: &str, skipped: u64) {
+        self.inner
+            .broadcast_lagged
+            .with_label_values(&[service])
+            .inc_by(skipped);
+    }
+
+    /// Record the time an operation of the token store took.
+    pub fn token_db(&self, operation: &str, elapsed: Duration) {
+        self.inner
+            .token_db_duration
+            .with_label_values(&[operation])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Sample the gauges of the store and the dispatcher, and encode every metric.
+    async fn scrape<Db: TokenDb>(&self, db: &Db, dispatcher: &Dispatcher) -> Response<Body> {
+        match db.count().await {
+            Ok(count) => self.inner.tokens.set(count as i64),
+            Err(error) => warn!(%error, "failed to count the tokens"),
+        }
+        self.inner.dispatch_queued.set(dispatcher.queued() as i64);
+
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+        if let Err(error) = encoder.encode(&self.inner.registry.gather(), &mut buffer) {
+            warn!(%error, "failed to encode the metrics");
+            return status_response(http::StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        let mut response = Response::new(Body::from(buffer));
+        if let Ok(content_type) = http::HeaderValue::from_str(encoder.format_type()) {
+            response
+                .headers_mut()
+                .insert(http::header::CONTENT_TYPE, content_type);
+        }
+        response
+    }
+
+    /// Serve the metrics over HTTP at `/metrics` on the address, binding it now and serving from
+    /// a spawned task. Must be called within a tokio runtime.
+    pub fn serve<Db: TokenDb>(
+        &self,
+        listen: SocketAddr,
+        db: Arc<Db>,
+        dispatcher: Dispatcher,
+    ) -> Result<JoinHandle<()>, hyper::Error> {
+        let builder = hyper::Server::try_bind(&listen)?;
+        let metrics = self.clone();
+
+        let make_service = make_service_fn(move |_| {
+            let (metrics, db, dispatcher) = (metrics.clone(), db.clone(), dispatcher.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: http::Request<Body>| {
+                    let (metrics, db, dispatcher) =
+                        (metrics.clone(), db.clone(), dispatcher.clone());
+                    async move {
+                        let response = match (request.method(), request.uri().path()) {
+                            (&http::Method::GET, METRICS_PATH) => {
+                                metrics.scrape(&*db, &dispatcher).await
+                            }
+                            (_, METRICS_PATH) => {
+                                status_response(http::StatusCode::METHOD_NOT_ALLOWED)
+                            }
+                            _ => status_response(http::StatusCode::NOT_FOUND),
+                        };
+                        Ok::<_, Infallible>(response)
+                    }
+                }))
+            }
+        });
+
+        info!(%listen, "serving metrics");
+        let server = builder.serve(make_service);
+        Ok(tokio::spawn(async move {
+            if let Err(error) = server.await {
+                warn!(%error, "metrics server failed");
+            }
+        }))
+    }
+}
+
+/// Gauge of the subscribers of a service, decremented once dropped.
+#[derive(Debug)]
+pub struct Subscribed {
+    gauge: IntGauge,
+}
+
+impl Drop for Subscribed {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+fn provider(platform: cm::Platform) -> &'static str {
+    match platform {
+        cm::Platform::Unspecified => "unspecified",
+        cm::Platform::Fcm => "fcm",
+        cm::Platform::Apns => "apns",
+        cm::Platform::Webpush => "webpush",
+    }
+}
+
+fn status_response(status: http::StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// Counts and times every RPC call by its method and the code it is responded to with.
+#[derive(Debug, Clone)]
+pub struct MetricsLayer {
+    metrics: Metrics,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Metrics) -> Self {
+        Self { metrics }
+    }
+}
+
+/// Label of the method of a call, among the methods served only, so that calls to arbitrary paths
+/// cannot grow the series without bound.
+pub(crate) fn method_label(path: &str) -> &'static str {
+    let method = SERVICES
+        .iter()
+        .find_map(|service| path.strip_prefix(service));
+
+    METHODS
+        .iter()
+        .chain(HEALTH_METHODS.iter())
+        .find(|known| Some(**known) == method)
+        .copied()
+        .unwrap_or(UNKNOWN_METHOD)
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Metrics,
+}
+
+impl<S, B, R> Service<http::Request<B>> for MetricsService<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<R>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<R>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let method = method_label(request.uri().path());
+        let metrics = self.metrics.clone();
+        let started = Instant::now();
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = response.await.map_err(Into::into);
+
+            // A refused call carries its code in the headers of the response, while an accepted
+            // one carries it in the trailers, once complete, and is counted as OK.
+            let code = match &response {
+                Ok(response) => response
+                    .headers()
+                    .get("grpc-status")
+                    .and_then(|code| code.to_str().ok())
+                    .and_then(|code| code.parse().ok())
+                    .map_or(Code::Ok, Code::from_i32),
+                Err(error) => error
+                    .downcast_ref::<Status>()
+                    .map_or(Code::Unknown, Status::code),
+            };
+            metrics.rpc(method, code, started.elapsed());
+
+            response
+        })
+    }
+}
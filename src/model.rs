@@ -1,18 +1,35 @@
+use std::collections::BTreeSet;
 use std::sync::Arc;
 
 use crate::cm::{self, Tokens};
 use chrono::NaiveDateTime;
 use prost_types::Timestamp;
 
+/// Tenant of the callers whose credentials name none.
+pub const DEFAULT_TENANT: &str = "";
+
+/// Key of a token, unique within its tenant.
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct TokenKey {
     pub key: Arc<str>,
+    pub tenant: Arc<str>,
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct Token {
     pub key: TokenKey,
     pub timestamp: chrono::NaiveDateTime,
+    pub topics: BTreeSet<Arc<str>>,
+    pub owner: Option<Arc<str>>,
+    pub platform: cm::Platform,
+    pub webpush: Option<WebPushKeys>,
+    pub version: u64,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct WebPushKeys {
+    pub p256dh: Arc<str>,
+    pub auth: Arc<str>,
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -21,10 +38,35 @@ pub struct TokenUpdate {
     pub delta: Token,
 }
 
+/// Outcome of inserting a token, telling a newly created token from a refreshed one.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum TokenInsert {
+    Created(Token),
+    Refreshed(TokenUpdate),
+}
+
+impl TokenInsert {
+    /// The token as it was inserted.
+    pub fn token(&self) -> &Token {
+        match self {
+            TokenInsert::Created(token) => token,
+            TokenInsert::Refreshed(update) => &update.delta,
+        }
+    }
+
+    pub fn into_token(self) -> Token {
+        match self {
+            TokenInsert::Created(token) => token,
+            TokenInsert::Refreshed(update) => update.delta,
+        }
+    }
+}
+
 impl From<TokenKey> for cm::TokenKey {
     fn from(source: TokenKey) -> Self {
         Self {
             key: source.key.to_string(),
+            tenant: source.tenant.to_string(),
         }
     }
 }
@@ -33,6 +75,7 @@ impl From<&TokenKey> for cm::TokenKey {
     fn from(source: &TokenKey) -> Self {
         Self {
             key: source.key.to_string(),
+            tenant: source.tenant.to_string(),
         }
     }
 }
@@ -41,6 +84,7 @@ impl From<cm::TokenKey> for TokenKey {
     fn from(source: cm::TokenKey) -> Self {
         Self {
             key: Arc::from(source.key),
+            tenant: Arc::from(source.tenant),
         }
     }
 }
@@ -53,6 +97,19 @@ impl From<Token> for cm::Token {
                 seconds: source.timestamp.timestamp(),
                 nanos: 0,
             }),
+            topics: source
+                .topics
+                .iter()
+                .map(|topic| topic.to_string())
+                .collect(),
+            owner: source
+                .owner
+                .as_ref()
+                .map(|owner| owner.to_string())
+                .unwrap_or_default(),
+            platform: source.platform as i32,
+            webpush: source.webpush.as_ref().map(cm::WebPushKeys::from),
+            version: source.version,
         }
     }
 }
@@ -65,6 +122,19 @@ impl From<&Token> for cm::Token {
                 seconds: source.timestamp.timestamp(),
                 nanos: 0,
             }),
+            topics: source
+                .topics
+                .iter()
+                .map(|topic| topic.to_string())
+                .collect(),
+            owner: source
+                .owner
+                .as_ref()
+                .map(|owner| owner.to_string())
+                .unwrap_or_default(),
+            platform: source.platform as i32,
+            webpush: source.webpush.as_ref().map(cm::WebPushKeys::from),
+            version: source.version,
         }
     }
 }
@@ -94,11 +164,18 @@ impl From<TokenKey> for Token {
 }
 
 impl TokenKey {
+    /// A key in the default tenant.
     pub fn new(key: &str) -> Self {
         Self {
             key: Arc::from(key),
+            tenant: Arc::from(DEFAULT_TENANT),
         }
     }
+
+    /// Move the key into a tenant.
+    pub fn with_tenant(self, tenant: Arc<str>) -> Self {
+        Self { tenant, ..self }
+    }
 }
 
 impl Token {
@@ -106,6 +183,52 @@ impl Token {
         Self {
             key,
             timestamp: chrono::Utc::now().naive_utc(),
+            topics: BTreeSet::new(),
+            owner: None,
+            platform: cm::Platform::Unspecified,
+            webpush: None,
+            version: 0,
+        }
+    }
+
+    /// Declare the platform the key is issued by, along with the keys of a Web Push subscription.
+    pub fn with_platform(self, platform: cm::Platform, webpush: Option<WebPushKeys>) -> Self {
+        Self {
+            platform,
+            webpush,
+            ..self
+        }
+    }
+
+    /// Assign the token to an owner, e.g. the user the device belongs to.
+    pub fn with_owner(self, owner: Option<Arc<str>>) -> Self {
+        Self { owner, ..self }
+    }
+
+    /// Derive a refreshed copy of the token, retaining its state but renewing the timestamp and
+    /// advancing the version.
+    pub fn refresh(&self) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().naive_utc(),
+            version: self.version + 1,
+            ..self.clone()
+        }
+    }
+
+    /// Convert a token read back from storage, which unlike one from a request was not checked
+    /// beforehand, failing rather than panicking on a missing key or a timestamp out of range.
+    pub fn restored(source: cm::Token) -> Result<Self, String> {
+        if source.key.is_none() {
+            return Err("key is missing".to_string());
+        }
+        match &source.timestamp {
+            Some(timestamp)
+                if NaiveDateTime::from_timestamp_opt(timestamp.seconds, 0).is_some() =>
+            {
+                Ok(Self::from(source))
+            }
+            Some(_) => Err("timestamp is out of range".to_string()),
+            None => Err("timestamp is missing".to_string()),
         }
     }
 }
@@ -115,6 +238,31 @@ impl From<cm::Token> for Token {
         Self {
             key: source.key.unwrap().into(),
             timestamp: NaiveDateTime::from_timestamp(source.timestamp.unwrap().seconds, 0),
+            topics: source.topics.into_iter().map(Arc::from).collect(),
+            owner: Some(source.owner)
+                .filter(|owner| !owner.is_empty())
+                .map(Arc::from),
+            platform: cm::Platform::from_i32(source.platform).unwrap_or(cm::Platform::Unspecified),
+            webpush: source.webpush.map(WebPushKeys::from),
+            version: source.version,
+        }
+    }
+}
+
+impl From<&WebPushKeys> for cm::WebPushKeys {
+    fn from(source: &WebPushKeys) -> Self {
+        Self {
+            p256dh: source.p256dh.to_string(),
+            auth: source.auth.to_string(),
+        }
+    }
+}
+
+impl From<cm::WebPushKeys> for WebPushKeys {
+    fn from(source: cm::WebPushKeys) -> Self {
+        Self {
+            p256dh: Arc::from(source.p256dh),
+            auth: Arc::from(source.auth),
         }
     }
 }
@@ -0,0 +1,804 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use chrono::NaiveDateTime;
+use prost_types::{Duration, Timestamp};
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+use crate::cm;
+use crate::metrics::{MessageOutcome, Metrics};
+use crate::model::TokenKey;
+use crate::telemetry::TraceContext;
+use crate::throttle::Throttled;
+
+/// Number of delivery statuses retained for lookup before the oldest are forgotten.
+const STATUS_RETENTION: usize = 65536;
+/// Number of messages kept pending per token, as FCM keeps for a device offline, past which the
+/// oldest of them expire.
+const PENDING_PER_KEY_MAX: usize = 100;
+/// Number of messages kept pending over every token, past which the oldest of them expire.
+const PENDING_MAX: usize = 1 << 20;
+/// Minimum interval between two sweeps of the expired pending messages.
+const SWEEP_INTERVAL_SECONDS: i64 = 1;
+/// Maximum time to live of a message, as imposed by FCM.
+pub const MAX_TTL_SECONDS: i64 = 28 * 24 * 60 * 60;
+
+/// Messages pending delivery to their tokens, along with the delivery status of every message.
+///
+/// Pending messages sharing a collapse key are coalesced per token, so that only the latest
+/// of them is delivered to a device which comes back online. Messages held back from a token by
+/// its throttling stay pending, but are not delivered before they are released. The pending
+/// messages are bounded per token and in total, expiring the oldest past either bound.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    inner: Mutex<OutboxInner>,
+}
+
+#[derive(Debug, Default)]
+struct OutboxInner {
+    pending: HashMap<TokenKey, Vec<Pending>>,
+    // Number of messages pending over every token.
+    queued: usize,
+    statuses: HashMap<Arc<str>, Status>,
+    order: VecDeque<Arc<str>>,
+    sequence: u64,
+    swept: Option<NaiveDateTime>,
+    metrics: Option<Metrics>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    sequence: u64,
+    id: Arc<str>,
+    collapse_key: Option<Arc<str>>,
+    expire_at: Option<NaiveDateTime>,
+    held_until: Option<NaiveDateTime>,
+    message: Arc<cm::Message>,
+    trace_context: Arc<TraceContext>,
+}
+
+#[derive(Debug, Default)]
+struct Status {
+    tenant: Arc<str>,
+    deliveries: HashMap<TokenKey, Delivery>,
+    collapsed: Vec<Arc<str>>,
+}
+
+#[derive(Debug, Clone)]
+struct Delivery {
+    state: cm::DeliveryState,
+    // Platform of the token, as the provider the delivery is counted for.
+    platform: cm::Platform,
+    collapsed_by: Option<Arc<str>>,
+    not_before: Option<NaiveDateTime>,
+}
+
+impl Delivery {
+    fn new(state: cm::DeliveryState, platform: cm::Platform) -> Self {
+        Self {
+            state,
+            platform,
+            collapsed_by: None,
+            not_before: None,
+        }
+    }
+
+    fn settled(&self) -> bool {
+        !matches!(
+            self.state,
+            cm::DeliveryState::DeliveryPending | cm::DeliveryState::DeliveryDelayed
+        )
+    }
+}
+
+fn timestamp(moment: NaiveDateTime) -> Timestamp {
+    Timestamp {
+        seconds: moment.timestamp(),
+        nanos: moment.timestamp_subsec_nanos() as i32,
+    }
+}
+
+/// The moment of a timestamp, unless out of range.
+fn moment(timestamp: &Timestamp) -> Option<NaiveDateTime> {
+    let nanos = u32::try_from(timestamp.nanos)
+        .ok()
+        .filter(|nanos| *nanos < 1_000_000_000)?;
+    NaiveDateTime::from_timestamp_opt(timestamp.seconds, nanos)
+}
+
+fn message_collapse_key(message: &cm::Message) -> Option<Arc<str>> {
+    message
+        .payload
+        .as_ref()
+        .map(|payload| payload.collapse_key.as_str())
+        .filter(|collapse_key| !collapse_key.is_empty())
+        .map(Arc::from)
+}
+
+/// Moment after which the message is no longer relevant, if any and valid.
+pub fn message_expiry(message: &cm::Message) -> Option<NaiveDateTime> {
+    message.expire_at.as_ref().and_then(moment)
+}
+
+pub fn message_expired(message: &cm::Message, now: NaiveDateTime) -> bool {
+    message_expiry(message).is_some_and(|expire_at| expire_at <= now)
+}
+
+/// Settle the expiry of a message from its time to live, unless an absolute expiry is given,
+/// which is capped to the maximum time to live.
+pub fn message_expire_at(message: &mut cm::Message, now: NaiveDateTime) -> Result<(), String> {
+    if let Some(expire_at) = &message.expire_at {
+        let expire_at = moment(expire_at).ok_or_else(|| "expire_at is out of range".to_string())?;
+        let latest = now + chrono::Duration::seconds(MAX_TTL_SECONDS);
+        message.expire_at = Some(timestamp(expire_at.min(latest)));
+        return Ok(());
+    }
+
+    // A zero time to live asks providers for a delivery now or never, which is left to them.
+    if let Some(ttl) = message
+        .payload
+        .as_ref()
+        .and_then(|payload| payload.ttl.as_ref())
+        .filter(|ttl| ttl.seconds > 0 || ttl.nanos > 0)
+    {
+        let expire_at = now
+            + chrono::Duration::seconds(ttl.seconds)
+            + chrono::Duration::nanoseconds(ttl.nanos as i64);
+        message.expire_at = Some(timestamp(expire_at));
+    }
+
+    Ok(())
+}
+
+/// Rewrite the time to live of a message to what remains of it, as handed to push providers.
+pub fn message_remaining_ttl(message: &mut cm::Message, now: NaiveDateTime) {
+    let expire_at = match message_expiry(message) {
+        Some(expire_at) => expire_at,
+        None => return,
+    };
+
+    let remaining = (expire_at - now).max(chrono::Duration::zero());
+    let seconds = remaining.num_seconds();
+    let nanos = (remaining - chrono::Duration::seconds(seconds))
+        .num_nanoseconds()
+        .unwrap_or_default() as i32;
+
+    // A message of content only carries no time to live, only its expiry.
+    if let Some(payload) = message.payload.as_mut() {
+        payload.ttl = Some(Duration { seconds, nanos });
+    }
+}
+
+fn message_codomain(message: &cm::Message) -> Vec<TokenKey> {
+    message
+        .codomain
+        .as_ref()
+        .map(|codomain| codomain.keys.iter().cloned().map(TokenKey::from).collect())
+        .unwrap_or_default()
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count what becomes of every message into the metrics, if given.
+    pub fn with_metrics(mut self, metrics: Option<Metrics>) -> Self {
+        self.inner.get_mut().metrics = metrics;
+        self
+    }
+
+    /// Queue a message for delivery to every token of its codomain, but those it was throttled
+    /// for: it is dropped for those it was throttled, and held back from those it was delayed for.
+    /// The deliveries are counted for the given platforms of the tokens, and traced in the trace
+    /// the message was sent in.
+    ///
+    /// Returns the ids of the older pending messages which were replaced by it.
+    pub async fn enqueue(
+        &self,
+        message: Arc<cm::Message>,
+        trace_context: TraceContext,
+        throttled: &HashMap<TokenKey, Throttled>,
+        platforms: &HashMap<TokenKey, cm::Platform>,
+    ) -> Vec<Arc<str>> {
+        let id: Arc<str> = Arc::from(message.id.as_str());
+        let collapse_key = message_collapse_key(&message);
+        let expire_at = message_expiry(&message);
+        let trace_context = Arc::new(trace_context);
+        let now = chrono::Utc::now().naive_utc();
+
+        let mut locked = self.inner.lock().await;
+        locked.sweep(now);
+
+        let mut status = Status {
+            tenant: Arc::from(message.tenant.as_str()),
+            ..Status::default()
+        };
+        let mut collapsed = Vec::new();
+        let mut evicted = Vec::new();
+
+        locked.sequence += 1;
+        let sequence = locked.sequence;
+
+        for key in message_codomain(&message) {
+            let platform = platforms
+                .get(&key)
+                .copied()
+                .unwrap_or(cm::Platform::Unspecified);
+            locked.count(platform, MessageOutcome::Sent);
+
+            let (held_until, replaces) = match throttled.get(&key) {
+                Some(Throttled::Dropped) => {
+                    locked.count(platform, MessageOutcome::Throttled);
+                    let delivery = Delivery::new(cm::DeliveryState::DeliveryThrottled, platform);
+                    status.deliveries.insert(key, delivery);
+                    continue;
+                }
+                Some(Throttled::Held { delay, collapses }) => (
+                    Some(
+                        now + chrono::Duration::from_std(*delay)
+                            .unwrap_or_else(|_| chrono::Duration::zero()),
+                    ),
+                    collapses.as_ref(),
+                ),
+                None => (None, None),
+            };
+
+            let queue = locked.pending.entry(key.clone()).or_default();
+            let before = queue.len();
+
+            // Replace the pending messages to the same token sharing the collapse key, and the
+            // one held back for the token which this one takes the turn of.
+            queue.retain(|pending| {
+                let shares_key = collapse_key.is_some()
+                    && pending.collapse_key.as_ref() == collapse_key.as_ref();
+                if !shares_key && Some(&pending.id) != replaces {
+                    return true;
+                }
+                collapsed.push((pending.id.clone(), key.clone()));
+                false
+            });
+
+            queue.push(Pending {
+                sequence,
+                id: id.clone(),
+                collapse_key: collapse_key.clone(),
+                expire_at,
+                held_until,
+                message: message.clone(),
+                trace_context: trace_context.clone(),
+            });
+
+            // The oldest messages to the token expire once past the bound.
+            let overflow = queue.len().saturating_sub(PENDING_PER_KEY_MAX);
+            evicted.extend(
+                queue
+                    .iter()
+                    .take(overflow)
+                    .map(|pending| (pending.id.clone(), key.clone())),
+            );
+            let after = queue.len();
+            locked.queued = locked.queued + after - before;
+
+            let delivery = match held_until {
+                Some(held_until) => Delivery {
+                    not_before: Some(held_until),
+                    ..Delivery::new(cm::DeliveryState::DeliveryDelayed, platform)
+                },
+                None => Delivery::new(cm::DeliveryState::DeliveryPending, platform),
+            };
+            status.deliveries.insert(key, delivery);
+        }
+
+        for (collapsed_id, key) in collapsed.iter() {
+            let delivery = locked
+                .statuses
+                .get_mut(collapsed_id)
+                .and_then(|status| status.deliveries.get_mut(key));
+            if let Some(delivery) = delivery {
+                delivery.state = cm::DeliveryState::DeliveryCollapsed;
+                delivery.collapsed_by = Some(id.clone());
+                delivery.not_before = None;
+
+                let platform = delivery.platform;
+                locked.count(platform, MessageOutcome::Collapsed);
+            }
+
+            if !status.collapsed.contains(collapsed_id) {
+                status.collapsed.push(collapsed_id.clone());
+            }
+        }
+
+        debug!(%id, collapsed = status.collapsed.len(), "message queued");
+
+        for (evicted_id, key) in evicted.iter() {
+            debug!(id = %evicted_id, "pending message evicted");
+            locked.settle(
+                evicted_id,
+                std::slice::from_ref(key),
+                cm::DeliveryState::DeliveryExpired,
+            );
+        }
+
+        let collapsed = status.collapsed.clone();
+        locked.statuses.insert(id.clone(), status);
+        locked.order.push_back(id);
+        locked.evict(PENDING_MAX);
+
+        // Forget the oldest statuses once past the retention.
+        while locked.order.len() > STATUS_RETENTION {
+            if let Some(oldest) = locked.order.pop_front() {
+                locked.statuses.remove(&oldest);
+            }
+        }
+
+        collapsed
+    }
+
+    /// Select the keys to which a message is still deliverable, i.e. not collapsed by a newer one.
+    pub async fn deliverable(&self, id: &str, keys: Vec<TokenKey>) -> Vec<TokenKey> {
+        let locked = self.inner.lock().await;
+
+        match locked.statuses.get(id) {
+            Some(status) => keys
+                .into_iter()
+                .filter(|key| {
+                    status.deliveries.get(key).is_none_or(|delivery| {
+                        delivery.state != cm::DeliveryState::DeliveryCollapsed
+                    })
+                })
+                .collect(),
+            None => keys,
+        }
+    }
+
+    /// Release a message held back from the given keys, selecting those it is still pending for.
+    pub async fn release(&self, id: &str, keys: &[TokenKey]) -> Vec<TokenKey> {
+        let now = chrono::Utc::now().naive_utc();
+        let mut locked = self.inner.lock().await;
+
+        let mut released = Vec::new();
+        let mut expired = Vec::new();
+        for key in keys.iter() {
+            let pending = locked
+                .pending
+                .get_mut(key)
+                .and_then(|queue| queue.iter_mut().find(|pending| &*pending.id == id));
+            let pending = match pending {
+                Some(pending) => pending,
+                None => continue,
+            };
+            // The message expired while held back, so it is not delivered at all.
+            if pending.expire_at.is_some_and(|expire_at| expire_at <= now) {
+                expired.push(key.clone());
+                continue;
+            }
+            pending.held_until = None;
+            released.push(key.clone());
+
+            let delivery = locked
+                .statuses
+                .get_mut(id)
+                .and_then(|status| status.deliveries.get_mut(key))
+                .filter(|delivery| delivery.state == cm::DeliveryState::DeliveryDelayed);
+            if let Some(delivery) = delivery {
+                delivery.state = cm::DeliveryState::DeliveryPending;
+                delivery.not_before = None;
+            }
+        }
+
+        if !expired.is_empty() {
+            debug!(%id, keys = expired.len(), "held back message expired");
+            locked.settle(id, &expired, cm::DeliveryState::DeliveryExpired);
+        }
+
+        debug!(%id, keys = released.len(), "message released");
+        released
+    }
+
+    /// Mark a message as delivered to the given keys, removing it from their pending messages.
+    pub async fn delivered(&self, id: &str, keys: &[TokenKey]) {
+        let mut locked = self.inner.lock().await;
+        locked.settle(id, keys, cm::DeliveryState::DeliveryDelivered);
+
+        info!(%id, keys = keys.len(), "message delivered");
+    }
+
+    /// Mark a message as expired for the given keys, removing it from their pending messages.
+    pub async fn expired(&self, id: &str, keys: &[TokenKey]) {
+        let mut locked = self.inner.lock().await;
+        locked.settle(id, keys, cm::DeliveryState::DeliveryExpired);
+
+        info!(%id, keys = keys.len(), "message expired");
+    }
+
+    /// Collect the pending messages to the keys accepted by the filter, oldest first.
+    ///
+    /// Every message is listed once along with the trace it was sent in and the accepted keys it
+    /// is pending for.
+    pub async fn pending<F>(
+        &self,
+        filter: F,
+    ) -> Vec<(Arc<cm::Message>, Arc<TraceContext>, Vec<TokenKey>)>
+    where
+        F: Fn(&TokenKey) -> bool,
+    {
+        let now = chrono::Utc::now().naive_utc();
+        let mut locked = self.inner.lock().await;
+        locked.sweep(now);
+
+        let mut pending: HashMap<Arc<str>, (u64, &Pending, Vec<TokenKey>)> = HashMap::new();
+
+        for (key, queue) in locked.pending.iter().filter(|(key, _)| filter(key)) {
+            for entry in queue.iter().filter(|entry| {
+                entry.expire_at.is_none_or(|expire_at| expire_at > now)
+                    && entry.held_until.is_none_or(|held_until| held_until <= now)
+            }) {
+                pending
+                    .entry(entry.id.clone())
+                    .or_insert_with(|| (entry.sequence, entry, Vec::new()))
+                    .2
+                    .push(key.clone());
+            }
+        }
+
+        let mut pending: Vec<_> = pending.into_values().collect();
+        pending.sort_by_key(|(sequence, _, _)| *sequence);
+
+        pending
+            .into_iter()
+            .map(|(_, entry, keys)| (entry.message.clone(), entry.trace_context.clone(), keys))
+            .collect()
+    }
+
+    /// Look up the delivery status of a message sent in the tenant.
+    pub async fn status(&self, tenant: &str, id: &str) -> Option<cm::DeliveryStatus> {
+        let locked = self.inner.lock().await;
+
+        let status = locked
+            .statuses
+            .get(id)
+            .filter(|status| &*status.tenant == tenant);
+
+        status.map(|status| cm::DeliveryStatus {
+            id: id.to_string(),
+            deliveries: status
+                .deliveries
+                .iter()
+                .map(|(key, delivery)| cm::Delivery {
+                    key: Some(key.into()),
+                    state: delivery.state as i32,
+                    collapsed_by: delivery
+                        .collapsed_by
+                        .as_ref()
+                        .map(|id| id.to_string())
+                        .unwrap_or_default(),
+                    not_before: delivery.not_before.map(timestamp),
+                })
+                .collect(),
+            collapsed: status.collapsed.iter().map(|id| id.to_string()).collect(),
+        })
+    }
+}
+
+impl OutboxInner {
+    fn count(&self, platform: cm::Platform, outcome: MessageOutcome) {
+        if let Some(metrics) = &self.metrics {
+            metrics.message(platform, outcome);
+        }
+    }
+
+    /// Settle the delivery of a pending message to the given keys in the given state.
+    fn settle(&mut self, id: &str, keys: &[TokenKey], state: cm::DeliveryState) {
+        for key in keys.iter() {
+            if let Some(queue) = self.pending.get_mut(key) {
+                let before = queue.len();
+                queue.retain(|pending| &*pending.id != id);
+                self.queued -= before - queue.len();
+                if queue.is_empty() {
+                    self.pending.remove(key);
+                }
+            }
+
+            let delivery = self
+                .statuses
+                .get_mut(id)
+                .and_then(|status| status.deliveries.get_mut(key))
+                .filter(|delivery| !delivery.settled());
+            if let Some(delivery) = delivery {
+                delivery.state = state;
+                delivery.not_before = None;
+
+                let platform = delivery.platform;
+                match state {
+                    cm::DeliveryState::DeliveryDelivered => {
+                        self.count(platform, MessageOutcome::Delivered)
+                    }
+                    cm::DeliveryState::DeliveryExpired => {
+                        self.count(platform, MessageOutcome::Expired)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Expire the oldest pending messages once past the bound over every token, along with a
+    /// sixteenth of the bound, so that the pending messages are not scanned for every message.
+    fn evict(&mut self, max: usize) {
+        if self.queued <= max {
+            return;
+        }
+        let excess = self.queued - max + max / 16;
+
+        let mut oldest: Vec<(u64, Arc<str>, TokenKey)> = self
+            .pending
+            .iter()
+            .flat_map(|(key, queue)| {
+                queue
+                    .iter()
+                    .map(|pending| (pending.sequence, pending.id.clone(), key.clone()))
+            })
+            .collect();
+        oldest.sort_unstable_by_key(|(sequence, _, _)| *sequence);
+        oldest.truncate(excess);
+
+        let mut evicted: HashMap<Arc<str>, Vec<TokenKey>> = HashMap::new();
+        for (_, id, key) in oldest {
+            evicted.entry(id).or_default().push(key);
+        }
+
+        info!(messages = evicted.len(), "oldest pending messages evicted");
+        for (id, keys) in evicted.iter() {
+            self.settle(id, keys, cm::DeliveryState::DeliveryExpired);
+        }
+    }
+
+    /// Drop the pending messages which expired, at most once per sweep interval.
+    fn sweep(&mut self, now: NaiveDateTime) {
+        if self
+            .swept
+            .is_some_and(|swept| now - swept < chrono::Duration::seconds(SWEEP_INTERVAL_SECONDS))
+        {
+            return;
+        }
+        self.swept = Some(now);
+
+        let mut expired: HashMap<Arc<str>, Vec<TokenKey>> = HashMap::new();
+        for (key, queue) in self.pending.iter() {
+            for pending in queue.iter() {
+                if pending.expire_at.is_some_and(|expire_at| expire_at <= now) {
+                    expired
+                        .entry(pending.id.clone())
+                        .or_default()
+                        .push(key.clone());
+                }
+            }
+        }
+
+        for (id, keys) in expired.iter() {
+            debug!(%id, keys = keys.len(), "pending message expired");
+            self.settle(id, keys, cm::DeliveryState::DeliveryExpired);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noon() -> NaiveDateTime {
+        chrono::NaiveDate::from_ymd(2026, 10, 18).and_hms(12, 0, 0)
+    }
+
+    #[test]
+    fn remaining_ttl_is_rewritten_into_the_payload() {
+        let mut message = cm::Message {
+            payload: Some(cm::Payload {
+                data: [("k".to_string(), "v".to_string())].into(),
+                ttl: Some(Duration {
+                    seconds: 60,
+                    nanos: 0,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        message_expire_at(&mut message, noon()).unwrap();
+
+        message_remaining_ttl(&mut message, noon() + chrono::Duration::seconds(45));
+        let ttl = message.payload.unwrap().ttl.unwrap();
+        assert_eq!((ttl.seconds, ttl.nanos), (15, 0));
+    }
+
+    #[test]
+    fn content_only_message_is_left_without_a_payload() {
+        let mut message = cm::Message {
+            content: [("k".to_string(), "v".to_string())].into(),
+            expire_at: Some(timestamp(noon() + chrono::Duration::seconds(60))),
+            ..Default::default()
+        };
+        message_expire_at(&mut message, noon()).unwrap();
+
+        message_remaining_ttl(&mut message, noon() + chrono::Duration::seconds(45));
+        assert!(message.payload.is_none());
+        assert_eq!(message.content.len(), 1);
+        assert_eq!(
+            message_expiry(&message),
+            Some(noon() + chrono::Duration::seconds(60))
+        );
+    }
+
+    fn message(id: &str, keys: &[&str], collapse_key: &str) -> Arc<cm::Message> {
+        let keys: Vec<TokenKey> = keys.iter().map(|key| TokenKey::new(key)).collect();
+        Arc::new(cm::Message {
+            id: id.to_string(),
+            codomain: Some(keys.as_slice().into()),
+            payload: Some(cm::Payload {
+                data: [("k".to_string(), "v".to_string())].into(),
+                collapse_key: collapse_key.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    async fn enqueue(outbox: &Outbox, message: Arc<cm::Message>) -> Vec<Arc<str>> {
+        outbox
+            .enqueue(
+                message,
+                TraceContext::default(),
+                &HashMap::new(),
+                &HashMap::new(),
+            )
+            .await
+    }
+
+    /// The state of the delivery of a message to a key, along with the message collapsing it.
+    async fn delivery(outbox: &Outbox, id: &str, key: &str) -> (cm::DeliveryState, String) {
+        let status = outbox.status("", id).await.unwrap();
+        let delivery = status
+            .deliveries
+            .into_iter()
+            .find(|delivery| delivery.key.as_ref().unwrap().key == key)
+            .unwrap();
+        (delivery.state(), delivery.collapsed_by)
+    }
+
+    async fn pending_ids(outbox: &Outbox) -> Vec<String> {
+        outbox
+            .pending(|_| true)
+            .await
+            .into_iter()
+            .map(|(message, _, _)| message.id.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn messages_sharing_a_collapse_key_are_coalesced_per_token() {
+        let outbox = Outbox::new();
+
+        assert!(
+            enqueue(&outbox, message("m1", &["dev-1", "dev-2"], "score"))
+                .await
+                .is_empty()
+        );
+        assert!(enqueue(&outbox, message("m2", &["dev-3"], "news"))
+            .await
+            .is_empty());
+        let collapsed = enqueue(&outbox, message("m3", &["dev-1", "dev-3"], "score")).await;
+        assert_eq!(collapsed, vec![Arc::from("m1")]);
+
+        assert_eq!(
+            delivery(&outbox, "m1", "dev-1").await,
+            (cm::DeliveryState::DeliveryCollapsed, "m3".to_string())
+        );
+        assert_eq!(
+            delivery(&outbox, "m1", "dev-2").await,
+            (cm::DeliveryState::DeliveryPending, String::new())
+        );
+        assert_eq!(
+            delivery(&outbox, "m2", "dev-3").await,
+            (cm::DeliveryState::DeliveryPending, String::new())
+        );
+        let status = outbox.status("", "m3").await.unwrap();
+        assert_eq!(status.collapsed, ["m1"]);
+
+        // The collapsed message is still pending for the token it was not collapsed for.
+        assert_eq!(pending_ids(&outbox).await, ["m1", "m2", "m3"]);
+        let pending = outbox.pending(|key| &*key.key == "dev-1").await;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].0.id, "m3");
+        assert_eq!(
+            outbox
+                .deliverable("m1", vec![TokenKey::new("dev-1"), TokenKey::new("dev-2")])
+                .await,
+            [TokenKey::new("dev-2")]
+        );
+    }
+
+    #[tokio::test]
+    async fn oldest_messages_to_a_token_expire_past_its_bound() {
+        let outbox = Outbox::new();
+
+        for index in 0..=PENDING_PER_KEY_MAX {
+            let id = format!("m{}", index);
+            enqueue(&outbox, message(&id, &["dev-1", &id], "")).await;
+        }
+
+        assert_eq!(
+            delivery(&outbox, "m0", "dev-1").await.0,
+            cm::DeliveryState::DeliveryExpired
+        );
+        // The bound is per token, so the message is still pending for its other token.
+        assert_eq!(
+            delivery(&outbox, "m0", "m0").await.0,
+            cm::DeliveryState::DeliveryPending
+        );
+        assert_eq!(
+            delivery(&outbox, "m1", "dev-1").await.0,
+            cm::DeliveryState::DeliveryPending
+        );
+
+        let pending = outbox.pending(|key| &*key.key == "dev-1").await;
+        assert_eq!(pending.len(), PENDING_PER_KEY_MAX);
+        assert_eq!(pending[0].0.id, "m1");
+    }
+
+    #[tokio::test]
+    async fn oldest_messages_expire_past_the_bound_over_every_token() {
+        let outbox = Outbox::new();
+        for index in 0..40 {
+            let id = format!("m{}", index);
+            enqueue(&outbox, message(&id, &[&id], "")).await;
+        }
+
+        // Past the bound of 32 by 8, along with a sixteenth of it.
+        let mut locked = outbox.inner.lock().await;
+        locked.evict(32);
+        assert_eq!(locked.queued, 30);
+        drop(locked);
+
+        for index in 0..10 {
+            let id = format!("m{}", index);
+            assert_eq!(
+                delivery(&outbox, &id, &id).await.0,
+                cm::DeliveryState::DeliveryExpired
+            );
+        }
+        assert_eq!(
+            delivery(&outbox, "m10", "m10").await.0,
+            cm::DeliveryState::DeliveryPending
+        );
+        assert_eq!(pending_ids(&outbox).await.len(), 30);
+    }
+
+    #[tokio::test]
+    async fn expired_messages_are_swept() {
+        let outbox = Outbox::new();
+        let now = chrono::Utc::now().naive_utc();
+
+        let mut expiring = (*message("m1", &["dev-1"], "")).clone();
+        expiring.expire_at = Some(timestamp(now + chrono::Duration::seconds(10)));
+        enqueue(&outbox, Arc::new(expiring)).await;
+        enqueue(&outbox, message("m2", &["dev-1"], "")).await;
+
+        // Swept at most once per interval.
+        let later = now + chrono::Duration::seconds(20);
+        let mut locked = outbox.inner.lock().await;
+        locked.swept = Some(later - chrono::Duration::milliseconds(500));
+        locked.sweep(later);
+        assert_eq!(locked.queued, 2);
+        locked.swept = None;
+        locked.sweep(later);
+        assert_eq!(locked.queued, 1);
+        drop(locked);
+
+        assert_eq!(
+            delivery(&outbox, "m1", "dev-1").await.0,
+            cm::DeliveryState::DeliveryExpired
+        );
+        assert_eq!(pending_ids(&outbox).await, ["m2"]);
+    }
+}
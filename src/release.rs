@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::cm::{self, message_broadcast::Operation};
+use crate::dispatch::{Dispatcher, Lane};
+use crate::model::TokenKey;
+use crate::outbox::Outbox;
+use crate::telemetry::TraceContext;
+
+/// A message held back from tokens, along with the outbox it is pending in.
+#[derive(Debug)]
+struct Held {
+    outbox: Arc<Outbox>,
+    message: cm::Message,
+    trace_context: TraceContext,
+    keys: Vec<TokenKey>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Hold(Instant, Box<Held>),
+    Stop(oneshot::Sender<()>),
+}
+
+/// Releases the messages held back from tokens by their throttling as each of them is due.
+///
+/// A single task keeps every held message, so they are released in order of when they are due.
+/// Once stopped on shutdown, none is released anymore: a burst of them would flood the devices
+/// their throttling protects, so they are left pending in the outbox instead.
+#[derive(Debug, Clone)]
+pub struct Releaser {
+    tx: mpsc::UnboundedSender<Command>,
+}
+
+impl Releaser {
+    /// Spawn the releasing task. Must be called within a tokio runtime.
+    pub fn spawn(dispatcher: Dispatcher) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(release(rx, dispatcher));
+
+        Self { tx }
+    }
+
+    /// Hold a message back from the keys until due, then broadcast it to those it is still
+    /// pending for in the outbox.
+    pub fn hold(
+        &self,
+        due: Instant,
+        outbox: Arc<Outbox>,
+        message: cm::Message,
+        trace_context: TraceContext,
+        keys: Vec<TokenKey>,
+    ) {
+        let held = Box::new(Held {
+            outbox,
+            message,
+            trace_context,
+            keys,
+        });
+        if self.tx.send(Command::Hold(due, held)).is_err() {
+            warn!("releaser closed");
+        }
+    }
+
+    /// Stop releasing the held messages, and any held from now on, leaving them pending in the
+    /// outbox. Waits for a release in progress to be queued in the dispatcher.
+    pub async fn stop(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.tx.send(Command::Stop(tx)).is_ok() {
+            let _ = rx.await;
+        }
+    }
+}
+
+async fn release(mut rx: mpsc::UnboundedReceiver<Command>, dispatcher: Dispatcher) {
+    // Held messages by when they are due, then in the order they were held.
+    let mut held: BTreeMap<(Instant, u64), Box<Held>> = BTreeMap::new();
+    let mut sequence: u64 = 0;
+    let mut stopped = false;
+
+    loop {
+        let next = held.keys().next().map(|(due, _)| *due);
+
+        tokio::select! {
+            command = rx.recv() => match command {
+                Some(Command::Hold(_, message)) if stopped => {
+                    debug!(id = %message.message.id, "held message left pending");
+                }
+                Some(Command::Hold(due, message)) => {
+                    sequence += 1;
+                    held.insert((due, sequence), message);
+                }
+                Some(Command::Stop(done)) => {
+                    stopped = true;
+                    let count = held.len();
+                    held.clear();
+                    info!(count, "held messages left pending");
+                    let _ = done.send(());
+                }
+                None => break,
+            },
+            _ = tokio::time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                if let Some((_, message)) = held.pop_first() {
+                    release_one(&dispatcher, message).await;
+                }
+            }
+        }
+    }
+}
+
+/// Broadcast a held message to the keys it is still pending for, but not to those it stopped
+/// being pending for meanwhile, such as by being collapsed or expiring.
+async fn release_one(dispatcher: &Dispatcher, held: Box<Held>) {
+    let released = held.outbox.release(&held.message.id, &held.keys).await;
+    if released.is_empty() {
+        return;
+    }
+
+    let lane = Lane::of(&held.message);
+    let id = held.message.id.clone();
+    let mut broadcast = held.message;
+    broadcast.codomain = Some(released.as_slice().into());
+    let bcast = cm::MessageBroadcast {
+        operation: Some(Operation::Send(broadcast)),
+        trace_context: held.trace_context,
+    };
+
+    // The message remains pending for the tokens if the dispatcher closed.
+    if dispatcher.dispatch(lane, bcast).await.is_err() {
+        warn!(%id, "failed to release a held back message");
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{async_trait, Request, Response, Status, Streaming};
+use tracing::info;
+
+use crate::access::{AccessPolicy, Permission};
+use crate::database::{ConflictPolicy, TokenDb, TokenDbInMemory};
+use crate::limit::RateLimiter;
+use crate::model;
+use crate::tenant;
+use crate::transfer::{self, Decoder};
+
+use super::cm::{
+    cm_admin_server::CmAdmin, ImportConflict, RateUsageRequest, RateUsageResponse,
+    TokenExportRequest, TokenImportRequest, TokenImportSummary, TokenTransferChunk,
+};
+use super::cm_token::token_validate_restored;
+
+/// Number of tokens encoded into a single chunk of an export.
+const EXPORT_CHUNK_TOKENS: usize = 256;
+/// Number of chunks buffered while waiting for room in the export stream.
+const EXPORT_BUFFER: usize = 4;
+
+/// Administrative operations on the token database, such as backing it up and restoring it.
+///
+/// Operations are scoped to the tenant of the caller: only its tokens are exported, only tokens of
+/// it are imported, and only the usage of its rate limits is reported. Tokens restored by an
+/// import are not broadcast to the token subscribers.
+#[derive(Debug)]
+pub struct CmAdminService<Db: TokenDb> {
+    db: Arc<Db>,
+    access: Arc<AccessPolicy>,
+    limiter: Option<RateLimiter>,
+}
+
+impl<Db: TokenDb> CmAdminService<Db> {
+    pub fn new_with_db(db: Arc<Db>) -> Self {
+        Self {
+            db,
+            access: Arc::new(AccessPolicy::open()),
+            limiter: None,
+        }
+    }
+
+    /// Authorize every request by the access policy, rather than permitting everything.
+    pub fn with_access(self, access: Arc<AccessPolicy>) -> Self {
+        Self { access, ..self }
+    }
+
+    /// Report the usage of the rate limits of the limiter, rather than of none.
+    pub fn with_limiter(self, limiter: Option<RateLimiter>) -> Self {
+        Self { limiter, ..self }
+    }
+}
+
+impl Default for CmAdminService<TokenDbInMemory> {
+    fn default() -> Self {
+        CmAdminService::new_with_db(Arc::new(TokenDbInMemory::default()))
+    }
+}
+
+impl From<ImportConflict> for ConflictPolicy {
+    fn from(source: ImportConflict) -> Self {
+        match source {
+            ImportConflict::ImportSkip => ConflictPolicy::Skip,
+            ImportConflict::ImportOverwrite => ConflictPolicy::Overwrite,
+            ImportConflict::ImportNewer => ConflictPolicy::Newer,
+        }
+    }
+}
+
+/// The tenant an administrator of which calls, which the operation is scoped to.
+fn admin_tenant<T>(access: &AccessPolicy, request: &Request<T>) -> Result<Arc<str>, Status> {
+    access.authorize(request, Permission::Admin)?;
+    tenant::of(request)
+}
+
+#[async_trait]
+impl<Db: TokenDb> CmAdmin for CmAdminService<Db> {
+    type TokenExportStream = ReceiverStream<Result<TokenTransferChunk, Status>>;
+
+    /// Stream every token of the tenant out in the requested format.
+    async fn token_export(
+        &self,
+        request: Request<TokenExportRequest>,
+    ) -> Result<Response<Self::TokenExportStream>, Status> {
+        let tenant = match admin_tenant(&self.access, &request) {
+            Ok(tenant) => tenant,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
+        let format = request.into_inner().format();
+
+        let tokens = match self.db.select_all().await {
+            Ok(tokens) => tokens
+                .into_iter()
+                .filter(|token| token.key.tenant == tenant)
+                .collect::<Vec<_>>(),
+            Err(error) => {
+                let status = Status::from(error);
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
+        info!(
+            "\nrpc::TokenExport :: ({:?}, {} tokens)",
+            format,
+            tokens.len()
+        );
+
+        let (tx, rx) = mpsc::channel(EXPORT_BUFFER);
+
+        tokio::spawn(async move {
+            for chunk in tokens.chunks(EXPORT_CHUNK_TOKENS) {
+                let mut data = Vec::new();
+                for token in chunk {
+                    transfer::encode(format, token, &mut data);
+                }
+
+                if tx.send(Ok(TokenTransferChunk { data })).await.is_err() {
+                    info!("channel closed");
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    /// Restore the tokens streamed in, resolving conflicts with the registered tokens by policy.
+    ///
+    /// The import stops at the first malformed or invalid token, or token of another tenant than
+    /// the caller, keeping the tokens restored before it.
+    async fn token_import(
+        &self,
+        request: Request<Streaming<TokenImportRequest>>,
+    ) -> Result<Response<TokenImportSummary>, Status> {
+        let tenant = match admin_tenant(&self.access, &request) {
+            Ok(tenant) => tenant,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
+        let mut stream = request.into_inner();
+        let mut summary = TokenImportSummary::default();
+
+        // The format and conflict policy of the whole import are taken from the first request.
+        let first = match stream.message().await? {
+            Some(first) => first,
+            None => return Ok(Response::new(summary)),
+        };
+        let policy = ConflictPolicy::from(first.conflict());
+        let mut decoder = Decoder::new(first.format());
+        decoder.push(&first.data);
+        let mut finished = false;
+
+        loop {
+            while let Some(token) = decoder.next_token() {
+                let token = match token {
+                    Ok(token) => token,
+                    Err(error) => {
+                        let status = Status::invalid_argument(error.to_string());
+                        info!(status = ?&status, ?summary, "request failed");
+                        return Err(status);
+                    }
+                };
+
+                if token.key.tenant != tenant {
+                    let status = Status::permission_denied(format!(
+                        "token {} is of tenant `{}`, not `{}`",
+                        summary.received, token.key.tenant, tenant
+                    ));
+                    info!(status = ?&status, ?summary, "request failed");
+                    return Err(status);
+                }
+
+                // Checked as the registration of the token would be.
+                if let Err(status) = token_validate_restored(&token, summary.received) {
+                    info!(status = ?&status, ?summary, "request failed");
+                    return Err(status);
+                }
+
+                summary.received += 1;
+                match self.db.restore(token, policy).await {
+                    Ok(Some(model::TokenInsert::Created(_))) => summary.created += 1,
+                    Ok(Some(model::TokenInsert::Refreshed(_))) => summary.overwritten += 1,
+                    Ok(None) => summary.skipped += 1,
+                    Err(error) => {
+                        let status = Status::from(error);
+                        info!(status = ?&status, ?summary, "request failed");
+                        return Err(status);
+                    }
+                }
+            }
+
+            if finished {
+                break;
+            }
+
+            match stream.message().await? {
+                Some(request) => decoder.push(&request.data),
+                None => {
+                    // Decode what remains as the last token.
+                    decoder.finish();
+                    finished = true;
+                }
+            }
+        }
+
+        info!("\nrpc::TokenImport :: {:?}", &summary);
+
+        Ok(Response::new(summary))
+    }
+
+    /// Report the current usage of the rate limits, of every client of the tenant or of the one
+    /// asked for.
+    async fn rate_usage(
+        &self,
+        request: Request<RateUsageRequest>,
+    ) -> Result<Response<RateUsageResponse>, Status> {
+        let tenant = match admin_tenant(&self.access, &request) {
+            Ok(tenant) => tenant,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
+        let identity = Some(request.into_inner().identity).filter(|identity| !identity.is_empty());
+
+        let usages = match &self.limiter {
+            Some(limiter) => limiter.usage(&tenant, identity.as_deref()),
+            None => Vec::new(),
+        };
+
+        Ok(Response::new(RateUsageResponse { usages }))
+    }
+}
@@ -1,6 +1,11 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
 use super::cm;
 use super::cm::MessageBroadcast;
 use super::cm::MessageSubscribeRequest;
+use prost::Message as _;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
@@ -8,40 +13,529 @@ use tonic::async_trait;
 use tonic::Request;
 use tonic::Response;
 use tonic::Status;
-use tracing::debug;
-use tracing::info;
+use tonic::Streaming;
+use tracing::{debug, info, warn, Instrument, Span};
+
+use crate::access::{AccessPolicy, Permission};
+use crate::database::TokenDb;
+use crate::database::TokenDbInMemory;
+use crate::dispatch::{Dispatcher, Lane, Lanes};
+use crate::lifecycle::{Lifecycle, Subscriber};
+use crate::limit::Charge;
+use crate::metrics::Metrics;
+use crate::model;
+use crate::outbox::{self, Outbox};
+use crate::release::Releaser;
+use crate::telemetry::{self, TraceContext};
+use crate::throttle::{Throttle, Throttled};
+use crate::topic::Condition;
 
 use super::cm::cm_message_server::CmMessage;
 use super::cm::message_broadcast::Operation;
+use super::cm::message_send_result;
+use super::cm::DeliveryStatus;
+use super::cm::Failure;
 use super::cm::HealthCheckRequest;
 use super::cm::HealthCheckResponse;
+use super::cm::Message;
+use super::cm::MessageSendBatchRequest;
+use super::cm::MessageSendBatchResponse;
 use super::cm::MessageSendRequest;
 use super::cm::MessageSendResponse;
+use super::cm::MessageSendResult;
+use super::cm::MessageSendSummary;
+use super::cm::MessageStatusRequest;
+use super::cm::Payload;
 use super::cm::TokenKey;
+use super::health;
+
+/// Name of the service its metrics are labelled with.
+const SERVICE: &str = "cm.cm_message";
+/// Capacity of each priority lane in front of the broadcast channel.
+const DISPATCH_CAPACITY: usize = 16;
+/// Maximum number of messages in a single batch.
+const MESSAGE_BATCH_MAX: usize = 500;
+/// Maximum number of failures recorded in the summary of a stream, past which they are only
+/// counted.
+const SUMMARY_FAILURES_MAX: usize = 100;
+/// Number of messages buffered per subscriber while waiting for room in its stream.
+const SUBSCRIBER_BUFFER: usize = 64;
+/// Capacity of the stream of each subscriber.
+const STREAM_CAPACITY: usize = 4;
 
 #[derive(Debug)]
-pub struct CmMessageService {
+pub struct CmMessageService<Db: TokenDb> {
     subscribe_tx: broadcast::Sender<MessageBroadcast>,
-    subscribe_rx: broadcast::Receiver<MessageBroadcast>,
+    // Held so that broadcasting never fails for the lack of subscribers.
+    _subscribe_rx: broadcast::Receiver<MessageBroadcast>,
+    dispatcher: Dispatcher,
+    releaser: Releaser,
+    db: Arc<Db>,
+    outbox: Arc<Outbox>,
+    stream_capacity: usize,
+    lifecycle: Lifecycle,
+    access: Arc<AccessPolicy>,
+    throttle: Option<Arc<Throttle>>,
+    metrics: Option<Metrics>,
 }
 
-impl CmMessageService {
+impl<Db: TokenDb> CmMessageService<Db> {
+    /// Construct the service, spawning its dispatcher. Must be called within a tokio runtime.
     pub fn new(
         ch: (
             broadcast::Sender<MessageBroadcast>,
             broadcast::Receiver<MessageBroadcast>,
         ),
+        db: Arc<Db>,
+    ) -> Self {
+        CmMessageService::new_with_dispatch_capacity(ch, db, DISPATCH_CAPACITY)
+    }
+
+    /// Construct the service with priority lanes of the given capacity, spawning its dispatcher
+    /// and the releaser of the messages held back by throttling. Must be called within a tokio runtime.
+    pub fn new_with_dispatch_capacity(
+        ch: (
+            broadcast::Sender<MessageBroadcast>,
+            broadcast::Receiver<MessageBroadcast>,
+        ),
+        db: Arc<Db>,
+        dispatch_capacity: usize,
     ) -> Self {
+        let dispatcher = Dispatcher::spawn(dispatch_capacity, ch.0.clone());
         Self {
+            releaser: Releaser::spawn(dispatcher.clone()),
+            dispatcher,
             subscribe_tx: ch.0,
-            subscribe_rx: ch.1,
+            _subscribe_rx: ch.1,
+            db,
+            outbox: Arc::new(Outbox::new()),
+            stream_capacity: STREAM_CAPACITY,
+            lifecycle: Lifecycle::new(),
+            access: Arc::new(AccessPolicy::open()),
+            throttle: None,
+            metrics: None,
+        }
+    }
+
+    pub fn new_with_db(db: Arc<Db>) -> Self {
+        CmMessageService::new(broadcast::channel(16), db)
+    }
+
+    pub fn with_stream_capacity(self, stream_capacity: usize) -> Self {
+        Self {
+            stream_capacity,
+            ..self
+        }
+    }
+
+    /// The dispatcher of the service, to wait for its queued messages to be flushed.
+    pub fn dispatcher(&self) -> Dispatcher {
+        self.dispatcher.clone()
+    }
+
+    /// The releaser of the messages held back by throttling, to stop it on shutdown.
+    pub fn releaser(&self) -> Releaser {
+        self.releaser.clone()
+    }
+
+    /// Share the lifecycle of the server, so the service is shut down along with it.
+    pub fn with_lifecycle(self, lifecycle: Lifecycle) -> Self {
+        Self { lifecycle, ..self }
+    }
+
+    /// Authorize every request by the access policy, rather than permitting everything.
+    pub fn with_access(self, access: Arc<AccessPolicy>) -> Self {
+        Self { access, ..self }
+    }
+
+    /// Count the messages and subscribers of the service into the metrics, if given.
+    pub fn with_metrics(self, metrics: Option<Metrics>) -> Self {
+        Self {
+            outbox: Arc::new(Outbox::new().with_metrics(metrics.clone())),
+            metrics,
+            ..self
+        }
+    }
+
+    /// Cap the messages each token receives by the throttle, if given.
+    pub fn with_throttle(self, throttle: Option<Throttle>) -> Self {
+        Self {
+            throttle: throttle.map(Arc::new),
+            ..self
+        }
+    }
+
+    /// Resolve the topic, topic condition and owner of a message to the keys of the targeted tokens.
+    ///
+    /// The resolved keys are merged into the codomain of the message, while the topic and
+    /// condition themselves are retained for providers capable of native topic messaging.
+    async fn message_resolve(&self, message: &mut Message) -> Result<(), Status> {
+        let condition = match (message.topic.is_empty(), message.condition.is_empty()) {
+            (true, true) => None,
+            (false, true) => Some(Condition::topic(&message.topic)),
+            (true, false) => Some(Condition::parse(&message.condition)),
+            (false, false) => {
+                return Err(Status::invalid_argument(
+                    "topic and condition are mutually exclusive",
+                ))
+            }
+        }
+        .transpose()
+        .map_err(|error| Status::invalid_argument(format!("invalid topic condition: {}", error)))?;
+
+        let mut keys = Vec::new();
+
+        if let Some(condition) = condition {
+            keys.extend(
+                self.db
+                    .select_topics(&message.tenant, &condition)
+                    .await
+                    .map_err(Status::from)?,
+            );
+        }
+
+        if !message.owner.is_empty() {
+            keys.extend(
+                self.db
+                    .select_owner(&message.tenant, &message.owner)
+                    .await
+                    .map_err(Status::from)?,
+            );
+        }
+
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let codomain = message.codomain.get_or_insert_with(Default::default);
+        for key in keys.iter().map(TokenKey::from) {
+            if !codomain.keys.contains(&key) {
+                codomain.keys.push(key);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Look up the platforms of the registered tokens among the keys, as the providers the
+    /// messages to them are counted for.
+    async fn message_platforms(
+        &self,
+        keys: &[model::TokenKey],
+    ) -> HashMap<model::TokenKey, cm::Platform> {
+        let mut platforms = HashMap::new();
+        for key in keys.iter() {
+            if let Ok(Some(token)) = self.db.get(key.clone()).await {
+                platforms.insert(key.clone(), token.platform);
+            }
+        }
+        platforms
+    }
+
+    /// Validate, resolve and dispatch a single message within a tenant, as shared by every send
+    /// RPC.
+    async fn message_submit(
+        &self,
+        tenant: &str,
+        mut message: Message,
+    ) -> Result<MessageSendResponse, Status> {
+        // Refuse new messages while the queued ones are flushed for shutting down.
+        if let Err(status) = self.lifecycle.admit() {
+            info!(status = ?&status, "request failed");
+            return Err(status);
+        }
+
+        // Assert that the payload of the message is deliverable.
+        if let Err(status) = message_validate(&message) {
+            info!(status = ?&status, "request failed");
+            return Err(status);
+        }
+
+        // Settle when the message stops being relevant, and refuse it if it already has.
+        let now = chrono::Utc::now().naive_utc();
+        if let Err(error) = outbox::message_expire_at(&mut message, now) {
+            let status = Status::invalid_argument(error);
+            info!(status = ?&status, "request failed");
+            return Err(status);
+        }
+        if outbox::message_expired(&message, now) {
+            let status = Status::invalid_argument("message already expired");
+            info!(status = ?&status, "request failed");
+            return Err(status);
+        }
+
+        // Confine the message and the keys it targets to the tenant of the sender.
+        message.tenant = tenant.to_string();
+        for key in message
+            .codomain
+            .iter_mut()
+            .flat_map(|codomain| codomain.keys.iter_mut())
+        {
+            key.tenant = tenant.to_string();
+        }
+
+        // Expand a topic targeted message to the keys subscribed to it.
+        if let Err(status) = self.message_resolve(&mut message).await {
+            info!(status = ?&status, "request failed");
+            return Err(status);
+        }
+
+        // Identify the message and count it against the tokens it targets, which it is dropped
+        // for or held back from once they received as many messages as they may.
+        message.id = uuid::Uuid::new_v4().to_string();
+        let codomain: Vec<model::TokenKey> = message
+            .codomain
+            .iter()
+            .flat_map(|codomain| codomain.keys.iter().cloned())
+            .map(model::TokenKey::from)
+            .collect();
+        let throttled = match &self.throttle {
+            Some(throttle) => throttle.admit(&message.id, &codomain).await,
+            None => HashMap::new(),
+        };
+
+        // Queue it as pending delivery, coalescing it with the pending messages sharing its
+        // collapse key.
+        let platforms = match &self.metrics {
+            Some(_) => self.message_platforms(&codomain).await,
+            None => HashMap::new(),
+        };
+        let trace_context = telemetry::context_of(&Span::current());
+        self.outbox
+            .enqueue(
+                Arc::new(message.clone()),
+                trace_context.clone(),
+                &throttled,
+                &platforms,
+            )
+            .await;
+
+        // Broadcast it to the tokens it is let through to, unless it is throttled for them all.
+        let admitted: Vec<model::TokenKey> = codomain
+            .iter()
+            .filter(|key| !throttled.contains_key(key))
+            .cloned()
+            .collect();
+        if codomain.is_empty() || !admitted.is_empty() {
+            let mut broadcast = message.clone();
+            if !throttled.is_empty() {
+                broadcast.codomain = Some(admitted.as_slice().into());
+            }
+
+            // Message is present. Now construct a broadcastable object and send it to the subscribers.
+            let bcast = MessageBroadcast {
+                operation: Some(Operation::Send(broadcast)),
+                trace_context: trace_context.clone(),
+            };
+
+            // Send through the priority lane of the message to the broadcast channel.
+            match self.dispatcher.dispatch(Lane::of(&message), bcast).await {
+                Ok(_) => {}
+                Err(_) => {
+                    let status = Status::internal("channel broken");
+                    info!(status = ?&status, "request failed");
+                    return Err(status);
+                }
+            };
+        }
+
+        let mut held: Vec<(Duration, model::TokenKey)> = throttled
+            .into_iter()
+            .filter_map(|(key, throttled)| match throttled {
+                Throttled::Held { delay, .. } => Some((delay, key)),
+                Throttled::Dropped => None,
+            })
+            .collect();
+        held.sort_by_key(|(delay, _)| *delay);
+        let start = tokio::time::Instant::now();
+        let mut held = held.into_iter().peekable();
+        while let Some((delay, key)) = held.next() {
+            let mut keys = vec![key];
+            while let Some((_, key)) = held.next_if(|(next, _)| *next == delay) {
+                keys.push(key);
+            }
+            self.releaser.hold(
+                start + delay,
+                self.outbox.clone(),
+                message.clone(),
+                trace_context.clone(),
+                keys,
+            );
+        }
+
+        let status = self.outbox.status(tenant, &message.id).await;
+        Ok(MessageSendResponse {
+            sent: Some(message),
+            status,
+        })
+    }
+}
+
+/// Count a message of a batch or stream against the rate limits, but for the first which was
+/// counted along with the call.
+fn message_charge(charge: Option<&Charge>, index: u64) -> Result<(), Status> {
+    match charge {
+        Some(charge) if index > 0 => charge.admit(),
+        _ => Ok(()),
+    }
+}
+
+/// Maximum encoded size of a payload, as imposed by FCM and APNs.
+const PAYLOAD_MAX_SIZE: usize = 4096;
+/// Maximum length of a collapse key, as imposed by APNs.
+const PAYLOAD_MAX_COLLAPSE_KEY: usize = 64;
+/// Data keys reserved by FCM.
+const PAYLOAD_RESERVED_KEYS: &[&str] = &["from", "notification", "message_type"];
+/// Data key prefixes reserved by FCM.
+const PAYLOAD_RESERVED_PREFIXES: &[&str] = &["google.", "gcm."];
+
+fn is_https_url(url: &str) -> bool {
+    url.strip_prefix("https://")
+        .is_some_and(|rest| !rest.is_empty())
+}
+
+fn is_hex_color(color: &str) -> bool {
+    color
+        .strip_prefix('#')
+        .is_some_and(|hex| hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Validate the content of a message before it gets broadcast.
+fn message_validate(message: &Message) -> Result<(), Status> {
+    let payload = match &message.payload {
+        Some(payload) => payload,
+        None => return Ok(()),
+    };
+
+    if !message.content.is_empty() {
+        return Err(Status::invalid_argument(
+            "content and payload are mutually exclusive",
+        ));
+    }
+
+    payload_validate(payload).map_err(Status::invalid_argument)
+}
+
+fn payload_validate(payload: &Payload) -> Result<(), String> {
+    if payload.notification.is_none() && payload.data.is_empty() {
+        return Err("payload has neither a notification nor data".to_string());
+    }
+
+    if let Some(notification) = &payload.notification {
+        if notification.title.is_empty() && notification.body.is_empty() {
+            return Err("notification has neither a title nor a body".to_string());
+        }
+
+        if !notification.image.is_empty() && !is_https_url(&notification.image) {
+            return Err(format!(
+                "notification image `{}` is not an https url",
+                notification.image
+            ));
+        }
+    }
+
+    for key in payload.data.keys() {
+        if PAYLOAD_RESERVED_KEYS.contains(&key.as_str())
+            || PAYLOAD_RESERVED_PREFIXES
+                .iter()
+                .any(|prefix| key.starts_with(prefix))
+        {
+            return Err(format!("data key `{}` is reserved", key));
+        }
+    }
+
+    if let Some(android) = &payload.android {
+        if !android.color.is_empty() && !is_hex_color(&android.color) {
+            return Err(format!(
+                "android color `{}` is not in #rrggbb format",
+                android.color
+            ));
+        }
+    }
+
+    if let Some(apns) = &payload.apns {
+        if apns.badge < 0 {
+            return Err(format!("apns badge `{}` is negative", apns.badge));
         }
     }
+
+    if let Some(webpush) = &payload.webpush {
+        if !webpush.link.is_empty() && !is_https_url(&webpush.link) {
+            return Err(format!(
+                "webpush link `{}` is not an https url",
+                webpush.link
+            ));
+        }
+    }
+
+    if let Some(ttl) = &payload.ttl {
+        if ttl.seconds < 0 || ttl.nanos < 0 {
+            return Err("ttl is negative".to_string());
+        }
+
+        if ttl.seconds > outbox::MAX_TTL_SECONDS {
+            return Err(format!(
+                "ttl exceeds the maximum of {} seconds",
+                outbox::MAX_TTL_SECONDS
+            ));
+        }
+    }
+
+    if payload.collapse_key.len() > PAYLOAD_MAX_COLLAPSE_KEY {
+        return Err(format!(
+            "collapse key exceeds the maximum of {} bytes",
+            PAYLOAD_MAX_COLLAPSE_KEY
+        ));
+    }
+
+    if payload.encoded_len() > PAYLOAD_MAX_SIZE {
+        return Err(format!(
+            "payload exceeds the maximum of {} bytes",
+            PAYLOAD_MAX_SIZE
+        ));
+    }
+
+    Ok(())
 }
 
-impl Default for CmMessageService {
+fn message_send_result(
+    index: u64,
+    outcome: Result<MessageSendResponse, Status>,
+) -> MessageSendResult {
+    MessageSendResult {
+        index,
+        outcome: Some(match outcome {
+            Ok(response) => message_send_result::Outcome::Sent(response),
+            Err(status) => message_send_result::Outcome::Failure(Failure {
+                code: status.code() as i32,
+                message: status.message().to_string(),
+            }),
+        }),
+    }
+}
+
+impl Default for CmMessageService<TokenDbInMemory> {
     fn default() -> Self {
-        CmMessageService::new(broadcast::channel(16))
+        CmMessageService::new_with_db(Arc::new(TokenDbInMemory::default()))
+    }
+}
+
+/// Scope the keys of the filter of a subscription to the tenant of the subscriber.
+fn message_subscribe_scope(request: &mut MessageSubscribeRequest, tenant: &str) {
+    let predicate = request
+        .filter
+        .as_mut()
+        .and_then(|filter| filter.predicate.as_mut());
+
+    if let Some(
+        cm::message_subscribe_filter::Predicate::Complement(keys)
+        | cm::message_subscribe_filter::Predicate::Intersection(keys)
+        | cm::message_subscribe_filter::Predicate::Union(keys),
+    ) = predicate
+    {
+        for key in keys.keys.iter_mut() {
+            key.tenant = tenant.to_string();
+        }
     }
 }
 
@@ -100,15 +594,165 @@ fn messages_subscribe_filter(request: &MessageSubscribeRequest, keys: Vec<TokenK
     false
 }
 
+/// Stream the messages in the domain of a subscriber, by their priority. Only the messages sent
+/// in the tenant of the subscriber are in its domain.
+///
+/// Messages wait in per subscriber priority lanes for room in the stream, so that a slow
+/// subscriber receives time critical messages ahead of the bulk queued for it.
+///
+/// Once asked to close, the subscriber stops taking in messages, flushes its lanes and ends the
+/// stream with `UNAVAILABLE`. Messages not taken in by then remain pending in the outbox.
+async fn message_subscribe_stream(
+    req: MessageSubscribeRequest,
+    tenant: Arc<str>,
+    mut subscribe_rx: broadcast::Receiver<MessageBroadcast>,
+    outbox: Arc<Outbox>,
+    tx: mpsc::Sender<Result<MessageBroadcast, Status>>,
+    mut subscriber: Subscriber,
+    metrics: Option<Metrics>,
+) {
+    let _subscribed = metrics.as_ref().map(|metrics| metrics.subscribed(SERVICE));
+    let mut lanes: Lanes<(Message, Arc<TraceContext>, Vec<model::TokenKey>)> = Lanes::new();
+
+    // Catch up on the messages pending delivery in the domain of the subscriber first.
+    let pending = outbox
+        .pending(|key| key.tenant == tenant && message_subscribe_filter(&req, &key.into()))
+        .await;
+
+    let mut caught_up = HashSet::new();
+    for (message, trace_context, keys) in pending {
+        caught_up.insert(message.id.clone());
+        lanes.push(
+            Lane::of(&message),
+            ((*message).clone(), trace_context, keys),
+        );
+    }
+
+    let mut closing = false;
+
+    loop {
+        if closing && lanes.is_empty() {
+            let _ = tx
+                .send(Err(Status::unavailable("server is shutting down")))
+                .await;
+            break;
+        }
+
+        tokio::select! {
+            biased;
+
+            _ = subscriber.closing(), if !closing => {
+                closing = true;
+            }
+
+            permit = tx.reserve(), if !lanes.is_empty() => {
+                let permit = match permit {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        // Channel is somehow broken. Prevent exhaustion and break the loop.
+                        info!("channel closed");
+                        break;
+                    }
+                };
+
+                let (mut message, trace_context, domain) = match lanes.pop() {
+                    Some(queued) => queued,
+                    None => continue,
+                };
+
+                // Skip the message if it was collapsed for the whole domain while queued.
+                let deliverable = outbox.deliverable(&message.id, domain.clone()).await;
+                if deliverable.is_empty() && !domain.is_empty() {
+                    continue;
+                }
+
+                // Drop the message if it stopped being relevant while queued.
+                let now = chrono::Utc::now().naive_utc();
+                if outbox::message_expired(&message, now) {
+                    outbox.expired(&message.id, &deliverable).await;
+                    continue;
+                }
+                outbox::message_remaining_ttl(&mut message, now);
+
+                // Trace the delivery in the trace the message was sent in, and hand the trace on.
+                let span = tracing::info_span!("message_deliver", id = %message.id);
+                telemetry::continue_trace(&span, &trace_context);
+
+                let id = message.id.clone();
+                permit.send(Ok(MessageBroadcast {
+                    operation: Some(Operation::Send(message)),
+                    trace_context: telemetry::context_of(&span),
+                }));
+
+                outbox.delivered(&id, &deliverable).instrument(span).await;
+            }
+
+            update = subscribe_rx.recv(), if lanes.len() < SUBSCRIBER_BUFFER && !closing => {
+                let update = match update {
+                    Ok(update) => update,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // The skipped messages remain pending in the outbox.
+                        warn!(skipped, "subscriber lagged behind");
+                        if let Some(metrics) = &metrics {
+                            metrics.lagged(SERVICE, skipped);
+                        }
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                info!("message recv");
+
+                // Match the defined operation and handle the set logic.
+                let trace_context = Arc::new(update.trace_context);
+                if let Some(operation) = update.operation {
+                    match operation {
+                        Operation::Send(message) => {
+                            debug!(id = %message.id, "message received");
+                            if caught_up.remove(&message.id) || *message.tenant != *tenant {
+                                continue;
+                            }
+
+                            if let Some(codomain) = message.codomain.clone() {
+                                // Determine whether or not the the processed update is in the domain of the subscriber.
+                                debug!(keys = codomain.keys.len(), "message to filter");
+                                let domain: Vec<model::TokenKey> = codomain
+                                    .keys
+                                    .iter()
+                                    .filter(|key| message_subscribe_filter(&req, key))
+                                    .cloned()
+                                    .map(model::TokenKey::from)
+                                    .collect();
+
+                                if messages_subscribe_filter(&req, codomain.keys) {
+                                    lanes.push(Lane::of(&message), (message, trace_context, domain));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
 #[async_trait]
-impl CmMessage for CmMessageService {
+impl<Db: TokenDb> CmMessage for CmMessageService<Db> {
     async fn message_send(
         &self,
         request: Request<MessageSendRequest>,
     ) -> Result<Response<MessageSendResponse>, Status> {
+        let grant = match self.access.authorize(&request, Permission::Send) {
+            Ok(grant) => grant,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
         // Assert that there is an inner message present in the request.
         let message = match &request.get_ref().inner {
-            Some(message) => message,
+            Some(message) => message.clone(),
             None => {
                 let status = Status::invalid_argument("inner message not present");
                 info!(status = ?&status, "request failed");
@@ -116,31 +760,103 @@ impl CmMessage for CmMessageService {
             }
         };
 
-        // Message is present. Now construct a broadcastable object and send it to the subscribers.
-        let bcast = MessageBroadcast {
-            operation: Some(Operation::Send(message.clone())),
+        let response = self.message_submit(grant.tenant(), message).await?;
+
+        info!(
+            "\nrpc#MessageSend :: ({:?}) \n\n{:?}\n",
+            &request.get_ref(),
+            &response.sent
+        );
+
+        // Ok, all things executed successfully. Send the response to finalize.
+        Ok(Response::new(response))
+    }
+
+    /// Send many messages at once, with an outcome for each of them.
+    async fn message_send_batch(
+        &self,
+        request: Request<MessageSendBatchRequest>,
+    ) -> Result<Response<MessageSendBatchResponse>, Status> {
+        let grant = match self.access.authorize(&request, Permission::Send) {
+            Ok(grant) => grant,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
         };
 
-        // Send through the broadcast channel.
-        match self.subscribe_tx.send(bcast) {
-            Ok(_) => {}
-            Err(_) => {
-                let status = Status::internal("channel broken");
+        let charge = Charge::of(&request);
+        let messages = request.into_inner().messages;
+
+        if messages.len() > MESSAGE_BATCH_MAX {
+            let status = Status::invalid_argument(format!(
+                "batch exceeds the maximum of {} messages",
+                MESSAGE_BATCH_MAX
+            ));
+            info!(status = ?&status, "request failed");
+            return Err(status);
+        }
+
+        let mut results = Vec::with_capacity(messages.len());
+        for (index, message) in messages.into_iter().enumerate() {
+            let outcome = match message_charge(charge.as_ref(), index as u64) {
+                Ok(_) => self.message_submit(grant.tenant(), message).await,
+                Err(status) => Err(status),
+            };
+            results.push(message_send_result(index as u64, outcome));
+        }
+
+        info!("\nrpc#MessageSendBatch :: ({} messages)", results.len());
+
+        Ok(Response::new(MessageSendBatchResponse { results }))
+    }
+
+    /// Ingest a stream of messages, summarizing the outcome once the stream ends.
+    ///
+    /// The stream is consumed no faster than the messages are dispatched, so a full dispatch
+    /// queue pushes back on the sender through flow control.
+    async fn message_send_stream(
+        &self,
+        request: Request<Streaming<MessageSendRequest>>,
+    ) -> Result<Response<MessageSendSummary>, Status> {
+        let grant = match self.access.authorize(&request, Permission::Send) {
+            Ok(grant) => grant,
+            Err(status) => {
                 info!(status = ?&status, "request failed");
                 return Err(status);
             }
         };
 
-        info!(
-            "\nrpc#MessageSend :: ({:?}) \n\n{:?}\n",
-            &request.get_ref(),
-            &message
-        );
+        let charge = Charge::of(&request);
+        let mut stream = request.into_inner();
+        let mut summary = MessageSendSummary::default();
 
-        // Ok, all things executed successfully. Send the response to finalize.
-        Ok(Response::new(MessageSendResponse {
-            sent: Some(message.clone()),
-        }))
+        while let Some(request) = stream.message().await? {
+            let index = summary.received;
+            summary.received += 1;
+
+            let outcome = match (message_charge(charge.as_ref(), index), request.inner) {
+                (Err(status), _) => Err(status),
+                (Ok(_), Some(message)) => self.message_submit(grant.tenant(), message).await,
+                (Ok(_), None) => Err(Status::invalid_argument("inner message not present")),
+            };
+
+            match outcome {
+                Ok(_) => summary.sent += 1,
+                Err(status) => {
+                    summary.failed += 1;
+                    if summary.failures.len() < SUMMARY_FAILURES_MAX {
+                        summary
+                            .failures
+                            .push(message_send_result(index, Err(status)));
+                    }
+                }
+            }
+        }
+
+        info!("\nrpc#MessageSendStream :: {:?}", &summary);
+
+        Ok(Response::new(summary))
     }
 
     type MessageSubscribeStream = ReceiverStream<Result<MessageBroadcast, Status>>;
@@ -149,56 +865,72 @@ impl CmMessage for CmMessageService {
         &self,
         request: Request<MessageSubscribeRequest>,
     ) -> Result<Response<Self::MessageSubscribeStream>, Status> {
+        let grant = match self
+            .access
+            .authorize(&request, Permission::SubscribeMessages)
+        {
+            Ok(grant) => grant,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
         // Spend up an internal mpsc channel for in process streaming.
-        let (tx, rx) = mpsc::channel(4);
+        let (tx, rx) = mpsc::channel(self.stream_capacity);
 
-        let req = request.into_inner().clone();
+        let mut req = request.into_inner();
+        message_subscribe_scope(&mut req, grant.tenant());
         let req2 = req.clone();
 
         // Take a new subscription for this instance of subscribe task.
-        let mut subscribe_rx = self.subscribe_tx.subscribe();
-        tokio::spawn(async move {
-            while let Ok(update) = subscribe_rx.recv().await {
-
-                info!("message recv");
+        let subscribe_rx = self.subscribe_tx.subscribe();
+        tokio::spawn(message_subscribe_stream(
+            req,
+            grant.tenant().clone(),
+            subscribe_rx,
+            self.outbox.clone(),
+            tx,
+            self.lifecycle.subscriber(),
+            self.metrics.clone(),
+        ));
 
-                // Match the defined operation and handle the set logic.
-                if let Some(operation) = update.clone().operation {
+        info!("\nrpc#MessageSubscribe :: ({:?})", &req2);
 
-                    info!("{:?}", operation);
+        // Subscribed successfully, begin streaming.
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
 
-                    match operation {
-                        Operation::Send(message) => {
-                            if let Some(codomain) = message.codomain {
-                                // Determine whether or not the the processed update is in the domain of the subscriber.
-                                println!("{:?}", codomain.keys);
-                                if messages_subscribe_filter(&req, codomain.keys) {
-                                    match tx.send(Ok(update)).await {
-                                        Ok(_) => {},
-                                        Err(_) => {
-                                            info!("channel closed");
-                                            break;
-                                        },
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+    /// Look up the delivery status of a sent message.
+    async fn message_status(
+        &self,
+        request: Request<MessageStatusRequest>,
+    ) -> Result<Response<DeliveryStatus>, Status> {
+        let grant = match self.access.authorize(&request, Permission::Send) {
+            Ok(grant) => grant,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
             }
-        });
+        };
 
-        info!("\nrpc#MessageSubscribe :: ({:?})", &req2);
+        let id = request.into_inner().id;
 
-        // Subscribed successfully, begin streaming.
-        Ok(Response::new(ReceiverStream::new(rx)))
+        match self.outbox.status(grant.tenant(), &id).await {
+            Some(status) => Ok(Response::new(status)),
+            None => {
+                let status = Status::not_found(format!("message `{}` not existing", id));
+                info!(status = ?&status, "request failed");
+                Err(status)
+            }
+        }
     }
 
     async fn check(
         &self,
         request: Request<HealthCheckRequest>,
     ) -> Result<Response<HealthCheckResponse>, Status> {
-        todo!()
+        health::check(&self.lifecycle, request)
     }
 
     type WatchStream = ReceiverStream<Result<HealthCheckResponse, Status>>;
@@ -207,6 +939,6 @@ impl CmMessage for CmMessageService {
         &self,
         request: Request<HealthCheckRequest>,
     ) -> Result<Response<Self::WatchStream>, Status> {
-        todo!()
+        Ok(health::watch(&self.lifecycle, request))
     }
 }
@@ -1,27 +1,53 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 
-use futures::TryFutureExt;
 use tokio::sync::{broadcast, mpsc};
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{async_trait, Request, Response, Status};
+use tonic::{async_trait, Request, Response, Status, Streaming};
 use tracing::info;
 
 use crate::{
-    database::{self, TokenDb, TokenDbError, TokenDbInMemory},
+    access::{AccessPolicy, Grant, Permission},
+    database::{self, RegistrationPolicy, TokenDb, TokenDbInMemory},
+    lifecycle::Lifecycle,
+    metrics::Metrics,
+    model,
     rpc::cm::TokenUpdate,
+    rpc::google::rpc::bad_request::FieldViolation,
+    rpc::health,
+    rpc::status::{bad_request, field_violation},
+    topic,
 };
 
 use super::cm::{
-    self, cm_token_server::CmToken, token_broadcast, HealthCheckRequest, HealthCheckResponse,
-    TokenBroadcast, TokenKey, TokenRegisterRequest, TokenRegisterResponse, TokenSubscribeRequest,
-    TokenUpdateRequest, TokenUpdateResponse,
+    self, cm_token_server::CmToken, token_broadcast, token_register_result, Failure,
+    HealthCheckRequest, HealthCheckResponse, TokenBroadcast, TokenKey, TokenRegisterBatchRequest,
+    TokenRegisterBatchResponse, TokenRegisterRequest, TokenRegisterResponse, TokenRegisterResult,
+    TokenRegisterSummary, TokenSubscribeRequest, TokenTopicRequest, TokenTopicResponse,
+    TokenUpdateRequest, TokenUpdateResponse, Tokens,
 };
 
+/// Maximum number of tokens in a single batch.
+pub const TOKEN_BATCH_MAX: usize = 1000;
+/// Maximum number of failures recorded in the summary of a stream, past which they are only
+/// counted.
+const SUMMARY_FAILURES_MAX: usize = 100;
+/// Name of the service its metrics are labelled with.
+const SERVICE: &str = "cm.cm_token";
+/// Capacity of the stream of each subscriber.
+const STREAM_CAPACITY: usize = 4;
+
 #[derive(Debug)]
 pub struct CmTokenService<Db: TokenDb> {
     subscribe_tx: broadcast::Sender<TokenBroadcast>,
-    subscribe_rx: broadcast::Receiver<TokenBroadcast>,
+    // Held so that broadcasting never fails for the lack of subscribers.
+    _subscribe_rx: broadcast::Receiver<TokenBroadcast>,
     db: Arc<Db>,
+    policy: RegistrationPolicy,
+    stream_capacity: usize,
+    lifecycle: Lifecycle,
+    access: Arc<AccessPolicy>,
+    metrics: Option<Metrics>,
 }
 
 impl<Db: TokenDb> CmTokenService<Db> {
@@ -30,32 +56,112 @@ impl<Db: TokenDb> CmTokenService<Db> {
             broadcast::Sender<TokenBroadcast>,
             broadcast::Receiver<TokenBroadcast>,
         ),
-        db: Db,
+        db: Arc<Db>,
     ) -> Self {
         Self {
             subscribe_tx: ch.0,
-            subscribe_rx: ch.1,
-            db: Arc::new(db),
+            _subscribe_rx: ch.1,
+            db,
+            policy: RegistrationPolicy::default(),
+            stream_capacity: STREAM_CAPACITY,
+            lifecycle: Lifecycle::new(),
+            access: Arc::new(AccessPolicy::open()),
+            metrics: None,
         }
     }
 
-    pub fn new_with_db(db: Db) -> Self {
-        let ch = broadcast::channel(16);
+    pub fn new_with_db(db: Arc<Db>) -> Self {
+        let ch = broadcast::channel(TOKEN_BATCH_MAX);
         Self {
             subscribe_tx: ch.0,
-            subscribe_rx: ch.1,
-            db: Arc::new(db),
+            _subscribe_rx: ch.1,
+            db,
+            policy: RegistrationPolicy::default(),
+            stream_capacity: STREAM_CAPACITY,
+            lifecycle: Lifecycle::new(),
+            access: Arc::new(AccessPolicy::open()),
+            metrics: None,
+        }
+    }
+
+    /// Treat the registrations of registered keys by the policy, rather than refreshing them.
+    pub fn with_policy(self, policy: RegistrationPolicy) -> Self {
+        Self { policy, ..self }
+    }
+
+    pub fn with_stream_capacity(self, stream_capacity: usize) -> Self {
+        Self {
+            stream_capacity,
+            ..self
+        }
+    }
+
+    /// Share the lifecycle of the server, so the service is shut down along with it.
+    pub fn with_lifecycle(self, lifecycle: Lifecycle) -> Self {
+        Self { lifecycle, ..self }
+    }
+
+    /// Authorize every request by the access policy, rather than permitting everything.
+    pub fn with_access(self, access: Arc<AccessPolicy>) -> Self {
+        Self { access, ..self }
+    }
+
+    /// Count the subscribers of the service into the metrics, if given.
+    pub fn with_metrics(self, metrics: Option<Metrics>) -> Self {
+        Self { metrics, ..self }
+    }
+
+    /// Refuse a request for a registered token out of the scope of the grant.
+    async fn token_scope(&self, grant: &Grant, key: &model::TokenKey) -> Result<(), Status> {
+        if !grant.restricted() {
+            return Ok(());
+        }
+
+        match self.db.get(key.clone()).await {
+            Ok(Some(registered)) => grant.check(&registered),
+            Ok(None) => Ok(()),
+            Err(error) => Err(error.into()),
         }
     }
+
+    /// Admit a registration into the scope of the grant, as well as the token it replaces.
+    async fn token_authorize(&self, grant: &Grant, token: &mut model::Token) -> Result<(), Status> {
+        grant.admit(token)?;
+        self.token_scope(grant, &token.key).await
+    }
 }
 
 impl Default for CmTokenService<TokenDbInMemory> {
     fn default() -> Self {
-        CmTokenService::new_with_db(database::TokenDbInMemory::default())
+        CmTokenService::new_with_db(Arc::new(database::TokenDbInMemory::default()))
     }
 }
 
-fn token_subscribe_filter(request: &TokenSubscribeRequest, key: &TokenKey) -> bool {
+/// Scope the keys of the filter of a subscription to the tenant of the subscriber.
+fn token_subscribe_scope(request: &mut TokenSubscribeRequest, tenant: &str) {
+    let predicate = request
+        .filter
+        .as_mut()
+        .and_then(|filter| filter.predicate.as_mut());
+
+    if let Some(
+        cm::token_subscribe_filter::Predicate::Complement(keys)
+        | cm::token_subscribe_filter::Predicate::Intersection(keys)
+        | cm::token_subscribe_filter::Predicate::Union(keys),
+    ) = predicate
+    {
+        for key in keys.keys.iter_mut() {
+            key.tenant = tenant.to_string();
+        }
+    }
+}
+
+/// Whether a key is in the domain of a subscriber in the tenant.
+fn token_subscribe_filter(request: &TokenSubscribeRequest, tenant: &str, key: &TokenKey) -> bool {
+    if key.tenant != tenant {
+        return false;
+    }
+
     if let Some(filter) = request.filter.as_ref() {
         if let Some(predicate) = &filter.predicate {
             match predicate {
@@ -75,10 +181,280 @@ fn token_subscribe_filter(request: &TokenSubscribeRequest, key: &TokenKey) -> bo
     false
 }
 
+/// Key of the token an update is of.
+fn token_update_key(update: &cm::TokenUpdate) -> Option<&TokenKey> {
+    update
+        .original
+        .as_ref()
+        .and_then(|original| original.key.as_ref())
+}
+
+/// Narrow a broadcast down to the domain of a subscriber in the tenant, unless none of it is in it.
+fn token_subscribe_narrow(
+    request: &TokenSubscribeRequest,
+    tenant: &str,
+    update: TokenBroadcast,
+) -> Option<TokenBroadcast> {
+    let in_domain = |key: Option<&TokenKey>| {
+        key.is_some_and(|key| token_subscribe_filter(request, tenant, key))
+    };
+
+    // Broadcasts of many tokens are narrowed to those in domain, the others pass whole or not.
+    let narrowed = match update.operation.as_ref()? {
+        token_broadcast::Operation::Addition(token)
+        | token_broadcast::Operation::Invalidation(token) => {
+            in_domain(token.key.as_ref()).then_some(None)
+        }
+        token_broadcast::Operation::Update(update) => {
+            in_domain(token_update_key(update)).then_some(None)
+        }
+        token_broadcast::Operation::Additions(additions) => {
+            let tokens: Vec<cm::Token> = additions
+                .tokens
+                .iter()
+                .filter(|token| in_domain(token.key.as_ref()))
+                .cloned()
+                .collect();
+            (!tokens.is_empty()).then_some(Some(token_broadcast::Operation::Additions(Tokens {
+                tokens,
+            })))
+        }
+        token_broadcast::Operation::Updates(updates) => {
+            let updates: Vec<cm::TokenUpdate> = updates
+                .updates
+                .iter()
+                .filter(|update| in_domain(token_update_key(update)))
+                .cloned()
+                .collect();
+            (!updates.is_empty()).then_some(Some(token_broadcast::Operation::Updates(
+                cm::TokenUpdates { updates },
+            )))
+        }
+    }?;
+
+    Some(match narrowed {
+        Some(operation) => TokenBroadcast {
+            operation: Some(operation),
+        },
+        None => update,
+    })
+}
+
+/// Length of an APNs device token in hexadecimal digits.
+const APNS_TOKEN_LENGTH: usize = 64;
+/// Bounds of the length of an FCM registration token.
+const FCM_TOKEN_LENGTH: (usize, usize) = (64, 4096);
+/// Length of the base64url encoded, uncompressed P-256 public key of a Web Push subscription.
+const WEBPUSH_P256DH_LENGTH: usize = 87;
+/// Length of the base64url encoded, 16 byte authentication secret of a Web Push subscription.
+const WEBPUSH_AUTH_LENGTH: usize = 22;
+
+fn is_base64url(value: &str, length: usize) -> bool {
+    let value = value.trim_end_matches('=');
+    value.len() == length
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Validate the key of a registration against the platform it declares.
+fn token_validate(request: &TokenRegisterRequest) -> Result<(), Status> {
+    let violations = token_violations(request);
+    match violations.is_empty() {
+        true => Ok(()),
+        false => Err(bad_request("token registration is invalid", violations)),
+    }
+}
+
+/// Validate a token restored by an import as its registration would be, along with its topics.
+pub(crate) fn token_validate_restored(token: &model::Token, record: u64) -> Result<(), Status> {
+    let request = TokenRegisterRequest {
+        token: Some(cm::TokenKey::from(&token.key)),
+        owner: token.owner.as_deref().unwrap_or_default().to_string(),
+        platform: token.platform as i32,
+        webpush: token.webpush.as_ref().map(cm::WebPushKeys::from),
+    };
+
+    let mut violations = token_violations(&request);
+    for topic in token.topics.iter() {
+        if !topic::is_valid_topic(topic) {
+            violations.push(field_violation(
+                "topics",
+                format!("topic `{}` is not a valid topic name", topic),
+            ));
+        }
+    }
+
+    match violations.is_empty() {
+        true => Ok(()),
+        false => Err(bad_request(
+            format!("token {} is invalid", record),
+            violations,
+        )),
+    }
+}
+
+/// The violations of the key of a registration against the platform it declares.
+fn token_violations(request: &TokenRegisterRequest) -> Vec<FieldViolation> {
+    let key = request
+        .token
+        .as_ref()
+        .map(|token| token.key.as_str())
+        .unwrap_or_default();
+
+    let mut violations = Vec::new();
+
+    if key.is_empty() {
+        violations.push(field_violation("token.key", "key is empty"));
+    } else {
+        match request.platform() {
+            cm::Platform::Unspecified => {}
+            cm::Platform::Fcm => {
+                if key.len() < FCM_TOKEN_LENGTH.0 || key.len() > FCM_TOKEN_LENGTH.1 {
+                    violations.push(field_violation(
+                        "token.key",
+                        format!(
+                            "fcm registration token must be {} to {} characters long",
+                            FCM_TOKEN_LENGTH.0, FCM_TOKEN_LENGTH.1
+                        ),
+                    ));
+                }
+                if !key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':'))
+                {
+                    violations.push(field_violation(
+                        "token.key",
+                        "fcm registration token may only contain [A-Za-z0-9-_:]",
+                    ));
+                }
+            }
+            cm::Platform::Apns => {
+                if key.len() != APNS_TOKEN_LENGTH || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+                    violations.push(field_violation(
+                        "token.key",
+                        format!(
+                            "apns device token must be {} hexadecimal digits",
+                            APNS_TOKEN_LENGTH
+                        ),
+                    ));
+                }
+            }
+            cm::Platform::Webpush => {
+                if key.strip_prefix("https://").is_none_or(str::is_empty) {
+                    violations.push(field_violation(
+                        "token.key",
+                        "webpush endpoint must be an https url",
+                    ));
+                }
+
+                match &request.webpush {
+                    Some(webpush) => {
+                        if !is_base64url(&webpush.p256dh, WEBPUSH_P256DH_LENGTH) {
+                            violations.push(field_violation(
+                                "webpush.p256dh",
+                                "p256dh must be a base64url encoded uncompressed P-256 public key",
+                            ));
+                        }
+                        if !is_base64url(&webpush.auth, WEBPUSH_AUTH_LENGTH) {
+                            violations.push(field_violation(
+                                "webpush.auth",
+                                "auth must be a base64url encoded 16 byte secret",
+                            ));
+                        }
+                    }
+                    None => violations.push(field_violation(
+                        "webpush",
+                        "webpush keys are required by the webpush platform",
+                    )),
+                }
+            }
+        }
+    }
+
+    if request.platform() != cm::Platform::Webpush && request.webpush.is_some() {
+        violations.push(field_violation(
+            "webpush",
+            "webpush keys are only accepted by the webpush platform",
+        ));
+    }
+
+    violations
+}
+
+/// Construct the token to register in a tenant from a registration request.
+fn token_registration(
+    request: TokenRegisterRequest,
+    tenant: &Arc<str>,
+) -> Result<model::Token, Status> {
+    token_validate(&request)?;
+
+    let platform = request.platform();
+    let key = request
+        .token
+        .ok_or_else(|| Status::invalid_argument("token not present"))?;
+
+    let owner = Some(request.owner)
+        .filter(|owner| !owner.is_empty())
+        .map(Arc::from);
+
+    let key = model::TokenKey::from(key).with_tenant(tenant.clone());
+
+    Ok(model::Token::new(key)
+        .with_owner(owner)
+        .with_platform(platform, request.webpush.map(model::WebPushKeys::from)))
+}
+
+/// Broadcast a newly created token as an addition, and a refreshed one as an update.
+fn token_insert_broadcast(insert: model::TokenInsert) -> TokenBroadcast {
+    TokenBroadcast {
+        operation: Some(match insert {
+            model::TokenInsert::Created(token) => {
+                token_broadcast::Operation::Addition(token.into())
+            }
+            model::TokenInsert::Refreshed(update) => {
+                token_broadcast::Operation::Update(update.into())
+            }
+        }),
+    }
+}
+
+fn token_register_result(
+    index: u64,
+    outcome: Result<model::TokenInsert, Status>,
+) -> TokenRegisterResult {
+    TokenRegisterResult {
+        index,
+        outcome: Some(match outcome {
+            Ok(insert) => token_register_result::Outcome::Token(insert.into_token().into()),
+            Err(status) => token_register_result::Outcome::Failure(Failure {
+                code: status.code() as i32,
+                message: status.message().to_string(),
+            }),
+        }),
+    }
+}
+
+/// Validate the topic names of a topic (un)subscription request.
+fn token_topics(topics: Vec<String>) -> Result<Vec<Arc<str>>, Status> {
+    if topics.is_empty() {
+        return Err(Status::invalid_argument("topics not present"));
+    }
+
+    topics
+        .into_iter()
+        .map(|topic| match topic::is_valid_topic(&topic) {
+            true => Ok(Arc::from(topic)),
+            false => Err(Status::invalid_argument(format!(
+                "topic `{}` is not a valid topic name",
+                topic
+            ))),
+        })
+        .collect()
+}
 
 #[async_trait]
 impl<Db: TokenDb> CmToken for CmTokenService<Db> {
-    // TODO: Token validation
     /// Register a token to the database implementation.
     async fn token_register(
         &self,
@@ -86,36 +462,45 @@ impl<Db: TokenDb> CmToken for CmTokenService<Db> {
     ) -> Result<Response<TokenRegisterResponse>, Status> {
         let req0 = request.get_ref().clone();
 
-        // Assert that there is a token present in the request.
-        let token = match request.into_inner().token {
-            Some(tok) => tok,
-            None => {
-                let status = Status::invalid_argument("token not present");
+        let grant = match self.access.authorize(&request, Permission::Register) {
+            Ok(grant) => grant,
+            Err(status) => {
                 info!(status = ?&status, "request failed");
                 return Err(status);
             }
         };
 
-        // Token is present. Attempt to insert the token to the database.
-        let token = match self.db.insert(token.into()).await {
-            Ok(tok) => tok,
-            Err(error) => {
-                let status = match error {
-                    TokenDbError::TokenNotPresent(tok) => {
-                        Status::invalid_argument(format!("token `{}` not existing", tok.key))
-                    }
-                    TokenDbError::Unknown => Status::internal("database failed"),
-                };
+        let request = request.into_inner();
+
+        // Assert that there is a valid token present in the request, admitted by the policy.
+        let mut token = match token_registration(request, grant.tenant()) {
+            Ok(token) => token,
+            Err(status) => {
                 info!(status = ?&status, "request failed");
                 return Err(status);
             }
         };
 
-        // The insert was successful. Now construct a broadcastable object and send it to the subscribers.
-        let bcast = TokenBroadcast {
-            operation: Some(token_broadcast::Operation::Addition(token.clone().into())),
+        if let Err(status) = self.token_authorize(&grant, &mut token).await {
+            info!(status = ?&status, "request failed");
+            return Err(status);
+        }
+
+        // Token is present. Attempt to insert the token to the database, as admitted by the policy.
+        let insert = match self.db.insert(token, self.policy).await {
+            Ok(insert) => insert,
+            Err(error) => {
+                let status = Status::from(error);
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
         };
 
+        // The insert was successful. Now construct a broadcastable object and send it to the subscribers,
+        // as an addition of a new token or an update of a refreshed one.
+        let token = insert.token().clone();
+        let bcast = token_insert_broadcast(insert);
+
         // Send through the broadcast channel.
         match self.subscribe_tx.send(bcast) {
             Ok(_) => {}
@@ -134,32 +519,122 @@ impl<Db: TokenDb> CmToken for CmTokenService<Db> {
         }))
     }
 
+    /// Register many tokens at once with a single database call, with an outcome for each of them.
+    async fn token_register_batch(
+        &self,
+        request: Request<TokenRegisterBatchRequest>,
+    ) -> Result<Response<TokenRegisterBatchResponse>, Status> {
+        let grant = match self.access.authorize(&request, Permission::Register) {
+            Ok(grant) => grant,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
+        let request = request.into_inner();
+
+        if request.tokens.len() > TOKEN_BATCH_MAX {
+            let status = Status::invalid_argument(format!(
+                "batch exceeds the maximum of {} tokens",
+                TOKEN_BATCH_MAX
+            ));
+            info!(status = ?&status, "request failed");
+            return Err(status);
+        }
+
+        let results = self.token_register_many(&grant, request, 0).await?;
+
+        info!("\nrpc::TokenRegisterBatch :: ({} tokens)", results.len());
+
+        Ok(Response::new(TokenRegisterBatchResponse { results }))
+    }
+
+    /// Ingest a stream of token batches, summarizing the outcome once the stream ends.
+    async fn token_register_stream(
+        &self,
+        request: Request<Streaming<TokenRegisterBatchRequest>>,
+    ) -> Result<Response<TokenRegisterSummary>, Status> {
+        let grant = match self.access.authorize(&request, Permission::Register) {
+            Ok(grant) => grant,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
+        let mut stream = request.into_inner();
+        let mut summary = TokenRegisterSummary::default();
+
+        while let Some(request) = stream.message().await? {
+            if request.tokens.len() > TOKEN_BATCH_MAX {
+                let status = Status::invalid_argument(format!(
+                    "batch exceeds the maximum of {} tokens",
+                    TOKEN_BATCH_MAX
+                ));
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+
+            let results = self
+                .token_register_many(&grant, request, summary.received)
+                .await?;
+            summary.received += results.len() as u64;
+
+            for result in results {
+                match result.outcome {
+                    Some(token_register_result::Outcome::Token(_)) => summary.registered += 1,
+                    _ => {
+                        summary.failed += 1;
+                        if summary.failures.len() < SUMMARY_FAILURES_MAX {
+                            summary.failures.push(result);
+                        }
+                    }
+                }
+            }
+        }
+
+        info!("\nrpc::TokenRegisterStream :: {:?}", &summary);
+
+        Ok(Response::new(summary))
+    }
+
     /// Update an exeting token. If the token is not present, throw an error status.
     async fn token_update(
         &self,
         request: Request<TokenUpdateRequest>,
     ) -> Result<Response<TokenUpdateResponse>, Status> {
+        let grant = match self.access.authorize(&request, Permission::Update) {
+            Ok(grant) => grant,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
+        let request = request.into_inner();
+        let expected_version = Some(request.expected_version).filter(|version| *version > 0);
+
         // Assert that the token in RPC is actually present.
-        let original_key = match request.into_inner().key {
-            Some(key) => key,
+        let original_key = match request.key {
+            Some(key) => model::TokenKey::from(key).with_tenant(grant.tenant().clone()),
             None => {
                 let status = Status::invalid_argument("token not present");
                 info!(status = ?&status, "request failed");
                 return Err(status);
             }
+        };
+
+        if let Err(status) = self.token_scope(&grant, &original_key).await {
+            info!(status = ?&status, "request failed");
+            return Err(status);
         }
-        .into();
 
         // Token is present. Now update it.
-        let token_update = match self.db.update(original_key).await {
+        let token_update = match self.db.update(original_key, expected_version).await {
             Ok(tok) => tok,
             Err(error) => {
-                let status = match error {
-                    TokenDbError::TokenNotPresent(tok) => {
-                        Status::invalid_argument(format!("token `{}` not existing", tok.key))
-                    }
-                    TokenDbError::Unknown => Status::internal("database failed"),
-                };
+                let status = Status::from(error);
                 info!(status = ?&status, "request failed");
                 return Err(status);
             }
@@ -190,6 +665,22 @@ impl<Db: TokenDb> CmToken for CmTokenService<Db> {
         }))
     }
 
+    /// Subscribe an existing token to one or more topics.
+    async fn token_topic_subscribe(
+        &self,
+        request: Request<TokenTopicRequest>,
+    ) -> Result<Response<TokenTopicResponse>, Status> {
+        self.token_topic(request, true).await
+    }
+
+    /// Unsubscribe an existing token from one or more topics.
+    async fn token_topic_unsubscribe(
+        &self,
+        request: Request<TokenTopicRequest>,
+    ) -> Result<Response<TokenTopicResponse>, Status> {
+        self.token_topic(request, false).await
+    }
+
     type TokenSubscribeStream = ReceiverStream<Result<TokenBroadcast, Status>>;
 
     /// Mark an agreement to receive token updates as a unary stream.
@@ -197,48 +688,73 @@ impl<Db: TokenDb> CmToken for CmTokenService<Db> {
         &self,
         request: Request<TokenSubscribeRequest>,
     ) -> Result<Response<Self::TokenSubscribeStream>, Status> {
+        let grant = match self.access.authorize(&request, Permission::SubscribeTokens) {
+            Ok(grant) => grant,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+        let tenant = grant.tenant().clone();
+
         // spend up an internal mpsc channel for in process streaming.
-        let (tx, rx) = mpsc::channel(4);
+        let (tx, rx) = mpsc::channel(self.stream_capacity);
 
-        let req = request.into_inner().clone();
+        let mut req = request.into_inner();
+        token_subscribe_scope(&mut req, &tenant);
         let req2 = req.clone();
 
         // Take a new subscribtion for this instance of subscribe task.
         let mut subscribe_rx = self.subscribe_tx.subscribe();
+        let mut subscriber = self.lifecycle.subscriber();
+        let metrics = self.metrics.clone();
 
         tokio::spawn(async move {
-            while let Ok(update) = subscribe_rx.recv().await {
-                // Match the defined operation and handle the set logic.
-                if let Some(operation) = &update.operation {
-                    // Determine whether or not the processed update is in the domain of the subscriber.
-                    let pass = match operation {
-                        token_broadcast::Operation::Addition(addition) => addition
-                            .key
-                            .as_ref()
-                            .map_or(false, |key| token_subscribe_filter(&req, key)),
-                        token_broadcast::Operation::Invalidation(invalidation) => invalidation
-                            .key
-                            .as_ref()
-                            .map_or(false, |key| token_subscribe_filter(&req, key)),
-                        token_broadcast::Operation::Update(update) => {
-                            update.original.as_ref().map_or(false, |key| {
-                                token_subscribe_filter(&req, key.key.as_ref().unwrap())
-                            })
-                        }
-                    };
-
-                    if pass {
-                        // The update is in domain. Send it to the master process for the RPC stream.
-                        match tx.send(Ok(update)).await {
-                            Ok(_) => {}
-                            Err(_) => {
-                                // Channel is somehow broken. Prevent exhaustion and break the loop.
-                                info!("channel closed");
-                                break;
+            let _subscribed = metrics.as_ref().map(|metrics| metrics.subscribed(SERVICE));
+
+            loop {
+                let update = tokio::select! {
+                    update = subscribe_rx.recv() => match update {
+                        Ok(update) => update,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            // The stream ends, as the subscriber missed updates it cannot recover,
+                            // telling it to resynchronize.
+                            if let Some(metrics) = &metrics {
+                                metrics.lagged(SERVICE, skipped);
                             }
-                        };
+                            let status = Status::data_loss(format!(
+                                "subscriber lagged behind by {} updates",
+                                skipped
+                            ));
+                            info!(status = ?&status, "subscription ended");
+                            let _ = tx.send(Err(status)).await;
+                            break;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = subscriber.closing() => {
+                        // Updates are broadcast as they are made, so none are left to flush.
+                        let status = Status::unavailable("server is shutting down");
+                        let _ = tx.send(Err(status)).await;
+                        break;
                     }
-                }
+                };
+
+                // Narrow the update down to the domain of the subscriber, skipping it if out of it.
+                let update = match token_subscribe_narrow(&req, &tenant, update) {
+                    Some(update) => update,
+                    None => continue,
+                };
+
+                // The update is in domain. Send it to the master process for the RPC stream.
+                match tx.send(Ok(update)).await {
+                    Ok(_) => {}
+                    Err(_) => {
+                        // Channel is somehow broken. Prevent exhaustion and break the loop.
+                        info!("channel closed");
+                        break;
+                    }
+                };
             }
         });
 
@@ -252,7 +768,7 @@ impl<Db: TokenDb> CmToken for CmTokenService<Db> {
         &self,
         request: Request<HealthCheckRequest>,
     ) -> Result<Response<HealthCheckResponse>, Status> {
-        todo!()
+        health::check(&self.lifecycle, request)
     }
 
     type WatchStream = ReceiverStream<Result<HealthCheckResponse, Status>>;
@@ -261,6 +777,217 @@ impl<Db: TokenDb> CmToken for CmTokenService<Db> {
         &self,
         request: Request<HealthCheckRequest>,
     ) -> Result<Response<Self::WatchStream>, Status> {
-        todo!()
+        Ok(health::watch(&self.lifecycle, request))
+    }
+}
+
+impl<Db: TokenDb> CmTokenService<Db> {
+    /// Shared implementation of the batch registration RPCs, indexing the outcomes from `offset`.
+    async fn token_register_many(
+        &self,
+        grant: &Grant,
+        request: TokenRegisterBatchRequest,
+        offset: u64,
+    ) -> Result<Vec<TokenRegisterResult>, Status> {
+        // Outcomes of the malformed registrations, leaving a gap for the well formed ones.
+        let mut outcomes: Vec<Option<Result<model::TokenInsert, Status>>> =
+            Vec::with_capacity(request.tokens.len());
+        let mut tokens = Vec::new();
+
+        let mut keys = HashSet::new();
+
+        for registration in request.tokens {
+            let mut token = match token_registration(registration, grant.tenant()) {
+                Ok(token) => token,
+                Err(status) => {
+                    outcomes.push(Some(Err(status)));
+                    continue;
+                }
+            };
+
+            if let Err(status) = grant.admit(&mut token) {
+                outcomes.push(Some(Err(status)));
+                continue;
+            }
+
+            if self.policy == RegistrationPolicy::RejectExisting && !keys.insert(token.key.clone())
+            {
+                outcomes.push(Some(Err(bad_request(
+                    "token registration is invalid",
+                    vec![field_violation(
+                        "token.key",
+                        "key is repeated within the batch",
+                    )],
+                ))));
+                continue;
+            }
+
+            tokens.push(token);
+            outcomes.push(None);
+        }
+
+        // The tokens replaced under a restricted grant must be in its scope as well, which is
+        // checked for the whole batch with a single read.
+        if grant.restricted() {
+            let keys = tokens.iter().map(|token| token.key.clone()).collect();
+            let registered = match self.db.get_many(keys).await {
+                Ok(registered) => registered,
+                Err(error) => {
+                    let status = Status::from(error);
+                    info!(status = ?&status, "request failed");
+                    return Err(status);
+                }
+            };
+
+            let mut scoped = std::mem::take(&mut tokens).into_iter().zip(registered);
+            for outcome in outcomes.iter_mut().filter(|outcome| outcome.is_none()) {
+                if let Some((token, registered)) = scoped.next() {
+                    match registered.map_or(Ok(()), |registered| grant.check(&registered)) {
+                        Ok(()) => tokens.push(token),
+                        Err(status) => *outcome = Some(Err(status)),
+                    }
+                }
+            }
+        }
+
+        // Insert every well formed token with a single database call, as admitted by the policy,
+        // filling in the gaps.
+        let mut inserted = self.db.insert_many(tokens, self.policy).await.into_iter();
+        let outcomes: Vec<Result<model::TokenInsert, Status>> = outcomes
+            .into_iter()
+            .map(|outcome| {
+                outcome.unwrap_or_else(|| match inserted.next() {
+                    Some(result) => result.map_err(Status::from),
+                    None => Err(Status::internal("database failed")),
+                })
+            })
+            .collect();
+
+        let registered = outcomes.iter().filter_map(|outcome| outcome.as_ref().ok());
+
+        // Broadcast the registered tokens, either as one addition of the created ones and one
+        // update of the refreshed ones, or as an addition or update each.
+        let bcasts: Vec<TokenBroadcast> = match request.coalesce {
+            true => {
+                let mut tokens = Vec::new();
+                let mut updates = Vec::new();
+                for insert in registered.cloned() {
+                    match insert {
+                        model::TokenInsert::Created(token) => tokens.push(token.into()),
+                        model::TokenInsert::Refreshed(update) => updates.push(update.into()),
+                    }
+                }
+
+                let additions = Some(Tokens { tokens })
+                    .filter(|additions| !additions.tokens.is_empty())
+                    .map(token_broadcast::Operation::Additions);
+                let updates = Some(cm::TokenUpdates { updates })
+                    .filter(|updates| !updates.updates.is_empty())
+                    .map(token_broadcast::Operation::Updates);
+
+                additions
+                    .into_iter()
+                    .chain(updates)
+                    .map(|operation| TokenBroadcast {
+                        operation: Some(operation),
+                    })
+                    .collect()
+            }
+            false => registered.cloned().map(token_insert_broadcast).collect(),
+        };
+
+        for bcast in bcasts {
+            if self.subscribe_tx.send(bcast).is_err() {
+                let status = Status::internal("channel broken");
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        }
+
+        Ok(outcomes
+            .into_iter()
+            .enumerate()
+            .map(|(index, outcome)| token_register_result(offset + index as u64, outcome))
+            .collect())
+    }
+
+    /// Shared implementation of the topic (un)subscription RPCs.
+    async fn token_topic(
+        &self,
+        request: Request<TokenTopicRequest>,
+        subscribe: bool,
+    ) -> Result<Response<TokenTopicResponse>, Status> {
+        let grant = match self.access.authorize(&request, Permission::Update) {
+            Ok(grant) => grant,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
+        let request = request.into_inner();
+        let req0 = request.clone();
+
+        // Assert that the token and its topics in the request are present and well formed.
+        let key = match request.key {
+            Some(key) => model::TokenKey::from(key).with_tenant(grant.tenant().clone()),
+            None => {
+                let status = Status::invalid_argument("token not present");
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
+        if let Err(status) = self.token_scope(&grant, &key).await {
+            info!(status = ?&status, "request failed");
+            return Err(status);
+        }
+
+        let topics = match token_topics(request.topics) {
+            Ok(topics) => topics,
+            Err(status) => {
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
+        let result = match subscribe {
+            true => self.db.subscribe_topics(key, topics).await,
+            false => self.db.unsubscribe_topics(key, topics).await,
+        };
+
+        let token_update = match result {
+            Ok(tok) => tok,
+            Err(error) => {
+                let status = Status::from(error);
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        };
+
+        // The subscriptions of the token changed; broadcast it as an update to the subscribers.
+        let bcast = TokenBroadcast {
+            operation: Some(token_broadcast::Operation::Update(
+                token_update.clone().into(),
+            )),
+        };
+
+        match self.subscribe_tx.send(bcast) {
+            Ok(_) => {}
+            Err(_) => {
+                let status = Status::internal("channel broken");
+                info!(status = ?&status, "request failed");
+                return Err(status);
+            }
+        }
+
+        info!(
+            "\nrpc::TokenTopic :: ({:?}) \n\n{:?}\n",
+            &req0, &token_update.delta
+        );
+
+        Ok(Response::new(TokenTopicResponse {
+            token: Some(token_update.delta.into()),
+        }))
     }
 }
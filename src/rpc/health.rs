@@ -0,0 +1,84 @@
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tracing::info;
+
+use crate::lifecycle::{self, Lifecycle};
+
+use super::cm::health_check_response::ServingStatus;
+use super::cm::{HealthCheckRequest, HealthCheckResponse};
+
+/// Number of status changes buffered per watcher while waiting for room in its stream.
+const WATCH_CAPACITY: usize = 4;
+
+fn service_known(service: &str) -> bool {
+    service.is_empty() || lifecycle::SERVICES.contains(&service)
+}
+
+/// Report the serving status of the server as a whole, or of one of its services by name.
+pub fn check(
+    lifecycle: &Lifecycle,
+    request: Request<HealthCheckRequest>,
+) -> Result<Response<HealthCheckResponse>, Status> {
+    let service = request.into_inner().service;
+
+    if !service_known(&service) {
+        let status = Status::not_found(format!("service `{}` not existing", service));
+        info!(status = ?&status, "request failed");
+        return Err(status);
+    }
+
+    Ok(Response::new(HealthCheckResponse {
+        status: lifecycle.status() as i32,
+    }))
+}
+
+/// Stream the serving status of the server or one of its services, once now and again whenever it
+/// changes, until the server closes its subscribers.
+///
+/// An unknown service is reported as SERVICE_UNKNOWN rather than refused, as it may yet be served.
+pub fn watch(
+    lifecycle: &Lifecycle,
+    request: Request<HealthCheckRequest>,
+) -> Response<ReceiverStream<Result<HealthCheckResponse, Status>>> {
+    let known = service_known(&request.into_inner().service);
+    let (tx, rx) = mpsc::channel(WATCH_CAPACITY);
+
+    let mut subscriber = lifecycle.subscriber();
+    let mut status_rx = lifecycle.watch_status();
+
+    tokio::spawn(async move {
+        loop {
+            let status = match known {
+                true => *status_rx.borrow_and_update(),
+                false => ServingStatus::ServiceUnknown,
+            };
+
+            let response = HealthCheckResponse {
+                status: status as i32,
+            };
+            if tx.send(Ok(response)).await.is_err() {
+                info!("channel closed");
+                return;
+            }
+
+            tokio::select! {
+                // Report a change made on the way to closing before closing.
+                biased;
+
+                changed = status_rx.changed(), if known => {
+                    if changed.is_err() {
+                        break;
+                    }
+                }
+                _ = subscriber.closing() => break,
+            }
+        }
+
+        let _ = tx
+            .send(Err(Status::unavailable("server is shutting down")))
+            .await;
+    });
+
+    Response::new(ReceiverStream::new(rx))
+}
@@ -1,6 +1,16 @@
+pub mod cm_admin;
 pub mod cm_message;
 pub mod cm_token;
+pub mod health;
+pub mod status;
 
+#[allow(clippy::large_enum_variant)]
 pub mod cm {
     tonic::include_proto!("cm");
 }
+
+pub mod google {
+    pub mod rpc {
+        tonic::include_proto!("google.rpc");
+    }
+}
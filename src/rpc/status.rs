@@ -0,0 +1,114 @@
+use bytes::Bytes;
+use prost::Message;
+use tonic::{Code, Status};
+
+use super::google::rpc::{self, bad_request::FieldViolation};
+use crate::database::TokenDbError;
+use crate::model::TokenKey;
+
+const BAD_REQUEST_TYPE_URL: &str = "type.googleapis.com/google.rpc.BadRequest";
+const RESOURCE_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.ResourceInfo";
+const RETRY_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.RetryInfo";
+
+/// Resource type reported in the error details of token database failures.
+const TOKEN_RESOURCE_TYPE: &str = "cm.Token";
+/// Delay after which clients are advised to retry while the database is unavailable.
+const UNAVAILABLE_RETRY_SECONDS: i64 = 1;
+
+/// Construct a status carrying the given error details as `google.rpc.Status`.
+fn with_details(code: Code, message: impl Into<String>, details: Vec<prost_types::Any>) -> Status {
+    let message = message.into();
+
+    let status = rpc::Status {
+        code: code as i32,
+        message: message.clone(),
+        details,
+    };
+
+    Status::with_details(code, message, Bytes::from(status.encode_to_vec()))
+}
+
+fn any(type_url: &str, detail: impl Message) -> prost_types::Any {
+    prost_types::Any {
+        type_url: type_url.to_string(),
+        value: detail.encode_to_vec(),
+    }
+}
+
+/// Construct an `INVALID_ARGUMENT` status carrying the field violations as `google.rpc.BadRequest`
+/// error details.
+pub fn bad_request(message: impl Into<String>, field_violations: Vec<FieldViolation>) -> Status {
+    with_details(
+        Code::InvalidArgument,
+        message,
+        vec![any(
+            BAD_REQUEST_TYPE_URL,
+            rpc::BadRequest { field_violations },
+        )],
+    )
+}
+
+pub fn field_violation(field: &str, description: impl Into<String>) -> FieldViolation {
+    FieldViolation {
+        field: field.to_string(),
+        description: description.into(),
+    }
+}
+
+fn resource_info(key: &TokenKey, description: &str) -> prost_types::Any {
+    any(
+        RESOURCE_INFO_TYPE_URL,
+        rpc::ResourceInfo {
+            resource_type: TOKEN_RESOURCE_TYPE.to_string(),
+            resource_name: key.key.to_string(),
+            owner: String::new(),
+            description: description.to_string(),
+        },
+    )
+}
+
+/// Map a token database failure to the status of the RPC, along with its error details.
+impl From<TokenDbError> for Status {
+    fn from(error: TokenDbError) -> Self {
+        let message = error.to_string();
+
+        match &error {
+            TokenDbError::NotFound(key) => with_details(
+                Code::NotFound,
+                message,
+                vec![resource_info(key, "token is not registered")],
+            ),
+            TokenDbError::AlreadyExists(key) => with_details(
+                Code::AlreadyExists,
+                message,
+                vec![resource_info(key, "token is already registered")],
+            ),
+            TokenDbError::RejectedConflicting(key) => with_details(
+                Code::AlreadyExists,
+                message,
+                vec![resource_info(
+                    key,
+                    "token is registered with another platform or owner",
+                )],
+            ),
+            TokenDbError::Conflict { key, .. } => with_details(
+                Code::Aborted,
+                message,
+                vec![resource_info(key, "token was modified concurrently")],
+            ),
+            TokenDbError::Unavailable(_) => with_details(
+                Code::Unavailable,
+                message,
+                vec![any(
+                    RETRY_INFO_TYPE_URL,
+                    rpc::RetryInfo {
+                        retry_delay: Some(prost_types::Duration {
+                            seconds: UNAVAILABLE_RETRY_SECONDS,
+                            nanos: 0,
+                        }),
+                    },
+                )],
+            ),
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{self, Sampler, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::TraceError;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_http::{HttpClient, HttpError};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::{info, Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::OtlpConfig;
+
+/// W3C trace context, as its `traceparent` and `tracestate` headers.
+pub type TraceContext = HashMap<String, String>;
+
+/// Extracts the trace context from the metadata of a request.
+struct MetadataExtractor<'a>(&'a http::HeaderMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .map(http::header::HeaderName::as_str)
+            .collect()
+    }
+}
+
+/// Client posting the exported spans to the collector.
+#[derive(Debug, Default)]
+struct CollectorClient(Client<HttpConnector>);
+
+#[tonic::async_trait]
+impl HttpClient for CollectorClient {
+    async fn send(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        let response = self.0.request(request.map(Body::from)).await?;
+        if !response.status().is_success() {
+            return Err(format!("collector responded {}", response.status()).into());
+        }
+
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        Ok(http::Response::from_parts(parts, body))
+    }
+}
+
+/// Layer exporting the spans to the collector over OTLP/HTTP, in batches from a spawned task.
+/// Must be called within a tokio runtime.
+///
+/// The W3C trace context is propagated from then on, so the traces of the callers are continued.
+pub fn layer<S>(config: &OtlpConfig) -> Result<OpenTelemetryLayer<S, Tracer>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(&config.endpoint)
+        .with_http_client(CollectorClient::default());
+
+    // A trace continued from a caller follows its sampling decision.
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio)));
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(
+            trace::config()
+                .with_sampler(sampler)
+                .with_resource(Resource::new(vec![KeyValue::new(
+                    "service.name",
+                    config.service_name.clone(),
+                )])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+
+    info!(endpoint = %config.endpoint, "exporting traces");
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Export the spans which ended but were not exported yet, and stop exporting.
+pub async fn shutdown() {
+    // Blocks until the exporting task is done, which runs on the runtime.
+    let _ = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
+}
+
+/// Span of an RPC call, continuing the trace the caller propagated in its metadata, if any.
+pub fn server_span<B>(request: &http::Request<B>) -> Span {
+    let path = request.uri().path();
+    let span = tracing::info_span!("cm_server", otel.name = path, otel.kind = "server");
+
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&MetadataExtractor(request.headers()))
+    });
+    span.set_parent(parent);
+
+    span
+}
+
+/// The trace context of the span, to propagate it to where the work it started continues.
+///
+/// Empty unless traces are exported.
+pub fn context_of(span: &Span) -> TraceContext {
+    let mut context = TraceContext::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut context)
+    });
+    context.retain(|_, value| !value.is_empty());
+    context
+}
+
+/// Continue the trace of the context in the span.
+pub fn continue_trace(span: &Span, context: &TraceContext) {
+    if context.is_empty() {
+        return;
+    }
+
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(context));
+    span.set_parent(parent);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use prost::Message;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tonic::transport::{Channel, Server};
+    use tonic::Request;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::cm;
+    use crate::cm::cm_message_client::CmMessageClient;
+    use crate::cm::cm_message_server::CmMessageServer;
+    use crate::database::TokenDbInMemory;
+    use crate::rpc::cm_message::CmMessageService;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    /// The parts of an OTLP export request which the collector looks into.
+    #[derive(Clone, PartialEq, Message)]
+    struct ExportTraceServiceRequest {
+        #[prost(message, repeated, tag = "1")]
+        resource_spans: Vec<ResourceSpans>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct ResourceSpans {
+        #[prost(message, repeated, tag = "2")]
+        instrumentation_library_spans: Vec<InstrumentationLibrarySpans>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct InstrumentationLibrarySpans {
+        #[prost(message, repeated, tag = "2")]
+        spans: Vec<ExportedSpan>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct ExportedSpan {
+        #[prost(bytes = "vec", tag = "1")]
+        trace_id: Vec<u8>,
+        #[prost(bytes = "vec", tag = "4")]
+        parent_span_id: Vec<u8>,
+        #[prost(string, tag = "5")]
+        name: String,
+    }
+
+    /// Stand-in for a collector, passing on the spans exported to it.
+    async fn collect(
+        request: hyper::Request<Body>,
+        spans: mpsc::UnboundedSender<ExportedSpan>,
+    ) -> Result<hyper::Response<Body>, hyper::Error> {
+        if request.uri().path() != "/v1/traces" {
+            let mut response = hyper::Response::new(Body::empty());
+            *response.status_mut() = http::StatusCode::NOT_FOUND;
+            return Ok(response);
+        }
+
+        let body = hyper::body::to_bytes(request.into_body()).await?;
+        let spans_exported = ExportTraceServiceRequest::decode(body)
+            .unwrap()
+            .resource_spans
+            .into_iter()
+            .flat_map(|resource| resource.instrumentation_library_spans)
+            .flat_map(|library| library.spans);
+        for span in spans_exported {
+            let _ = spans.send(span);
+        }
+        Ok(hyper::Response::new(Body::empty()))
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Bind a local port, returning its address along with the connections accepted on it.
+    async fn listen() -> (
+        String,
+        impl futures::Stream<Item = std::io::Result<tokio::net::TcpStream>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = async_stream::stream! {
+            loop {
+                yield listener.accept().await.map(|(stream, _)| stream);
+            }
+        };
+        (address, incoming)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn message_send_continues_the_trace_of_the_caller() {
+        let (spans_tx, mut spans_rx) = mpsc::unbounded_channel();
+        let (address, incoming) = listen().await;
+        let collector = make_service_fn(move |_| {
+            let spans_tx = spans_tx.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |request| {
+                    collect(request, spans_tx.clone())
+                }))
+            }
+        });
+        tokio::spawn(
+            hyper::Server::builder(hyper::server::accept::from_stream(incoming)).serve(collector),
+        );
+
+        let config = OtlpConfig {
+            endpoint: format!("{}/v1/traces", address),
+            service_name: "pine5-cm-test".to_string(),
+            sample_ratio: 0.0,
+        };
+        let subscriber = tracing_subscriber::registry().with(layer(&config).unwrap());
+        tracing::subscriber::set_global_default(subscriber).unwrap();
+
+        let service = CmMessageService::new_with_db(Arc::new(TokenDbInMemory::new()));
+        let (address, incoming) = listen().await;
+        tokio::spawn(
+            Server::builder()
+                .trace_fn(server_span)
+                .add_service(CmMessageServer::new(service))
+                .serve_with_incoming(incoming),
+        );
+        let channel = Channel::from_shared(address)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = CmMessageClient::new(channel);
+
+        let key = cm::TokenKey {
+            key: "dev-1".to_string(),
+            tenant: String::new(),
+        };
+        let mut subscription = client
+            .message_subscribe(cm::MessageSubscribeRequest {
+                filter: Some(cm::MessageSubscribeFilter {
+                    predicate: Some(cm::message_subscribe_filter::Predicate::Union(
+                        cm::TokenKeys::default(),
+                    )),
+                }),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+
+        // Sampled by the caller, so the trace is exported whatever the sample ratio.
+        let mut request = Request::new(cm::MessageSendRequest {
+            inner: Some(cm::Message {
+                codomain: Some(cm::TokenKeys { keys: vec![key] }),
+                ..Default::default()
+            }),
+        });
+        request.metadata_mut().insert(
+            "traceparent",
+            format!("00-{}-{}-01", TRACE_ID, PARENT_ID).parse().unwrap(),
+        );
+        client.message_send(request).await.unwrap();
+
+        let broadcast = subscription.message().await.unwrap().unwrap();
+        let traceparent = &broadcast.trace_context["traceparent"];
+        assert!(traceparent.starts_with(&format!("00-{}-", TRACE_ID)));
+        assert!(!traceparent.contains(PARENT_ID));
+
+        shutdown().await;
+
+        let mut exported = Vec::new();
+        while let Ok(span) = spans_rx.try_recv() {
+            exported.push(span);
+        }
+        let span = exported
+            .iter()
+            .find(|span| span.name == "/cm.cm_message/MessageSend")
+            .expect("span of the call exported");
+        assert_eq!(hex(&span.trace_id), TRACE_ID);
+        assert_eq!(hex(&span.parent_span_id), PARENT_ID);
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+use tonic::{Request, Status};
+
+use crate::auth::Principal;
+use crate::model::DEFAULT_TENANT;
+
+/// Metadata naming the tenant of a request, as trusted from clients that are not authenticated.
+pub const TENANT_HEADER: &str = "x-tenant";
+
+// TODO: Provider credentials per tenant. Deferred, as the service hands messages to its
+// subscribers and sends through no provider itself, so there is nothing to use them yet.
+
+/// The tenant of the caller of a request, to which its lookups and fan-out are scoped.
+pub fn of<T>(request: &Request<T>) -> Result<Arc<str>, Status> {
+    let named = match request.metadata().get(TENANT_HEADER) {
+        Some(named) => Some(
+            named
+                .to_str()
+                .map_err(|_| Status::invalid_argument("tenant is malformed"))?,
+        ),
+        None => None,
+    };
+
+    resolve(Principal::of(request), named)
+}
+
+/// The tenant of a caller, authenticated as the principal if given, which names a tenant if given.
+///
+/// An authenticated caller belongs to the tenant of its principal, and is refused with
+/// `PERMISSION_DENIED` if it names another. A caller that is not authenticated belongs to the
+/// tenant it names, or else the default tenant.
+pub fn resolve(principal: Option<&Principal>, named: Option<&str>) -> Result<Arc<str>, Status> {
+    match (principal, named) {
+        (Some(principal), Some(named)) if named != principal.tenant => {
+            Err(Status::permission_denied(format!(
+                "`{}` is not a member of tenant `{}`",
+                principal.subject, named
+            )))
+        }
+        (Some(principal), _) => Ok(Arc::from(principal.tenant.as_str())),
+        (None, named) => Ok(Arc::from(named.unwrap_or(DEFAULT_TENANT))),
+    }
+}
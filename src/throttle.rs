@@ -0,0 +1,338 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+use crate::config::{ThrottleConfig, ThrottlePolicy};
+use crate::model::TokenKey;
+
+/// Minimum interval between two sweeps of the tokens which received no message for a while.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What becomes of a message to a token which received as many messages as it may.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Throttled {
+    /// The message is not delivered to the token.
+    Dropped,
+    /// The message is held back from the token for the delay, replacing the message of the
+    /// given id held back before it, if any.
+    Held {
+        delay: Duration,
+        collapses: Option<Arc<str>>,
+    },
+}
+
+/// Caps the messages each token receives per minute and per hour, so that a misbehaving sender
+/// cannot flood a device, independently of the limits of the callers.
+///
+/// A message is counted against a token once it is let through to it, or when it is due if held
+/// back, so the messages held back for a token are spread over the coming windows.
+#[derive(Debug)]
+pub struct Throttle {
+    // Length of each window along with the messages a token may receive within it.
+    windows: Vec<(Duration, usize)>,
+    policy: ThrottlePolicy,
+    // Messages which may be held back for a token, past which messages to it are dropped.
+    max_held: usize,
+    inner: Mutex<ThrottleInner>,
+}
+
+#[derive(Debug, Default)]
+struct ThrottleInner {
+    recipients: HashMap<TokenKey, Recipient>,
+    swept: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct Recipient {
+    // Moments the messages were or are due to be let through, oldest first.
+    deliveries: VecDeque<Instant>,
+    // Message held back last, along with when it is due, when collapsing.
+    held: Option<(Instant, Arc<str>)>,
+}
+
+impl Throttle {
+    pub fn new(config: &ThrottleConfig) -> Self {
+        let windows: Vec<(Duration, usize)> = [
+            (Duration::from_secs(60), config.per_minute),
+            (Duration::from_secs(60 * 60), config.per_hour),
+        ]
+        .into_iter()
+        .filter_map(|(window, limit)| Some((window, limit? as usize)))
+        .collect();
+
+        info!(
+            per_minute = ?config.per_minute,
+            per_hour = ?config.per_hour,
+            policy = ?config.policy,
+            max_held = config.max_held,
+            "per token throttling enabled"
+        );
+
+        Self {
+            windows,
+            policy: config.policy,
+            max_held: config.max_held,
+            inner: Mutex::new(ThrottleInner::default()),
+        }
+    }
+
+    /// Count a message against every token it targets, telling what becomes of it for the tokens
+    /// past their cap. The message is let through to the tokens not listed.
+    pub async fn admit(&self, id: &str, keys: &[TokenKey]) -> HashMap<TokenKey, Throttled> {
+        self.admit_at(id, keys, Instant::now()).await
+    }
+
+    async fn admit_at(
+        &self,
+        id: &str,
+        keys: &[TokenKey],
+        now: Instant,
+    ) -> HashMap<TokenKey, Throttled> {
+        let id: Arc<str> = Arc::from(id);
+        let span = self.span();
+
+        let mut locked = self.inner.lock().await;
+        locked.sweep(now, span);
+
+        let mut throttled = HashMap::new();
+        for key in keys.iter() {
+            let recipient = locked.recipients.entry(key.clone()).or_default();
+            recipient.forget(now, span);
+
+            // The message held back for the token is replaced by this one, which takes its turn.
+            if self.policy == ThrottlePolicy::Collapse {
+                if let Some((due, held)) = recipient.held.as_mut().filter(|(due, _)| *due > now) {
+                    let collapses = std::mem::replace(held, id.clone());
+                    throttled.insert(
+                        key.clone(),
+                        Throttled::Held {
+                            delay: *due - now,
+                            collapses: Some(collapses),
+                        },
+                    );
+                    continue;
+                }
+            }
+
+            let due = self.due(&recipient.deliveries, now);
+            if due == now {
+                recipient.deliveries.push_back(now);
+                continue;
+            }
+
+            // Messages past those held back for the token are dropped rather than held further.
+            let held = recipient
+                .deliveries
+                .iter()
+                .rev()
+                .take_while(|d| **d > now)
+                .count();
+            match self.policy {
+                ThrottlePolicy::Drop => {
+                    throttled.insert(key.clone(), Throttled::Dropped);
+                }
+                ThrottlePolicy::Delay | ThrottlePolicy::Collapse if held >= self.max_held => {
+                    throttled.insert(key.clone(), Throttled::Dropped);
+                }
+                ThrottlePolicy::Delay | ThrottlePolicy::Collapse => {
+                    recipient.deliveries.push_back(due);
+                    if self.policy == ThrottlePolicy::Collapse {
+                        recipient.held = Some((due, id.clone()));
+                    }
+                    throttled.insert(
+                        key.clone(),
+                        Throttled::Held {
+                            delay: due - now,
+                            collapses: None,
+                        },
+                    );
+                }
+            }
+        }
+
+        if !throttled.is_empty() {
+            debug!(%id, keys = throttled.len(), policy = ?self.policy, "message throttled");
+        }
+
+        throttled
+    }
+
+    /// The earliest moment a token may be let through another message without exceeding any
+    /// window, after the messages it was let through or is due to be.
+    fn due(&self, deliveries: &VecDeque<Instant>, now: Instant) -> Instant {
+        let mut due = deliveries.back().map_or(now, |last| now.max(*last));
+
+        loop {
+            let mut later = due;
+            for (window, limit) in self.windows.iter() {
+                let within = deliveries
+                    .iter()
+                    .rev()
+                    .take_while(|delivered| due.saturating_duration_since(**delivered) < *window)
+                    .count();
+
+                // Wait for enough of the messages within the window to leave it.
+                if within >= *limit {
+                    later = later.max(deliveries[deliveries.len() - limit] + *window);
+                }
+            }
+
+            if later == due {
+                return due;
+            }
+            due = later;
+        }
+    }
+
+    /// Length of the longest window, past which the messages of a token are forgotten.
+    fn span(&self) -> Duration {
+        self.windows
+            .iter()
+            .map(|(window, _)| *window)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+impl Recipient {
+    fn forget(&mut self, now: Instant, span: Duration) {
+        while self
+            .deliveries
+            .front()
+            .is_some_and(|delivered| now.saturating_duration_since(*delivered) >= span)
+        {
+            self.deliveries.pop_front();
+        }
+
+        if self.held.as_ref().is_some_and(|(due, _)| *due <= now) {
+            self.held = None;
+        }
+    }
+}
+
+impl ThrottleInner {
+    /// Forget the tokens with no message within any window, at most once per sweep interval.
+    fn sweep(&mut self, now: Instant, span: Duration) {
+        if self
+            .swept
+            .is_some_and(|swept| now.saturating_duration_since(swept) < SWEEP_INTERVAL)
+        {
+            return;
+        }
+        self.swept = Some(now);
+
+        self.recipients.retain(|_, recipient| {
+            recipient.forget(now, span);
+            !recipient.deliveries.is_empty() || recipient.held.is_some()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(
+        per_minute: Option<u32>,
+        per_hour: Option<u32>,
+        policy: ThrottlePolicy,
+        max_held: usize,
+    ) -> Throttle {
+        Throttle::new(&ThrottleConfig {
+            per_minute,
+            per_hour,
+            policy,
+            max_held,
+        })
+    }
+
+    fn minutes(minutes: u64) -> Duration {
+        Duration::from_secs(minutes * 60)
+    }
+
+    /// What becomes of a message to the token at the moment, or `None` if let through.
+    async fn admit(throttle: &Throttle, id: &str, now: Instant) -> Option<Throttled> {
+        let key = TokenKey::new("dev-1");
+        throttle
+            .admit_at(id, std::slice::from_ref(&key), now)
+            .await
+            .remove(&key)
+    }
+
+    fn held(delay: Duration, collapses: Option<&str>) -> Option<Throttled> {
+        Some(Throttled::Held {
+            delay,
+            collapses: collapses.map(Arc::from),
+        })
+    }
+
+    #[tokio::test]
+    async fn messages_past_the_minute_window_are_dropped_until_it_passes() {
+        let throttle = throttle(Some(2), None, ThrottlePolicy::Drop, 100);
+        let now = Instant::now();
+
+        assert_eq!(admit(&throttle, "m1", now).await, None);
+        assert_eq!(admit(&throttle, "m2", now).await, None);
+        assert_eq!(
+            admit(&throttle, "m3", now + Duration::from_secs(59)).await,
+            Some(Throttled::Dropped)
+        );
+        assert_eq!(admit(&throttle, "m4", now + minutes(1)).await, None);
+    }
+
+    #[tokio::test]
+    async fn messages_past_the_hour_window_are_dropped_until_it_passes() {
+        let throttle = throttle(Some(10), Some(3), ThrottlePolicy::Drop, 100);
+        let now = Instant::now();
+
+        for (index, id) in ["m1", "m2", "m3"].into_iter().enumerate() {
+            assert_eq!(
+                admit(&throttle, id, now + minutes(index as u64)).await,
+                None
+            );
+        }
+        assert_eq!(
+            admit(&throttle, "m4", now + minutes(59)).await,
+            Some(Throttled::Dropped)
+        );
+        assert_eq!(admit(&throttle, "m5", now + minutes(60)).await, None);
+    }
+
+    #[tokio::test]
+    async fn delayed_messages_are_spread_over_the_windows_up_to_the_held_maximum() {
+        let throttle = throttle(Some(1), None, ThrottlePolicy::Delay, 2);
+        let now = Instant::now();
+
+        assert_eq!(admit(&throttle, "m1", now).await, None);
+        assert_eq!(admit(&throttle, "m2", now).await, held(minutes(1), None));
+        assert_eq!(admit(&throttle, "m3", now).await, held(minutes(2), None));
+        assert_eq!(admit(&throttle, "m4", now).await, Some(Throttled::Dropped));
+    }
+
+    #[tokio::test]
+    async fn collapsed_message_takes_the_turn_of_the_one_held_back() {
+        let throttle = throttle(Some(1), None, ThrottlePolicy::Collapse, 100);
+        let now = Instant::now();
+
+        assert_eq!(admit(&throttle, "m1", now).await, None);
+        assert_eq!(admit(&throttle, "m2", now).await, held(minutes(1), None));
+        let later = now + Duration::from_secs(20);
+        assert_eq!(
+            admit(&throttle, "m3", later).await,
+            held(Duration::from_secs(40), Some("m2"))
+        );
+        assert_eq!(
+            admit(&throttle, "m4", later).await,
+            held(Duration::from_secs(40), Some("m3"))
+        );
+
+        // Once the held back message is due, the next one waits for the following turn.
+        assert_eq!(
+            admit(&throttle, "m5", now + minutes(1)).await,
+            held(minutes(1), None)
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::server::{AllowAnyAuthenticatedClient, NoClientAuth};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Request;
+use tracing::{debug, info, warn};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+use crate::config::TlsConfig;
+
+/// Number of handshaken connections buffered while waiting for the server to take them.
+const ACCEPT_BUFFER: usize = 64;
+/// Time a client is given to complete its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay before accepting again after failing to accept a connection, such as for the lack of
+/// file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("failed to read `{}`: {source}", .path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("`{}` holds no {what}", .path.display())]
+    Missing { path: PathBuf, what: &'static str },
+    #[error("`{}` holds an invalid certificate: {reason}", .path.display())]
+    Invalid { path: PathBuf, reason: String },
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+}
+
+/// TLS configuration of the listener, reloaded from its files whenever they change.
+///
+/// Connections are handshaken with the configuration current when they are accepted, so a
+/// renewed certificate is picked up without restarting, while established connections are kept.
+#[derive(Debug)]
+pub struct ServerTls {
+    files: TlsConfig,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl ServerTls {
+    /// Load the configuration from its files, spawning a task that reloads them as they change.
+    /// Must be called within a tokio runtime.
+    pub fn load(files: TlsConfig) -> Result<Arc<Self>, TlsError> {
+        let current = RwLock::new(server_config(&files)?);
+        let tls = Arc::new(Self { files, current });
+
+        let modified = tls.modified();
+        tokio::spawn(reload_periodically(Arc::downgrade(&tls), modified));
+
+        info!(client_auth = tls.files.client_ca.is_some(), "tls loaded");
+        Ok(tls)
+    }
+
+    fn current(&self) -> Arc<ServerConfig> {
+        // A panic while replacing the configuration leaves the previous one in place.
+        match self.current.read() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Modification times of the files, to tell whether they changed.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [
+            Some(&self.files.cert),
+            Some(&self.files.key),
+            self.files.client_ca.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+    }
+
+    fn reload(&self) -> Result<(), TlsError> {
+        let config = server_config(&self.files)?;
+        match self.current.write() {
+            Ok(mut current) => *current = config,
+            Err(poisoned) => *poisoned.into_inner() = config,
+        }
+        Ok(())
+    }
+
+    /// Accept connections from the listener, yielding them once their handshake completes.
+    ///
+    /// Handshakes run concurrently, so a slow client holds up no other, and connections failing
+    /// them are dropped. Accepting stops once the stream is dropped.
+    pub fn incoming(
+        self: Arc<Self>,
+        listener: TcpListener,
+    ) -> ReceiverStream<io::Result<TlsStream<TcpStream>>> {
+        let (tx, rx) = mpsc::channel(ACCEPT_BUFFER);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(error) => {
+                            warn!(%error, "failed to accept a connection");
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    },
+                    _ = tx.closed() => break,
+                };
+
+                let acceptor = TlsAcceptor::from(self.current());
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let handshake =
+                        tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+                    match handshake.await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send(Ok(stream)).await;
+                        }
+                        Ok(Err(error)) => debug!(%addr, %error, "tls handshake failed"),
+                        Err(_) => debug!(%addr, "tls handshake timed out"),
+                    }
+                });
+            }
+
+            debug!("listener closed");
+        });
+
+        ReceiverStream::new(rx)
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let read = |source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    };
+
+    let file = File::open(path).map_err(read)?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).map_err(read)?;
+    if certs.is_empty() {
+        return Err(TlsError::Missing {
+            path: path.to_path_buf(),
+            what: "certificate",
+        });
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let read = |source| TlsError::Read {
+        path: path.to_path_buf(),
+        source,
+    };
+
+    let file = File::open(path).map_err(read)?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader).map_err(read)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => {
+                return Err(TlsError::Missing {
+                    path: path.to_path_buf(),
+                    what: "private key",
+                })
+            }
+        }
+    }
+}
+
+fn server_config(files: &TlsConfig) -> Result<Arc<ServerConfig>, TlsError> {
+    let verifier = match &files.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots.add(&cert).map_err(|error| TlsError::Invalid {
+                    path: path.clone(),
+                    reason: format!("{:?}", error),
+                })?;
+            }
+            AllowAnyAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(read_certs(&files.cert)?, read_key(&files.key)?)?;
+    // gRPC runs over HTTP/2 only.
+    config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+async fn reload_periodically(tls: Weak<ServerTls>, mut modified: Vec<Option<SystemTime>>) {
+    let interval = match tls.upgrade() {
+        Some(tls) => tls.files.reload_interval(),
+        None => return,
+    };
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        // The listener closed along with its configuration.
+        let tls = match tls.upgrade() {
+            Some(tls) => tls,
+            None => break,
+        };
+
+        let current = tls.modified();
+        if current == modified {
+            continue;
+        }
+
+        // Files caught halfway through being replaced fail to load, and are retried once they
+        // are complete, which changes them again.
+        modified = current;
+        match tls.reload() {
+            Ok(_) => info!("tls reloaded"),
+            Err(error) => warn!(%error, "failed to reload tls, keeping the previous"),
+        }
+    }
+}
+
+/// Identity of a client, as verified from the certificate it presented over mutual TLS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// Distinguished name of the subject, such as `O=pine5, CN=worker`.
+    pub subject: String,
+    pub common_name: Option<String>,
+    /// DNS names, URIs, such as SPIFFE IDs, and email addresses the certificate is issued to.
+    pub alt_names: Vec<String>,
+}
+
+impl PeerIdentity {
+    /// The identity of the client of a request, if it presented a certificate.
+    ///
+    /// Only certificates verified against the client CA are accepted by the listener, so a
+    /// certificate being present means it was verified.
+    pub fn of<T>(request: &Request<T>) -> Option<Self> {
+        let certs = request.peer_certs()?;
+        Self::from_der(certs.first()?.get_ref())
+    }
+
+    /// Read the identity from a DER certificate.
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|name| name.as_str().ok())
+            .map(str::to_string);
+
+        let alt_names = match cert.subject_alternative_name() {
+            Ok(Some(names)) => names
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::URI(name)
+                    | GeneralName::RFC822Name(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Some(Self {
+            subject: cert.subject().to_string(),
+            common_name,
+            alt_names,
+        })
+    }
+
+    /// The name the client is known by: its common name, or else its first alternative name.
+    pub fn name(&self) -> Option<&str> {
+        self.common_name
+            .as_deref()
+            .or_else(|| self.alt_names.first().map(String::as_str))
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::BTreeSet;
+use std::iter::Peekable;
+use std::str::CharIndices;
+use std::sync::Arc;
+
+use thiserror::Error;
+
+/// Maximum length of a condition, in bytes.
+pub const CONDITION_MAX_LENGTH: usize = 1024;
+/// Maximum number of topics a condition refers to, as imposed by FCM.
+pub const CONDITION_MAX_TOPICS: usize = 5;
+/// Maximum nesting of negations and parentheses within a condition.
+pub const CONDITION_MAX_DEPTH: usize = 16;
+
+/// A parsed topic condition, e.g. `'news' && !'sports'`.
+///
+/// Both the bare form (`'news'`) and the FCM form (`'news' in topics`) of a
+/// topic reference are accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Topic(Arc<str>),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ConditionError {
+    #[error("unexpected end of condition")]
+    UnexpectedEnd,
+    #[error("unexpected `{1}` at offset {0}")]
+    UnexpectedChar(usize, char),
+    #[error("unterminated topic name at offset {0}")]
+    Unterminated(usize),
+    #[error("invalid topic name `{0}`")]
+    InvalidTopic(String),
+    #[error("condition exceeds the maximum of {0} bytes")]
+    TooLong(usize),
+    #[error("condition refers to more than {0} topics")]
+    TooManyTopics(usize),
+    #[error("condition is nested deeper than {0} levels")]
+    TooDeep(usize),
+}
+
+/// Check a topic name against the characters FCM permits: `[a-zA-Z0-9-_.~%]+`.
+pub fn is_valid_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '~' | '%'))
+}
+
+impl Condition {
+    pub fn topic(name: &str) -> Result<Self, ConditionError> {
+        if !is_valid_topic(name) {
+            return Err(ConditionError::InvalidTopic(name.to_string()));
+        }
+
+        Ok(Condition::Topic(Arc::from(name)))
+    }
+
+    /// Parse a condition, within the limits on its length, topics and nesting which keep its
+    /// evaluation bounded.
+    pub fn parse(source: &str) -> Result<Self, ConditionError> {
+        if source.len() > CONDITION_MAX_LENGTH {
+            return Err(ConditionError::TooLong(CONDITION_MAX_LENGTH));
+        }
+
+        let mut parser = Parser {
+            chars: source.char_indices().peekable(),
+            depth: 0,
+            topics: 0,
+        };
+
+        let condition = parser.or()?;
+
+        parser.skip_whitespace();
+        match parser.chars.next() {
+            Some((offset, c)) => Err(ConditionError::UnexpectedChar(offset, c)),
+            None => Ok(condition),
+        }
+    }
+
+    /// Evaluate the condition against the topics a single token is subscribed to.
+    pub fn matches(&self, topics: &BTreeSet<Arc<str>>) -> bool {
+        match self {
+            Condition::Topic(topic) => topics.contains(topic),
+            Condition::Not(inner) => !inner.matches(topics),
+            Condition::And(lhs, rhs) => lhs.matches(topics) && rhs.matches(topics),
+            Condition::Or(lhs, rhs) => lhs.matches(topics) || rhs.matches(topics),
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    // Negations and parentheses enclosing the current position.
+    depth: usize,
+    // Topics referred to so far.
+    topics: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while let Some((_, c)) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ConditionError> {
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            Some((offset, c)) => Err(ConditionError::UnexpectedChar(offset, c)),
+            None => Err(ConditionError::UnexpectedEnd),
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition, ConditionError> {
+        let mut lhs = self.and()?;
+
+        loop {
+            self.skip_whitespace();
+            match self.chars.peek() {
+                Some((_, '|')) => {
+                    self.chars.next();
+                    self.expect('|')?;
+                    let rhs = self.and()?;
+                    lhs = Condition::Or(Box::new(lhs), Box::new(rhs));
+                }
+                _ => return Ok(lhs),
+            }
+        }
+    }
+
+    fn and(&mut self) -> Result<Condition, ConditionError> {
+        let mut lhs = self.unary()?;
+
+        loop {
+            self.skip_whitespace();
+            match self.chars.peek() {
+                Some((_, '&')) => {
+                    self.chars.next();
+                    self.expect('&')?;
+                    let rhs = self.unary()?;
+                    lhs = Condition::And(Box::new(lhs), Box::new(rhs));
+                }
+                _ => return Ok(lhs),
+            }
+        }
+    }
+
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, ConditionError>,
+    ) -> Result<T, ConditionError> {
+        if self.depth >= CONDITION_MAX_DEPTH {
+            return Err(ConditionError::TooDeep(CONDITION_MAX_DEPTH));
+        }
+
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn unary(&mut self) -> Result<Condition, ConditionError> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some((_, '!')) => {
+                self.chars.next();
+                let inner = self.nested(Self::unary)?;
+                Ok(Condition::Not(Box::new(inner)))
+            }
+            Some((_, '(')) => {
+                self.chars.next();
+                let inner = self.nested(Self::or)?;
+                self.skip_whitespace();
+                self.expect(')')?;
+                Ok(inner)
+            }
+            Some((_, '\'')) | Some((_, '"')) => self.topic(),
+            Some((offset, c)) => Err(ConditionError::UnexpectedChar(*offset, *c)),
+            None => Err(ConditionError::UnexpectedEnd),
+        }
+    }
+
+    fn topic(&mut self) -> Result<Condition, ConditionError> {
+        let (start, quote) = self.chars.next().ok_or(ConditionError::UnexpectedEnd)?;
+
+        self.topics += 1;
+        if self.topics > CONDITION_MAX_TOPICS {
+            return Err(ConditionError::TooManyTopics(CONDITION_MAX_TOPICS));
+        }
+
+        let mut name = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, c)) if c == quote => break,
+                Some((_, c)) => name.push(c),
+                None => return Err(ConditionError::Unterminated(start)),
+            }
+        }
+
+        // Accept the optional FCM style `in topics` suffix.
+        self.skip_whitespace();
+        if let Some((_, 'i')) = self.chars.peek() {
+            for expected in "in".chars() {
+                self.expect(expected)?;
+            }
+            self.skip_whitespace();
+            for expected in "topics".chars() {
+                self.expect(expected)?;
+            }
+        }
+
+        Condition::topic(&name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic(name: &str) -> Box<Condition> {
+        Box::new(Condition::Topic(Arc::from(name)))
+    }
+
+    fn topics(names: &[&str]) -> BTreeSet<Arc<str>> {
+        names.iter().map(|name| Arc::from(*name)).collect()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            Condition::parse("'a' || 'b' && 'c'"),
+            Ok(Condition::Or(
+                topic("a"),
+                Box::new(Condition::And(topic("b"), topic("c")))
+            ))
+        );
+        assert_eq!(
+            Condition::parse("('a' || 'b') && 'c'"),
+            Ok(Condition::And(
+                Box::new(Condition::Or(topic("a"), topic("b"))),
+                topic("c")
+            ))
+        );
+    }
+
+    #[test]
+    fn not_binds_tightest() {
+        let condition = Condition::parse("!'a' && 'b'").unwrap();
+        assert_eq!(
+            condition,
+            Condition::And(Box::new(Condition::Not(topic("a"))), topic("b"))
+        );
+        assert!(condition.matches(&topics(&["b"])));
+        assert!(!condition.matches(&topics(&["a", "b"])));
+    }
+
+    #[test]
+    fn accepts_both_quotes_and_the_fcm_form() {
+        assert_eq!(
+            Condition::parse(r#""a" in topics && 'b'"#),
+            Ok(Condition::And(topic("a"), topic("b")))
+        );
+        assert_eq!(
+            Condition::parse(r#""it's""#),
+            Err(ConditionError::InvalidTopic("it's".to_string()))
+        );
+        assert_eq!(Condition::parse("'a"), Err(ConditionError::Unterminated(0)));
+        assert_eq!(
+            Condition::parse("'a' in topic"),
+            Err(ConditionError::UnexpectedEnd)
+        );
+        assert_eq!(
+            Condition::parse("'a' & 'b'"),
+            Err(ConditionError::UnexpectedChar(5, ' '))
+        );
+    }
+
+    #[test]
+    fn limits_the_topics() {
+        assert!(Condition::parse("'a' && 'b' && 'c' && 'd' && 'e'").is_ok());
+        assert_eq!(
+            Condition::parse("'a' && 'b' && 'c' && 'd' && 'e' && 'f'"),
+            Err(ConditionError::TooManyTopics(CONDITION_MAX_TOPICS))
+        );
+    }
+
+    #[test]
+    fn limits_the_nesting() {
+        let nested = |depth: usize| format!("{}'a'{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Condition::parse(&nested(CONDITION_MAX_DEPTH)).is_ok());
+        assert_eq!(
+            Condition::parse(&nested(CONDITION_MAX_DEPTH + 1)),
+            Err(ConditionError::TooDeep(CONDITION_MAX_DEPTH))
+        );
+
+        let negated = |depth: usize| format!("{}'a'", "!".repeat(depth));
+        assert!(Condition::parse(&negated(CONDITION_MAX_DEPTH)).is_ok());
+        assert_eq!(
+            Condition::parse(&negated(CONDITION_MAX_DEPTH + 1)),
+            Err(ConditionError::TooDeep(CONDITION_MAX_DEPTH))
+        );
+    }
+
+    #[test]
+    fn limits_the_length() {
+        let long = format!("'{}'", "a".repeat(CONDITION_MAX_LENGTH));
+        assert_eq!(
+            Condition::parse(&long),
+            Err(ConditionError::TooLong(CONDITION_MAX_LENGTH))
+        );
+        assert_eq!(
+            Condition::parse(&"(".repeat(100_000)),
+            Err(ConditionError::TooLong(CONDITION_MAX_LENGTH))
+        );
+    }
+}
@@ -0,0 +1,401 @@
+use std::sync::Arc;
+
+use bytes::{Buf, BytesMut};
+use chrono::NaiveDateTime;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cm::{self, TransferFormat};
+use crate::model;
+
+/// Maximum length of the varint prefixing a length-delimited message.
+const MAX_VARINT_LENGTH: usize = 10;
+/// Maximum length of a token in either format, past which the data is refused rather than
+/// buffered in search of its end.
+pub const MAX_RECORD_LENGTH: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum TransferError {
+    #[error("token {record} is malformed: {reason}")]
+    Malformed { record: u64, reason: String },
+    #[error("token {record} is truncated")]
+    Truncated { record: u64 },
+    #[error("token {record} exceeds {limit} bytes")]
+    TooLong { record: u64, limit: usize },
+}
+
+/// A token as a line of JSON.
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenRecord {
+    key: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    tenant: String,
+    timestamp: NaiveDateTime,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    topics: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+    #[serde(default)]
+    platform: PlatformRecord,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    webpush: Option<WebPushRecord>,
+    #[serde(default)]
+    version: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PlatformRecord {
+    #[default]
+    Unspecified,
+    Fcm,
+    Apns,
+    Webpush,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WebPushRecord {
+    p256dh: String,
+    auth: String,
+}
+
+impl From<&model::Token> for TokenRecord {
+    fn from(source: &model::Token) -> Self {
+        Self {
+            key: source.key.key.to_string(),
+            tenant: source.key.tenant.to_string(),
+            timestamp: source.timestamp,
+            topics: source
+                .topics
+                .iter()
+                .map(|topic| topic.to_string())
+                .collect(),
+            owner: source.owner.as_ref().map(|owner| owner.to_string()),
+            platform: match source.platform {
+                cm::Platform::Unspecified => PlatformRecord::Unspecified,
+                cm::Platform::Fcm => PlatformRecord::Fcm,
+                cm::Platform::Apns => PlatformRecord::Apns,
+                cm::Platform::Webpush => PlatformRecord::Webpush,
+            },
+            webpush: source.webpush.as_ref().map(|webpush| WebPushRecord {
+                p256dh: webpush.p256dh.to_string(),
+                auth: webpush.auth.to_string(),
+            }),
+            version: source.version,
+        }
+    }
+}
+
+impl From<TokenRecord> for model::Token {
+    fn from(source: TokenRecord) -> Self {
+        Self {
+            key: model::TokenKey::new(&source.key).with_tenant(Arc::from(source.tenant)),
+            timestamp: source.timestamp,
+            topics: source.topics.into_iter().map(Arc::from).collect(),
+            owner: source
+                .owner
+                .filter(|owner| !owner.is_empty())
+                .map(Arc::from),
+            platform: match source.platform {
+                PlatformRecord::Unspecified => cm::Platform::Unspecified,
+                PlatformRecord::Fcm => cm::Platform::Fcm,
+                PlatformRecord::Apns => cm::Platform::Apns,
+                PlatformRecord::Webpush => cm::Platform::Webpush,
+            },
+            webpush: source.webpush.map(|webpush| model::WebPushKeys {
+                p256dh: Arc::from(webpush.p256dh),
+                auth: Arc::from(webpush.auth),
+            }),
+            version: source.version,
+        }
+    }
+}
+
+/// Append a token to `buf` in the given format.
+pub fn encode(format: TransferFormat, token: &model::Token, buf: &mut Vec<u8>) {
+    match format {
+        TransferFormat::TransferJsonl => {
+            // Serializing a record of plain fields cannot fail.
+            serde_json::to_writer(&mut *buf, &TokenRecord::from(token))
+                .expect("token record is serializable");
+            buf.push(b'\n');
+        }
+        TransferFormat::TransferDelimited => {
+            // Encoding into a vector cannot run out of capacity.
+            cm::Token::from(token)
+                .encode_length_delimited(buf)
+                .expect("vector has capacity");
+        }
+    }
+}
+
+/// Decodes tokens from chunks of data in the given format, regardless of where the chunks split
+/// the tokens.
+#[derive(Debug)]
+pub struct Decoder {
+    format: TransferFormat,
+    buf: BytesMut,
+    finished: bool,
+    records: u64,
+}
+
+impl Decoder {
+    pub fn new(format: TransferFormat) -> Self {
+        Self {
+            format,
+            buf: BytesMut::new(),
+            finished: false,
+            records: 0,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Mark the end of the data, so that the remainder of it is decoded as the last token.
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    /// Decode the next token, if the data pushed so far completes one.
+    pub fn next_token(&mut self) -> Option<Result<model::Token, TransferError>> {
+        let token = match self.format {
+            TransferFormat::TransferJsonl => self.next_jsonl(),
+            TransferFormat::TransferDelimited => self.next_delimited(),
+        }?;
+
+        self.records += 1;
+        Some(token)
+    }
+
+    fn malformed(&self, reason: impl ToString) -> TransferError {
+        TransferError::Malformed {
+            record: self.records,
+            reason: reason.to_string(),
+        }
+    }
+
+    fn too_long(&self) -> TransferError {
+        TransferError::TooLong {
+            record: self.records,
+            limit: MAX_RECORD_LENGTH,
+        }
+    }
+
+    fn next_jsonl(&mut self) -> Option<Result<model::Token, TransferError>> {
+        loop {
+            let end = self.buf.iter().position(|b| *b == b'\n');
+            if end.unwrap_or(self.buf.len()) > MAX_RECORD_LENGTH {
+                return Some(Err(self.too_long()));
+            }
+
+            let line = match end {
+                Some(end) => self.buf.split_to(end + 1),
+                None if self.finished && !self.buf.is_empty() => self.buf.split(),
+                None => return None,
+            };
+
+            // Blank lines separate nothing; skip them.
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            return Some(
+                serde_json::from_slice::<TokenRecord>(&line)
+                    .map_err(|error| self.malformed(error))
+                    .and_then(|record| match record.key.is_empty() {
+                        true => Err(self.malformed("key is empty")),
+                        false => Ok(record.into()),
+                    }),
+            );
+        }
+    }
+
+    fn next_delimited(&mut self) -> Option<Result<model::Token, TransferError>> {
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let mut prefix = &self.buf[..];
+        let length = match prost::encoding::decode_varint(&mut prefix) {
+            Ok(length) => length as usize,
+            Err(_) if !self.finished && self.buf.len() < MAX_VARINT_LENGTH => return None,
+            Err(_) if self.buf.len() < MAX_VARINT_LENGTH => {
+                return Some(Err(TransferError::Truncated {
+                    record: self.records,
+                }))
+            }
+            Err(error) => return Some(Err(self.malformed(error))),
+        };
+
+        if length > MAX_RECORD_LENGTH {
+            return Some(Err(self.too_long()));
+        }
+
+        let header = self.buf.len() - prefix.len();
+        if prefix.len() < length {
+            return match self.finished {
+                true => Some(Err(TransferError::Truncated {
+                    record: self.records,
+                })),
+                false => None,
+            };
+        }
+
+        self.buf.advance(header);
+        let message = self.buf.split_to(length);
+
+        Some(
+            cm::Token::decode(&message[..])
+                .map_err(|error| self.malformed(error))
+                .and_then(|token| match &token.key {
+                    Some(key) if !key.key.is_empty() => {
+                        model::Token::restored(token).map_err(|error| self.malformed(error))
+                    }
+                    _ => Err(self.malformed("key is missing")),
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::cm_token::token_validate_restored;
+
+    fn tokens() -> Vec<model::Token> {
+        ["dev-1", "dev-2", "dev-3"]
+            .into_iter()
+            .map(|key| {
+                model::Token::new(model::TokenKey::new(key).with_tenant(Arc::from("acme")))
+                    .with_owner(Some(Arc::from("alice")))
+            })
+            .collect()
+    }
+
+    /// Decode the data pushed in chunks of the given size, then finished, up to the first error
+    /// as an import does.
+    fn decode(
+        format: TransferFormat,
+        data: &[u8],
+        chunk: usize,
+    ) -> Vec<Result<model::Token, TransferError>> {
+        let mut decoder = Decoder::new(format);
+        let mut decoded = Vec::new();
+        let mut chunks = data.chunks(chunk);
+        loop {
+            while let Some(token) = decoder.next_token() {
+                let failed = token.is_err();
+                decoded.push(token);
+                if failed {
+                    return decoded;
+                }
+            }
+            match chunks.next() {
+                Some(chunk) => decoder.push(chunk),
+                None if decoder.finished => return decoded,
+                None => decoder.finish(),
+            }
+        }
+    }
+
+    #[test]
+    fn tokens_split_across_chunks_are_decoded() {
+        let tokens = tokens();
+        for format in [
+            TransferFormat::TransferJsonl,
+            TransferFormat::TransferDelimited,
+        ] {
+            let mut data = Vec::new();
+            for token in tokens.iter() {
+                encode(format, token, &mut data);
+            }
+
+            for chunk in [1, 2, 7, data.len()] {
+                let decoded: Vec<model::Token> = decode(format, &data, chunk)
+                    .into_iter()
+                    .map(Result::unwrap)
+                    .collect();
+                assert_eq!(
+                    decoded.len(),
+                    tokens.len(),
+                    "{:?} in chunks of {}",
+                    format,
+                    chunk
+                );
+                for (decoded, token) in decoded.iter().zip(tokens.iter()) {
+                    assert_eq!(decoded.key, token.key);
+                    assert_eq!(decoded.owner, token.owner);
+                    assert_eq!(decoded.timestamp.timestamp(), token.timestamp.timestamp());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn last_line_without_newline_is_decoded() {
+        let mut data = Vec::new();
+        encode(TransferFormat::TransferJsonl, &tokens()[0], &mut data);
+        data.pop();
+
+        let decoded = decode(TransferFormat::TransferJsonl, &data, 3);
+        assert_eq!(decoded.len(), 1);
+        assert!(decoded[0].is_ok());
+    }
+
+    #[test]
+    fn truncated_token_is_refused() {
+        let mut data = Vec::new();
+        encode(TransferFormat::TransferDelimited, &tokens()[0], &mut data);
+        data.pop();
+
+        let decoded = decode(TransferFormat::TransferDelimited, &data, 5);
+        assert!(matches!(
+            decoded.as_slice(),
+            [Err(TransferError::Truncated { record: 0 })]
+        ));
+    }
+
+    #[test]
+    fn line_past_the_maximum_length_is_refused_before_it_ends() {
+        let mut decoder = Decoder::new(TransferFormat::TransferJsonl);
+        decoder.push(&vec![b'x'; MAX_RECORD_LENGTH]);
+        assert!(decoder.next_token().is_none());
+
+        decoder.push(b"x");
+        assert!(matches!(
+            decoder.next_token(),
+            Some(Err(TransferError::TooLong { record: 0, .. }))
+        ));
+    }
+
+    #[test]
+    fn delimited_token_past_the_maximum_length_is_refused() {
+        let mut data = Vec::new();
+        prost::encoding::encode_varint(MAX_RECORD_LENGTH as u64 + 1, &mut data);
+
+        let mut decoder = Decoder::new(TransferFormat::TransferDelimited);
+        decoder.push(&data);
+        assert!(matches!(
+            decoder.next_token(),
+            Some(Err(TransferError::TooLong { record: 0, .. }))
+        ));
+    }
+
+    #[test]
+    fn restored_tokens_are_validated_as_registrations() {
+        let token = tokens().remove(0);
+        assert!(token_validate_restored(&token, 0).is_ok());
+
+        let apns = token.clone().with_platform(cm::Platform::Apns, None);
+        let status = token_validate_restored(&apns, 4).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "token 4 is invalid");
+
+        let mut topics = token;
+        topics.topics.insert(Arc::from("not a topic"));
+        assert!(token_validate_restored(&topics, 0).is_err());
+    }
+}